@@ -0,0 +1,43 @@
+// Generates the built-in game database into OUT_DIR/gamedb.txt.
+//
+// src/rom/gamedb.txt is taken as it is, and the games in nes20db.xml given by `NES20DB` are
+// appended:
+//
+//   NES20DB=/path/to/nes20db.xml cargo build
+//
+// The build fails without `NES20DB`. `NES20DB=none` builds only src/rom/gamedb.txt, which has
+// no games, so that no ROM is identified and no header is corrected.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+mod nes20db {
+    include!("src/rom/nes20db.rs");
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/rom/gamedb.txt");
+    println!("cargo:rerun-if-changed=src/rom/nes20db.rs");
+    println!("cargo:rerun-if-env-changed=NES20DB");
+    println!("cargo:rustc-check-cfg=cfg(nes20db)");
+
+    let mut db = fs::read_to_string("src/rom/gamedb.txt").expect("src/rom/gamedb.txt is missing");
+    let path = env::var_os("NES20DB").unwrap_or_else(|| {
+        panic!(
+            "NES20DB is not set. Set it to the path of nes20db.xml of the NES 2.0 XML database, \
+             or to `none` to build without the game database"
+        )
+    });
+    if path != "none" {
+        let path = Path::new(&path);
+        let xml = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+        db.push_str(&nes20db::convert(&xml));
+
+        println!("cargo:rerun-if-changed={}", path.display());
+        println!("cargo:rustc-env=NES20DB_PATH={}", path.display());
+        println!("cargo:rustc-cfg=nes20db");
+    }
+    let out = Path::new(&env::var_os("OUT_DIR").unwrap()).join("gamedb.txt");
+    fs::write(out, db).unwrap();
+}
@@ -9,7 +9,7 @@ pub(crate) struct Apu {
     pulse2: channel::Pulse,
     triangle: channel::Triangle,
     noise: channel::Noise,
-    dmc: channel::DMC,
+    dmc: channel::Dmc,
 
     cycles: u32,
 
@@ -31,15 +31,15 @@ impl AudioBuffer for NopAudioBuffer {
 }
 
 impl Apu {
-    pub(crate) fn new(sampling_rate: u32, frame_period: u32) -> Self {
+    pub(crate) fn new(sampling_rate: u32) -> Self {
         Self {
             sampling_rate,
-            frame_period,
+            frame_period: Region::default().frame_counter_period(),
             pulse1: channel::Pulse::channel_1(),
             pulse2: channel::Pulse::channel_2(),
             triangle: channel::Triangle::new(),
             noise: channel::Noise::new(),
-            dmc: channel::DMC::new(),
+            dmc: channel::Dmc::new(),
             cycles: 0,
             frame_counter_control: Default::default(),
             frame_sequence_step: 0,
@@ -48,6 +48,14 @@ impl Apu {
         }
     }
 
+    // Frame counter timing and the period tables of noise and DMC
+    pub(crate) fn set_region(&mut self, region: Region) {
+        self.frame_period = region.frame_counter_period();
+        let pal = region == Region::Pal;
+        self.noise.set_pal(pal);
+        self.dmc.set_pal(pal);
+    }
+
     pub fn read_status(&mut self) -> Byte {
         let mut v: u8 = 0;
         if self.dmc.interrupted {
@@ -75,6 +83,11 @@ impl Apu {
         v.into()
     }
 
+    // IRQ line asserted by the frame counter or DMC
+    pub(crate) fn irq(&self) -> bool {
+        (self.frame_interrupted && !self.frame_interrupt_inhibit()) || self.dmc.interrupted
+    }
+
     fn frame_interrupt_inhibit(&self) -> bool {
         self.frame_counter_control.nth(6) == 1
     }
@@ -103,9 +116,10 @@ impl Apu {
 pub fn step(nes: &mut Nes) -> u128 {
     nes.apu.cycles += 1;
 
-    // Down sampling
-    if nes.apu.cycles % nes.apu.sampling_rate == 0 {
-        nes.apu.audio_buffer.write(nes.apu.sample());
+    // Down sampling; no output without sampling rate
+    if 0 < nes.apu.sampling_rate && nes.apu.cycles % nes.apu.sampling_rate == 0 {
+        let sample = nes.apu.sample(nes.mapper.audio_output());
+        nes.apu.audio_buffer.write(sample);
     }
 
     let cpu_stall = if nes.apu.cycles % 2 == 0 {
@@ -171,7 +185,8 @@ pub fn step(nes: &mut Nes) -> u128 {
 }
 
 impl Apu {
-    fn sample(&self) -> f32 {
+    // Mixes the internal channels with the cartridge expansion audio
+    fn sample(&self, expansion: f32) -> f32 {
         let p1 = self.pulse1.output() as f32;
         let p2 = self.pulse2.output() as f32;
         let t = self.triangle.output() as f32;
@@ -188,12 +203,11 @@ impl Apu {
         } else {
             0.0
         };
-        return p_out + t_out;
+        p_out + t_out + expansion
     }
 }
 
 mod channel {
-    use crate::bus::*;
     use crate::data_unit::*;
     use crate::nes::*;
 
@@ -228,18 +242,13 @@ mod channel {
         carry_mode: CarryMode,
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Default)]
     pub(super) enum CarryMode {
+        #[default]
         OnesComplement,
         TwosComplement,
     }
 
-    impl Default for CarryMode {
-        fn default() -> Self {
-            CarryMode::OnesComplement
-        }
-    }
-
     impl Pulse {
         pub(super) fn channel_1() -> Self {
             Pulse {
@@ -534,6 +543,7 @@ mod channel {
 
         timer_counter: u16,
         timer_period: u16,
+        pal: bool,
 
         enabled: bool,
     }
@@ -543,6 +553,11 @@ mod channel {
         4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
     ];
 
+    #[rustfmt::skip]
+    static NOISE_TIMER_PERIOD_TABLE_PAL: [u16; 16] = [
+        4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+    ];
+
     impl Noise {
         pub(super) fn new() -> Self {
             Self {
@@ -557,12 +572,10 @@ mod channel {
                 0x400C => self.envelope = value,
                 0x400E => {
                     self.period = value;
-                    self.timer_period = NOISE_TIMER_PERIOD_TABLE[self.timer_entry()];
+                    self.timer_period = self.period_table()[self.timer_entry()];
                 }
-                0x400F => {
-                    if self.enabled {
-                        self.length_counter = LENGTH_TABLE[(value.u16() & 0b11111000 >> 3) as usize]
-                    }
+                0x400F if self.enabled => {
+                    self.length_counter = LENGTH_TABLE[(value.u16() & 0b11111000 >> 3) as usize]
                 }
                 _ => {}
             }
@@ -575,6 +588,19 @@ mod channel {
             }
         }
 
+        pub(super) fn set_pal(&mut self, pal: bool) {
+            self.pal = pal;
+            self.timer_period = self.period_table()[self.timer_entry()];
+        }
+
+        fn period_table(&self) -> &'static [u16; 16] {
+            if self.pal {
+                &NOISE_TIMER_PERIOD_TABLE_PAL
+            } else {
+                &NOISE_TIMER_PERIOD_TABLE
+            }
+        }
+
         fn timer_entry(&self) -> usize {
             ((self.period.u8() as u16) & 0b1111) as usize
         }
@@ -588,7 +614,7 @@ mod channel {
                 let mode_flag = self.period.nth(7) == 1;
                 let shift_n = if mode_flag { 6 } else { 1 };
                 let feedback = self.shift_register ^ self.shift_register.nth(shift_n);
-                self.shift_register = self.shift_register >> 1;
+                self.shift_register >>= 1;
                 self.shift_register |= feedback << 14;
             }
         }
@@ -639,13 +665,14 @@ mod channel {
     }
 
     #[derive(Debug, Default)]
-    pub(super) struct DMC {
+    pub(super) struct Dmc {
         flags: Byte,
         direct: Byte,
         address: Byte,
         length: Byte,
 
-        timer_counter: u8,
+        timer_counter: u16,
+        pal: bool,
 
         bits_remaining_counter: Byte,
 
@@ -667,13 +694,39 @@ mod channel {
         pub(super) interrupted: bool,
     }
 
-    impl DMC {
+    // Periods in CPU cycles
+    // https://wiki.nesdev.com/w/index.php/APU_DMC
+    #[rustfmt::skip]
+    static DMC_RATE_TABLE: [u16; 16] = [
+        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+    ];
+
+    #[rustfmt::skip]
+    static DMC_RATE_TABLE_PAL: [u16; 16] = [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ];
+
+    impl Dmc {
         pub(super) fn new() -> Self {
             Self {
                 ..Default::default()
             }
         }
 
+        pub(super) fn set_pal(&mut self, pal: bool) {
+            self.pal = pal;
+        }
+
+        // The timer is clocked on every other CPU cycle
+        fn timer_period(&self) -> u16 {
+            let table = if self.pal {
+                &DMC_RATE_TABLE_PAL
+            } else {
+                &DMC_RATE_TABLE
+            };
+            table[(self.flags.u8() & 0x0F) as usize] / 2
+        }
+
         pub(super) fn write(&mut self, addr: impl Into<u16>, value: Byte) {
             let addr: u16 = addr.into();
             match addr {
@@ -712,10 +765,10 @@ mod channel {
             nes.apu.dmc.timer_counter = nes.apu.dmc.timer_counter.wrapping_sub(1);
         } else {
             // the output cycle ends
-            nes.apu.dmc.timer_counter = 8;
+            nes.apu.dmc.timer_counter = nes.apu.dmc.timer_period();
             // memory reader
             if nes.apu.dmc.sample_buffer_empty && nes.apu.dmc.bytes_remaining_counter != 0 {
-                nes.apu.dmc.sample_buffer = nes.read(nes.apu.dmc.address_counter);
+                nes.apu.dmc.sample_buffer = nes.read_bus(nes.apu.dmc.address_counter);
                 nes.apu.dmc.address_counter += 1;
                 if nes.apu.dmc.address_counter == 0u16.into() {
                     nes.apu.dmc.address_counter = 0x8000u16.into();
@@ -746,14 +799,12 @@ mod channel {
             nes.apu.dmc.sample_buffer = 0u8.into();
         }
 
-        if !nes.apu.dmc.silence {
-            if nes.apu.dmc.shift_register.nth(0) == 1 {
-                // consider wrapping around
-                if nes.apu.dmc.output_level < nes.apu.dmc.output_level + 2 {
-                    nes.apu.dmc.output_level += 2;
-                } else if nes.apu.dmc.output_level - 2 < nes.apu.dmc.output_level {
-                    nes.apu.dmc.output_level -= 2;
-                }
+        if !nes.apu.dmc.silence && nes.apu.dmc.shift_register.nth(0) == 1 {
+            // consider wrapping around
+            if nes.apu.dmc.output_level < nes.apu.dmc.output_level + 2 {
+                nes.apu.dmc.output_level += 2;
+            } else if nes.apu.dmc.output_level - 2 < nes.apu.dmc.output_level {
+                nes.apu.dmc.output_level -= 2;
             }
         }
         nes.apu.dmc.shift_register >>= 1;
@@ -1,7 +1,12 @@
+use std::fmt;
+
 use crate::bus::*;
 use crate::data_unit::*;
 use crate::nes::*;
 
+// Instructions kept for CpuFault
+const RECENT_INSTRUCTIONS: usize = 16;
+
 #[derive(Debug, Default, Clone)]
 pub struct Cpu {
     pub(crate) a: Byte,
@@ -12,6 +17,54 @@ pub struct Cpu {
     pub(crate) pc: Word,
 
     pub(crate) cycles: u128,
+
+    // Ring buffer of the last instructions; `recorded` counts all of them
+    recent: [InstructionTrace; RECENT_INSTRUCTIONS],
+    recorded: usize,
+    // Halted by JAM until reset
+    pub(crate) fault: Option<CpuFault>,
+}
+
+// Registers when the instruction was fetched
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct InstructionTrace {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub cycle: u128,
+}
+
+// The CPU has been locked up by a JAM (KIL) opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuFault {
+    pub pc: u16,
+    pub opcode: u8,
+    // Instructions executed before the fault, oldest first, ending with the JAM
+    pub trace: Vec<InstructionTrace>,
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CPU jammed at ${:04X} (opcode ${:02X})",
+            self.pc, self.opcode
+        )
+    }
+}
+
+impl fmt::Display for InstructionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc, self.opcode, self.a, self.x, self.y, self.p, self.s, self.cycle
+        )
+    }
 }
 
 bitflags! {
@@ -64,8 +117,15 @@ impl Nes {
 pub fn step(nes: &mut Nes) -> u128 {
     let before = nes.cpu.cycles;
 
+    if nes.cpu.fault.is_some() {
+        // No more fetches, but the clock keeps running
+        nes.cpu.cycles += 1;
+        return 1;
+    }
+
     // fetch
     let opcode = nes.read(nes.cpu.pc);
+    nes.cpu.record(opcode);
     nes.cpu.pc += 1;
 
     let instruction = decode(opcode);
@@ -146,12 +206,16 @@ pub fn step(nes: &mut Nes) -> u128 {
             nes.cpu.cycles += 1;
             operand
         }
-        AddressingMode::IndirectIndexed => {
+        AddressingMode::IndirectIndexed { penalty } => {
             let y: Word = nes.cpu.y.into();
             let data: Word = nes.read(nes.cpu.pc).into();
             let operand = nes.read_on_indirect(data) + y;
             nes.cpu.pc += 1;
-            if page_crossed_u16(y, operand - y) {
+            if penalty {
+                if page_crossed_u16(y, operand - y) {
+                    nes.cpu.cycles += 1;
+                }
+            } else {
                 nes.cpu.cycles += 1;
             }
             operand
@@ -163,10 +227,6 @@ pub fn step(nes: &mut Nes) -> u128 {
         (Mnemonic::LDA, _) => nes.lda(operand),
         (Mnemonic::LDX, _) => nes.ldx(operand),
         (Mnemonic::LDY, _) => nes.ldy(operand),
-        (Mnemonic::STA, AddressingMode::IndirectIndexed) => {
-            nes.sta(operand);
-            nes.cpu.cycles += 1;
-        }
         (Mnemonic::STA, _) => nes.sta(operand),
         (Mnemonic::STX, _) => nes.stx(operand),
         (Mnemonic::STY, _) => nes.sty(operand),
@@ -224,6 +284,7 @@ pub fn step(nes: &mut Nes) -> u128 {
         (Mnemonic::SEI, _) => nes.sei(operand),
         (Mnemonic::BRK, _) => nes.brk(operand),
         (Mnemonic::NOP, _) => nes.nop(operand),
+        (Mnemonic::LAX, AddressingMode::Immediate) => nes.lxa(operand),
         (Mnemonic::LAX, _) => nes.lax(operand),
         (Mnemonic::SAX, _) => nes.sax(operand),
         (Mnemonic::DCP, _) => nes.dcp(operand),
@@ -232,6 +293,17 @@ pub fn step(nes: &mut Nes) -> u128 {
         (Mnemonic::RLA, _) => nes.rla(operand),
         (Mnemonic::SRE, _) => nes.sre(operand),
         (Mnemonic::RRA, _) => nes.rra(operand),
+        (Mnemonic::ANC, _) => nes.anc(operand),
+        (Mnemonic::ALR, _) => nes.alr(operand),
+        (Mnemonic::ARR, _) => nes.arr(operand),
+        (Mnemonic::AXS, _) => nes.axs(operand),
+        (Mnemonic::LAS, _) => nes.las(operand),
+        (Mnemonic::SHX, _) => nes.shx(operand),
+        (Mnemonic::SHY, _) => nes.shy(operand),
+        (Mnemonic::SHA, _) => nes.sha(operand),
+        (Mnemonic::TAS, _) => nes.tas(operand),
+        (Mnemonic::XAA, _) => nes.xaa(operand),
+        (Mnemonic::JAM, _) => nes.jam(opcode),
     }
 
     if before <= nes.cpu.cycles {
@@ -250,7 +322,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xBD => (Mnemonic::LDA, AddressingMode::AbsoluteX { penalty: true }),
         0xB9 => (Mnemonic::LDA, AddressingMode::AbsoluteY { penalty: true }),
         0xA1 => (Mnemonic::LDA, AddressingMode::IndexedIndirect),
-        0xB1 => (Mnemonic::LDA, AddressingMode::IndirectIndexed),
+        0xB1 => (
+            Mnemonic::LDA,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0xA2 => (Mnemonic::LDX, AddressingMode::Immediate),
         0xA6 => (Mnemonic::LDX, AddressingMode::ZeroPage),
         0xB6 => (Mnemonic::LDX, AddressingMode::ZeroPageY),
@@ -267,7 +342,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x9D => (Mnemonic::STA, AddressingMode::AbsoluteX { penalty: false }),
         0x99 => (Mnemonic::STA, AddressingMode::AbsoluteY { penalty: false }),
         0x81 => (Mnemonic::STA, AddressingMode::IndexedIndirect),
-        0x91 => (Mnemonic::STA, AddressingMode::IndirectIndexed),
+        0x91 => (
+            Mnemonic::STA,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0x86 => (Mnemonic::STX, AddressingMode::ZeroPage),
         0x96 => (Mnemonic::STX, AddressingMode::ZeroPageY),
         0x8E => (Mnemonic::STX, AddressingMode::Absolute),
@@ -293,7 +371,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x3D => (Mnemonic::AND, AddressingMode::AbsoluteX { penalty: true }),
         0x39 => (Mnemonic::AND, AddressingMode::AbsoluteY { penalty: true }),
         0x21 => (Mnemonic::AND, AddressingMode::IndexedIndirect),
-        0x31 => (Mnemonic::AND, AddressingMode::IndirectIndexed),
+        0x31 => (
+            Mnemonic::AND,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0x49 => (Mnemonic::EOR, AddressingMode::Immediate),
         0x45 => (Mnemonic::EOR, AddressingMode::ZeroPage),
         0x55 => (Mnemonic::EOR, AddressingMode::ZeroPageX),
@@ -301,7 +382,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x5D => (Mnemonic::EOR, AddressingMode::AbsoluteX { penalty: true }),
         0x59 => (Mnemonic::EOR, AddressingMode::AbsoluteY { penalty: true }),
         0x41 => (Mnemonic::EOR, AddressingMode::IndexedIndirect),
-        0x51 => (Mnemonic::EOR, AddressingMode::IndirectIndexed),
+        0x51 => (
+            Mnemonic::EOR,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0x09 => (Mnemonic::ORA, AddressingMode::Immediate),
         0x05 => (Mnemonic::ORA, AddressingMode::ZeroPage),
         0x15 => (Mnemonic::ORA, AddressingMode::ZeroPageX),
@@ -309,7 +393,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x1D => (Mnemonic::ORA, AddressingMode::AbsoluteX { penalty: true }),
         0x19 => (Mnemonic::ORA, AddressingMode::AbsoluteY { penalty: true }),
         0x01 => (Mnemonic::ORA, AddressingMode::IndexedIndirect),
-        0x11 => (Mnemonic::ORA, AddressingMode::IndirectIndexed),
+        0x11 => (
+            Mnemonic::ORA,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0x24 => (Mnemonic::BIT, AddressingMode::ZeroPage),
         0x2C => (Mnemonic::BIT, AddressingMode::Absolute),
 
@@ -320,7 +407,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x7D => (Mnemonic::ADC, AddressingMode::AbsoluteX { penalty: true }),
         0x79 => (Mnemonic::ADC, AddressingMode::AbsoluteY { penalty: true }),
         0x61 => (Mnemonic::ADC, AddressingMode::IndexedIndirect),
-        0x71 => (Mnemonic::ADC, AddressingMode::IndirectIndexed),
+        0x71 => (
+            Mnemonic::ADC,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0xE9 => (Mnemonic::SBC, AddressingMode::Immediate),
         0xE5 => (Mnemonic::SBC, AddressingMode::ZeroPage),
         0xF5 => (Mnemonic::SBC, AddressingMode::ZeroPageX),
@@ -328,7 +418,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xFD => (Mnemonic::SBC, AddressingMode::AbsoluteX { penalty: true }),
         0xF9 => (Mnemonic::SBC, AddressingMode::AbsoluteY { penalty: true }),
         0xE1 => (Mnemonic::SBC, AddressingMode::IndexedIndirect),
-        0xF1 => (Mnemonic::SBC, AddressingMode::IndirectIndexed),
+        0xF1 => (
+            Mnemonic::SBC,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0xC9 => (Mnemonic::CMP, AddressingMode::Immediate),
         0xC5 => (Mnemonic::CMP, AddressingMode::ZeroPage),
         0xD5 => (Mnemonic::CMP, AddressingMode::ZeroPageX),
@@ -336,7 +429,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xDD => (Mnemonic::CMP, AddressingMode::AbsoluteX { penalty: true }),
         0xD9 => (Mnemonic::CMP, AddressingMode::AbsoluteY { penalty: true }),
         0xC1 => (Mnemonic::CMP, AddressingMode::IndexedIndirect),
-        0xD1 => (Mnemonic::CMP, AddressingMode::IndirectIndexed),
+        0xD1 => (
+            Mnemonic::CMP,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0xE0 => (Mnemonic::CPX, AddressingMode::Immediate),
         0xE4 => (Mnemonic::CPX, AddressingMode::ZeroPage),
         0xEC => (Mnemonic::CPX, AddressingMode::Absolute),
@@ -419,7 +515,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xA3 => (Mnemonic::LAX, AddressingMode::IndexedIndirect),
         0xA7 => (Mnemonic::LAX, AddressingMode::ZeroPage),
         0xAF => (Mnemonic::LAX, AddressingMode::Absolute),
-        0xB3 => (Mnemonic::LAX, AddressingMode::IndirectIndexed),
+        0xB3 => (
+            Mnemonic::LAX,
+            AddressingMode::IndirectIndexed { penalty: true },
+        ),
         0xB7 => (Mnemonic::LAX, AddressingMode::ZeroPageY),
         0xBF => (Mnemonic::LAX, AddressingMode::AbsoluteY { penalty: true }),
 
@@ -431,7 +530,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xC3 => (Mnemonic::DCP, AddressingMode::IndexedIndirect),
         0xC7 => (Mnemonic::DCP, AddressingMode::ZeroPage),
         0xCF => (Mnemonic::DCP, AddressingMode::Absolute),
-        0xD3 => (Mnemonic::DCP, AddressingMode::IndirectIndexed),
+        0xD3 => (
+            Mnemonic::DCP,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0xD7 => (Mnemonic::DCP, AddressingMode::ZeroPageX),
         0xDB => (Mnemonic::DCP, AddressingMode::AbsoluteY { penalty: false }),
         0xDF => (Mnemonic::DCP, AddressingMode::AbsoluteX { penalty: false }),
@@ -439,7 +541,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0xE3 => (Mnemonic::ISB, AddressingMode::IndexedIndirect),
         0xE7 => (Mnemonic::ISB, AddressingMode::ZeroPage),
         0xEF => (Mnemonic::ISB, AddressingMode::Absolute),
-        0xF3 => (Mnemonic::ISB, AddressingMode::IndirectIndexed),
+        0xF3 => (
+            Mnemonic::ISB,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0xF7 => (Mnemonic::ISB, AddressingMode::ZeroPageX),
         0xFB => (Mnemonic::ISB, AddressingMode::AbsoluteY { penalty: false }),
         0xFF => (Mnemonic::ISB, AddressingMode::AbsoluteX { penalty: false }),
@@ -447,7 +552,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x03 => (Mnemonic::SLO, AddressingMode::IndexedIndirect),
         0x07 => (Mnemonic::SLO, AddressingMode::ZeroPage),
         0x0F => (Mnemonic::SLO, AddressingMode::Absolute),
-        0x13 => (Mnemonic::SLO, AddressingMode::IndirectIndexed),
+        0x13 => (
+            Mnemonic::SLO,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0x17 => (Mnemonic::SLO, AddressingMode::ZeroPageX),
         0x1B => (Mnemonic::SLO, AddressingMode::AbsoluteY { penalty: false }),
         0x1F => (Mnemonic::SLO, AddressingMode::AbsoluteX { penalty: false }),
@@ -455,7 +563,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x23 => (Mnemonic::RLA, AddressingMode::IndexedIndirect),
         0x27 => (Mnemonic::RLA, AddressingMode::ZeroPage),
         0x2F => (Mnemonic::RLA, AddressingMode::Absolute),
-        0x33 => (Mnemonic::RLA, AddressingMode::IndirectIndexed),
+        0x33 => (
+            Mnemonic::RLA,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0x37 => (Mnemonic::RLA, AddressingMode::ZeroPageX),
         0x3B => (Mnemonic::RLA, AddressingMode::AbsoluteY { penalty: false }),
         0x3F => (Mnemonic::RLA, AddressingMode::AbsoluteX { penalty: false }),
@@ -463,7 +574,10 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x43 => (Mnemonic::SRE, AddressingMode::IndexedIndirect),
         0x47 => (Mnemonic::SRE, AddressingMode::ZeroPage),
         0x4F => (Mnemonic::SRE, AddressingMode::Absolute),
-        0x53 => (Mnemonic::SRE, AddressingMode::IndirectIndexed),
+        0x53 => (
+            Mnemonic::SRE,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0x57 => (Mnemonic::SRE, AddressingMode::ZeroPageX),
         0x5B => (Mnemonic::SRE, AddressingMode::AbsoluteY { penalty: false }),
         0x5F => (Mnemonic::SRE, AddressingMode::AbsoluteX { penalty: false }),
@@ -471,18 +585,43 @@ pub(crate) fn decode(opcode: Byte) -> (Mnemonic, AddressingMode) {
         0x63 => (Mnemonic::RRA, AddressingMode::IndexedIndirect),
         0x67 => (Mnemonic::RRA, AddressingMode::ZeroPage),
         0x6F => (Mnemonic::RRA, AddressingMode::Absolute),
-        0x73 => (Mnemonic::RRA, AddressingMode::IndirectIndexed),
+        0x73 => (
+            Mnemonic::RRA,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
         0x77 => (Mnemonic::RRA, AddressingMode::ZeroPageX),
         0x7B => (Mnemonic::RRA, AddressingMode::AbsoluteY { penalty: false }),
         0x7F => (Mnemonic::RRA, AddressingMode::AbsoluteX { penalty: false }),
 
-        _ => (Mnemonic::NOP, AddressingMode::Implicit),
+        0x0B | 0x2B => (Mnemonic::ANC, AddressingMode::Immediate),
+        0x4B => (Mnemonic::ALR, AddressingMode::Immediate),
+        0x6B => (Mnemonic::ARR, AddressingMode::Immediate),
+        0xCB => (Mnemonic::AXS, AddressingMode::Immediate),
+        0xBB => (Mnemonic::LAS, AddressingMode::AbsoluteY { penalty: true }),
+
+        0x9E => (Mnemonic::SHX, AddressingMode::AbsoluteY { penalty: false }),
+        0x9C => (Mnemonic::SHY, AddressingMode::AbsoluteX { penalty: false }),
+        0x93 => (
+            Mnemonic::SHA,
+            AddressingMode::IndirectIndexed { penalty: false },
+        ),
+        0x9F => (Mnemonic::SHA, AddressingMode::AbsoluteY { penalty: false }),
+        0x9B => (Mnemonic::TAS, AddressingMode::AbsoluteY { penalty: false }),
+
+        // Unstable; mixes the magic constant in like XAA
+        0xAB => (Mnemonic::LAX, AddressingMode::Immediate),
+        0x8B => (Mnemonic::XAA, AddressingMode::Immediate),
+
+        0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+            (Mnemonic::JAM, AddressingMode::Implicit)
+        }
     }
 }
 
 impl Bus for Nes {
     fn read(&mut self, addr: impl Into<Word>) -> Byte {
         self.cpu.cycles += 1;
+        self.catch_up();
         self.read_bus(addr)
     }
 
@@ -492,23 +631,28 @@ impl Bus for Nes {
         let value = value.into();
         let v: u16 = value.into();
 
+        self.cpu.cycles += 1;
+        self.catch_up();
         if a == 0x4014u16 {
-            // OAMDMA
+            // OAMDMA after the write cycle
+            // halt cycle, and one more to align the first read to a get cycle
+            self.cpu.cycles += 1;
+            self.catch_up();
+            if self.cpu.cycles % 2 == 1 {
+                self.cpu.cycles += 1;
+                self.catch_up();
+            }
             let start: u16 = v * 0x100u16;
-            for a in start..(start + 0xFF) {
+            for a in start..=(start + 0xFF) {
+                self.cpu.cycles += 1;
+                self.catch_up();
                 let data = self.read_bus(a);
                 self.cpu.cycles += 1;
+                self.catch_up();
                 self.write_bus(0x2004u16, data);
-                self.cpu.cycles += 1;
-            }
-            // dummy cycles
-            self.cpu.cycles += 1;
-            if self.cpu.cycles % 2 == 1 {
-                self.cpu.cycles += 1;
             }
             return;
         }
-        self.cpu.cycles += 1;
         self.write_bus(addr, value);
     }
 }
@@ -525,12 +669,14 @@ pub(crate) enum AddressingMode {
     AbsoluteX { penalty: bool },
     AbsoluteY { penalty: bool },
     Relative,
-    Indirect, IndexedIndirect, IndirectIndexed,
+    Indirect, IndexedIndirect,
+    IndirectIndexed { penalty: bool },
 }
 
 // http://obelisk.me.uk/6502/reference.html
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[rustfmt::skip]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum Mnemonic {
     // Load/Store Operations
     LDA, LDX, LDY, STA, STX, STY,
@@ -556,8 +702,13 @@ pub(crate) enum Mnemonic {
     BRK, NOP,
     // Unofficial
     LAX, SAX, DCP, ISB, SLO, RLA, SRE, RRA,
+    ANC, ALR, ARR, AXS, LAS, SHX, SHY, SHA, TAS, XAA,
+    JAM,
 }
 
+// Commonly used for the unstable XAA and LAX #imm, which vary by chip and temperature
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 type Operand = Word;
 
 mod instructions {
@@ -702,31 +853,18 @@ mod instructions {
 
         // ADd with Carry
         pub(super) fn adc(&mut self, operand: Operand) {
-            let a = self.cpu.a;
             let val = self.read(operand);
-            let mut result = a + val;
-
-            if self.cpu.p.contains(Status::C) {
-                result += 1;
-            }
-
-            // http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
-            let a7 = a.nth(7);
-            let v7 = val.nth(7);
-            let c6 = a7 ^ v7 ^ result.nth(7);
-            let c7 = (a7 & v7) | (a7 & c6) | (v7 & c6);
-
-            self.cpu.p.set(Status::C, c7 == 1);
-            self.cpu.p.set(Status::V, (c6 ^ c7) == 1);
-
-            self.cpu.a = result;
-            self.cpu.p.set_zn(self.cpu.a)
+            self.add_with_carry(val)
         }
 
         // SuBtract with carry
         pub(super) fn sbc(&mut self, operand: Operand) {
+            let val = self.read(operand);
+            self.add_with_carry(!val)
+        }
+
+        fn add_with_carry(&mut self, val: Byte) {
             let a = self.cpu.a;
-            let val = !self.read(operand);
             let mut result = a + val;
 
             if self.cpu.p.contains(Status::C) {
@@ -748,7 +886,12 @@ mod instructions {
 
         // CoMPare accumulator
         pub(super) fn cmp(&mut self, operand: Operand) {
-            let cmp = Word::from(self.cpu.a) - Word::from(self.read(operand));
+            let value = self.read(operand);
+            self.compare(value)
+        }
+
+        fn compare(&mut self, value: Byte) {
+            let cmp = Word::from(self.cpu.a) - Word::from(value);
             let cmp_i16 = <Word as Into<i16>>::into(cmp);
 
             self.cpu.p.set(Status::C, 0 <= cmp_i16);
@@ -775,11 +918,12 @@ mod instructions {
 
         // INCrement memory
         pub(super) fn inc(&mut self, operand: Operand) {
-            let result = self.read(operand) + 1;
+            let data = self.read(operand);
+            let result = data + 1;
 
             self.cpu.p.set_zn(result);
+            self.write(operand, data);
             self.write(operand, result);
-            self.cpu.cycles += 1
         }
 
         // INcrement X register
@@ -798,11 +942,12 @@ mod instructions {
 
         // DECrement memory
         pub(super) fn dec(&mut self, operand: Operand) {
-            let result = self.read(operand) - 1;
+            let data = self.read(operand);
+            let result = data - 1;
 
             self.cpu.p.set_zn(result);
+            self.write(operand, data);
             self.write(operand, result);
-            self.cpu.cycles += 1
         }
 
         // DEcrement X register
@@ -822,13 +967,13 @@ mod instructions {
         // Arithmetic Shift Left
         pub(super) fn asl(&mut self, operand: Operand) {
             let mut data = self.read(operand);
+            self.write(operand, data);
 
             self.cpu.p.set(Status::C, data.nth(7) == 1);
             data <<= 1;
             self.cpu.p.set_zn(data);
 
             self.write(operand, data);
-            self.cpu.cycles += 1;
         }
 
         pub(super) fn asl_for_accumelator(&mut self, _: Operand) {
@@ -842,13 +987,13 @@ mod instructions {
         // Logical Shift Right
         pub(super) fn lsr(&mut self, operand: Operand) {
             let mut data = self.read(operand);
+            self.write(operand, data);
 
             self.cpu.p.set(Status::C, data.nth(0) == 1);
             data >>= 1;
             self.cpu.p.set_zn(data);
 
             self.write(operand, data);
-            self.cpu.cycles += 1;
         }
 
         pub(super) fn lsr_for_accumelator(&mut self, _: Operand) {
@@ -862,6 +1007,7 @@ mod instructions {
         // ROtate Left
         pub(super) fn rol(&mut self, operand: Operand) {
             let mut data = self.read(operand);
+            self.write(operand, data);
             let c = data.nth(7);
 
             data <<= 1;
@@ -871,7 +1017,6 @@ mod instructions {
             self.cpu.p.set(Status::C, c == 1);
             self.cpu.p.set_zn(data);
             self.write(operand, data);
-            self.cpu.cycles += 1;
         }
 
         pub(super) fn rol_for_accumelator(&mut self, _: Operand) {
@@ -890,6 +1035,7 @@ mod instructions {
         // ROtate Right
         pub(super) fn ror(&mut self, operand: Operand) {
             let mut data = self.read(operand);
+            self.write(operand, data);
             let c = data.nth(0);
 
             data >>= 1;
@@ -899,7 +1045,6 @@ mod instructions {
             self.cpu.p.set(Status::C, c == 1);
             self.cpu.p.set_zn(data);
             self.write(operand, data);
-            self.cpu.cycles += 1;
         }
 
         pub(super) fn ror_for_accumelator(&mut self, _: Operand) {
@@ -1083,38 +1228,45 @@ mod instructions {
 
         // Decrement memory and ComPare to accumulator
         pub(super) fn dcp(&mut self, operand: Operand) {
-            let result = self.read(operand) - 1;
+            let data = self.read(operand);
+            let result = data - 1;
             self.cpu.p.set_zn(result);
+            self.write(operand, data);
             self.write(operand, result);
 
-            self.cmp(operand)
+            self.compare(result)
         }
 
         // Increment memory and SuBtract with carry
         pub(super) fn isb(&mut self, operand: Operand) {
-            let result = self.read(operand) + 1;
+            let data = self.read(operand);
+            let result = data + 1;
             self.cpu.p.set_zn(result);
+            self.write(operand, data);
             self.write(operand, result);
 
-            self.sbc(operand)
+            self.add_with_carry(!result)
         }
 
         // arithmetic Shift Left and bitwise Or with accumulator
         pub(super) fn slo(&mut self, operand: Operand) {
             let mut data = self.read(operand);
+            self.write(operand, data);
 
             self.cpu.p.set(Status::C, data.nth(7) == 1);
             data <<= 1;
             self.cpu.p.set_zn(data);
             self.write(operand, data);
 
-            self.ora(operand)
+            self.cpu.a |= data;
+            self.cpu.p.set_zn(self.cpu.a)
         }
 
         // Rotate Left and bitwise And with accumulator
         pub(super) fn rla(&mut self, operand: Operand) {
             // rotateLeft excluding tick
             let mut data = self.read(operand);
+            self.write(operand, data);
             let c = data & 0x80;
 
             data <<= 1;
@@ -1127,26 +1279,30 @@ mod instructions {
 
             self.write(operand, data);
 
-            self.and(operand)
+            self.cpu.a &= data;
+            self.cpu.p.set_zn(self.cpu.a)
         }
 
         // logical Shift Right and bitwise Exclusive or
         pub(super) fn sre(&mut self, operand: Operand) {
             // logicalShiftRight excluding tick
             let mut data = self.read(operand);
+            self.write(operand, data);
 
             self.cpu.p.set(Status::C, data.nth(0) == 1);
             data >>= 1;
             self.cpu.p.set_zn(data);
             self.write(operand, data);
 
-            self.eor(operand)
+            self.cpu.a ^= data;
+            self.cpu.p.set_zn(self.cpu.a)
         }
 
         // Rotate Right and Add with carry
         pub(super) fn rra(&mut self, operand: Operand) {
             // rotateRight excluding tick
             let mut data = self.read(operand);
+            self.write(operand, data);
             let c = data.nth(0);
 
             data >>= 1;
@@ -1158,11 +1314,148 @@ mod instructions {
 
             self.write(operand, data);
 
-            self.adc(operand)
+            self.add_with_carry(data)
+        }
+
+        // bitwise AND with accumulator, then copy N to C
+        pub(super) fn anc(&mut self, operand: Operand) {
+            self.and(operand);
+            self.cpu.p.set(Status::C, self.cpu.a.nth(7) == 1);
+        }
+
+        // bitwise AND with accumulator, then Logical shift Right
+        pub(super) fn alr(&mut self, operand: Operand) {
+            let value = self.read(operand);
+            let mut data = self.cpu.a & value;
+
+            self.cpu.p.set(Status::C, data.nth(0) == 1);
+            data >>= 1;
+            self.cpu.a = data;
+            self.cpu.p.set_zn(data);
+        }
+
+        // bitwise AND with accumulator, then Rotate Right
+        // C is bit 6 of the result and V is bit 6 xor bit 5
+        pub(super) fn arr(&mut self, operand: Operand) {
+            let value = self.read(operand);
+            let mut data = (self.cpu.a & value) >> 1;
+            if self.cpu.p.contains(Status::C) {
+                data |= 0x80
+            }
+            self.cpu.a = data;
+            self.cpu.p.set_zn(data);
+            self.cpu.p.set(Status::C, data.nth(6) == 1);
+            self.cpu.p.set(Status::V, (data.nth(6) ^ data.nth(5)) == 1);
+        }
+
+        // A AND X, then Subtract from X without borrow
+        pub(super) fn axs(&mut self, operand: Operand) {
+            let value = self.read(operand);
+            let ax = self.cpu.a & self.cpu.x;
+
+            self.cpu.p.set(Status::C, value <= ax);
+            self.cpu.x = ax - value;
+            self.cpu.p.set_zn(self.cpu.x);
+        }
+
+        // Load A, X and S with memory AND S
+        pub(super) fn las(&mut self, operand: Operand) {
+            let data = self.read(operand) & self.cpu.s;
+            self.cpu.a = data;
+            self.cpu.x = data;
+            self.cpu.s = data;
+            self.cpu.p.set_zn(data);
+        }
+
+        // Store X AND high byte of the address + 1
+        pub(super) fn shx(&mut self, operand: Operand) {
+            self.store_and_high(operand, self.cpu.y, self.cpu.x)
+        }
+
+        // Store Y AND high byte of the address + 1
+        pub(super) fn shy(&mut self, operand: Operand) {
+            self.store_and_high(operand, self.cpu.x, self.cpu.y)
+        }
+
+        // Store A AND X AND high byte of the address + 1
+        pub(super) fn sha(&mut self, operand: Operand) {
+            self.store_and_high(operand, self.cpu.y, self.cpu.a & self.cpu.x)
+        }
+
+        // Transfer A AND X to S, then store S AND high byte of the address + 1
+        pub(super) fn tas(&mut self, operand: Operand) {
+            self.cpu.s = self.cpu.a & self.cpu.x;
+            self.store_and_high(operand, self.cpu.y, self.cpu.s)
+        }
+
+        // Unstable; A = (A OR magic) AND X AND immediate
+        pub(super) fn xaa(&mut self, operand: Operand) {
+            let value = self.read(operand);
+            self.cpu.a = (self.cpu.a | UNSTABLE_MAGIC) & self.cpu.x & value;
+            self.cpu.p.set_zn(self.cpu.a);
+        }
+
+        // Unstable LAX #imm; A = X = (A OR magic) AND immediate
+        pub(super) fn lxa(&mut self, operand: Operand) {
+            let value = self.read(operand);
+            self.cpu.a = (self.cpu.a | UNSTABLE_MAGIC) & value;
+            self.cpu.x = self.cpu.a;
+            self.cpu.p.set_zn(self.cpu.a);
+        }
+
+        // Locks up the CPU until reset
+        // https://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+        pub(super) fn jam(&mut self, opcode: Byte) {
+            let pc = self.cpu.pc - 1;
+            self.cpu.pc = pc;
+            self.cpu.fault = Some(CpuFault {
+                pc: pc.into(),
+                opcode: opcode.into(),
+                trace: self.cpu.recent_instructions(),
+            });
+        }
+
+        // SHX, SHY, SHA and TAS are ANDed with the high byte of the base address + 1.
+        // When the page is crossed, the stored value also replaces the high byte of the address.
+        fn store_and_high(&mut self, operand: Operand, index: Byte, value: Byte) {
+            let addr: u16 = operand.into();
+            let base = addr.wrapping_sub(index.u8() as u16);
+            let data = value & ((base >> 8) as u8).wrapping_add(1);
+            let addr = if page_crossed_u16(index, base) {
+                (data.u8() as u16) << 8 | (addr & 0xFF)
+            } else {
+                addr
+            };
+            self.write(addr, data)
         }
     }
 }
 
+impl Cpu {
+    // Called for every instruction, so it only overwrites the oldest entry
+    fn record(&mut self, opcode: Byte) {
+        self.recent[self.recorded % RECENT_INSTRUCTIONS] = InstructionTrace {
+            pc: self.pc.into(),
+            opcode: opcode.into(),
+            a: self.a.into(),
+            x: self.x.into(),
+            y: self.y.into(),
+            s: self.s.into(),
+            p: self.p.bits(),
+            cycle: self.cycles,
+        };
+        self.recorded += 1;
+    }
+
+    // Oldest first
+    fn recent_instructions(&self) -> Vec<InstructionTrace> {
+        let len = self.recorded.min(RECENT_INSTRUCTIONS);
+        (self.recorded - len..self.recorded)
+            .map(|i| self.recent[i % RECENT_INSTRUCTIONS])
+            .collect()
+    }
+}
+
 impl Status {
     fn set_zn(&mut self, value: impl Into<u16>) {
         let v: u16 = value.into();
@@ -1213,10 +1506,15 @@ mod interrupt {
         pub(crate) fn interrupted(&self) -> bool {
             self.p.contains(Status::I)
         }
+
+        pub(crate) fn jammed(&self) -> bool {
+            self.fault.is_some()
+        }
     }
 
     impl Nes {
         pub(crate) fn reset(&mut self) {
+            self.cpu.fault = None;
             self.cpu.cycles += 5;
             self.cpu.pc = self.read_word(0xFFFCu16.into());
             self.cpu.p.insert(Status::I);
@@ -1288,4 +1586,212 @@ mod tests {
         assert_eq!(nes.pull_stack_word(), 0x003Au16.into());
         assert_eq!(nes.pull_stack_word(), 0x98AFu16.into());
     }
+
+    // Executes an instruction placed at $0000 and returns the cycles taken
+    fn execute(nes: &mut Nes, instruction: &[u8]) -> u128 {
+        for (i, b) in instruction.iter().enumerate() {
+            nes.write_bus(i as u16, *b);
+        }
+        nes.cpu.pc = 0x0000u16.into();
+        step(nes)
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut nes = Nes::default();
+        for i in 0..=0xFFu16 {
+            nes.write_bus(0x0200 + i, i as u8);
+        }
+        // STA $4014 with the write on an odd CPU cycle
+        nes.cpu.a = 0x02.into();
+        nes.cpu.cycles = 0;
+        let cycles = execute(&mut nes, &[0x8D, 0x14, 0x40]);
+        // 256 reads and writes after a halt cycle and an alignment cycle
+        assert_eq!(cycles, 4 + 514);
+
+        // Write on an even CPU cycle needs no alignment
+        nes.cpu.cycles = 1;
+        let cycles = execute(&mut nes, &[0x8D, 0x14, 0x40]);
+        assert_eq!(cycles, 4 + 513);
+
+        for i in [0x00u8, 0x80, 0xFF] {
+            nes.write_bus(0x2003u16, i);
+            assert_eq!(nes.read_bus(0x2004u16), i.into());
+        }
+    }
+
+    #[test]
+    fn read_modify_write() {
+        let mut nes = Nes::default();
+
+        // INC $0300
+        nes.write_bus(0x0300u16, 0x7F);
+        assert_eq!(execute(&mut nes, &[0xEE, 0x00, 0x03]), 6);
+        assert_eq!(nes.read_bus(0x0300u16), 0x80.into());
+        assert!(nes.cpu.p.contains(Status::N));
+
+        // ROR $0300 with carry
+        nes.cpu.p.insert(Status::C);
+        assert_eq!(execute(&mut nes, &[0x6E, 0x00, 0x03]), 6);
+        assert_eq!(nes.read_bus(0x0300u16), 0xC0.into());
+        assert!(!nes.cpu.p.contains(Status::C));
+
+        // DCP $0300
+        nes.cpu.a = 0xBF.into();
+        assert_eq!(execute(&mut nes, &[0xCF, 0x00, 0x03]), 6);
+        assert_eq!(nes.read_bus(0x0300u16), 0xBF.into());
+        assert!(nes.cpu.p.contains(Status::Z | Status::C));
+
+        // SLO $0300
+        nes.cpu.a = 0x01.into();
+        assert_eq!(execute(&mut nes, &[0x0F, 0x00, 0x03]), 6);
+        assert_eq!(nes.read_bus(0x0300u16), 0x7E.into());
+        assert_eq!(nes.cpu.a, 0x7F.into());
+        assert!(nes.cpu.p.contains(Status::C));
+
+        // DCP ($10),Y takes 8 cycles without crossing the page
+        nes.write_bus(0x0010u16, 0x00);
+        nes.write_bus(0x0011u16, 0x03);
+        nes.cpu.y = 0x00.into();
+        assert_eq!(execute(&mut nes, &[0xD3, 0x10]), 8);
+        assert_eq!(nes.read_bus(0x0300u16), 0x7D.into());
+    }
+
+    #[test]
+    fn unofficial_immediate() {
+        let mut nes = Nes::default();
+
+        // ANC #$80
+        nes.cpu.a = 0xC0.into();
+        assert_eq!(execute(&mut nes, &[0x0B, 0x80]), 2);
+        assert_eq!(nes.cpu.a, 0x80.into());
+        assert!(nes.cpu.p.contains(Status::C | Status::N));
+
+        // ALR #$03
+        nes.cpu.a = 0xFF.into();
+        assert_eq!(execute(&mut nes, &[0x4B, 0x03]), 2);
+        assert_eq!(nes.cpu.a, 0x01.into());
+        assert!(nes.cpu.p.contains(Status::C));
+
+        // ARR #$FF with carry
+        nes.cpu.a = 0x40.into();
+        nes.cpu.p.insert(Status::C);
+        assert_eq!(execute(&mut nes, &[0x6B, 0xFF]), 2);
+        assert_eq!(nes.cpu.a, 0xA0.into());
+        assert!(!nes.cpu.p.contains(Status::C));
+        assert!(nes.cpu.p.contains(Status::V | Status::N));
+
+        // AXS #$01
+        nes.cpu.a = 0x0F.into();
+        nes.cpu.x = 0xF3.into();
+        assert_eq!(execute(&mut nes, &[0xCB, 0x01]), 2);
+        assert_eq!(nes.cpu.x, 0x02.into());
+        assert!(nes.cpu.p.contains(Status::C));
+
+        // LAX #$5A
+        nes.cpu.a = 0x01.into();
+        assert_eq!(execute(&mut nes, &[0xAB, 0x5A]), 2);
+        assert_eq!(nes.cpu.a, ((UNSTABLE_MAGIC | 0x01) & 0x5A).into());
+        assert_eq!(nes.cpu.x, nes.cpu.a);
+
+        // XAA #$FF
+        nes.cpu.a = 0x00.into();
+        nes.cpu.x = 0x37.into();
+        assert_eq!(execute(&mut nes, &[0x8B, 0xFF]), 2);
+        assert_eq!(nes.cpu.a, (UNSTABLE_MAGIC & 0x37).into());
+    }
+
+    #[test]
+    fn unofficial_store_and_high() {
+        let mut nes = Nes::default();
+
+        // SHX $0180,Y
+        nes.cpu.x = 0xFF.into();
+        nes.cpu.y = 0x10.into();
+        assert_eq!(execute(&mut nes, &[0x9E, 0x80, 0x01]), 5);
+        assert_eq!(nes.read_bus(0x0190u16), 0x02.into());
+
+        // SHY $02F0,X crosses the page; stored to $0110 instead of $0310
+        nes.cpu.x = 0x20.into();
+        nes.cpu.y = 0x01.into();
+        nes.write_bus(0x0310u16, 0xAA);
+        assert_eq!(execute(&mut nes, &[0x9C, 0xF0, 0x02]), 5);
+        assert_eq!(nes.read_bus(0x0110u16), 0x01.into());
+        assert_eq!(nes.read_bus(0x0310u16), 0xAA.into());
+
+        // TAS $0180,Y
+        nes.cpu.a = 0xF0.into();
+        nes.cpu.x = 0x3F.into();
+        nes.cpu.y = 0x00.into();
+        assert_eq!(execute(&mut nes, &[0x9B, 0x80, 0x01]), 5);
+        assert_eq!(nes.cpu.s, 0x30.into());
+        assert_eq!(nes.read_bus(0x0180u16), 0x00.into());
+
+        // LAS $0180,Y
+        nes.write_bus(0x0180u16, 0x1F);
+        assert_eq!(execute(&mut nes, &[0xBB, 0x80, 0x01]), 4);
+        assert_eq!(nes.cpu.a, 0x10.into());
+        assert_eq!(nes.cpu.x, 0x10.into());
+        assert_eq!(nes.cpu.s, 0x10.into());
+
+        // SHA ($10),Y
+        nes.write_bus(0x0010u16, 0x00);
+        nes.write_bus(0x0011u16, 0x03);
+        nes.cpu.a = 0xFF.into();
+        nes.cpu.x = 0x07.into();
+        nes.cpu.y = 0x05.into();
+        assert_eq!(execute(&mut nes, &[0x93, 0x10]), 6);
+        assert_eq!(nes.read_bus(0x0305u16), 0x04.into());
+
+        // SHA ($10),Y takes 6 cycles with the page crossed as well
+        nes.cpu.y = 0xFF.into();
+        assert_eq!(execute(&mut nes, &[0x93, 0x10]), 6);
+    }
+
+    #[test]
+    fn jam() {
+        let mut nes = Nes::default();
+        // LDA #$01; JAM
+        for (i, b) in [0xA9, 0x01, 0x02].iter().enumerate() {
+            nes.write_bus(i as u16, *b);
+        }
+        nes.cpu.pc = 0x0000u16.into();
+        step(&mut nes);
+        step(&mut nes);
+
+        let fault = nes.cpu.fault.clone().unwrap();
+        assert_eq!(fault.pc, 0x0002);
+        assert_eq!(fault.opcode, 0x02);
+        assert_eq!(fault.to_string(), "CPU jammed at $0002 (opcode $02)");
+        assert_eq!(
+            fault.trace.iter().map(|t| t.pc).collect::<Vec<_>>(),
+            [0x0000, 0x0002]
+        );
+        assert_eq!(fault.trace[1].a, 0x01);
+
+        // Stays halted and ignores NMI
+        nes.interrupt.insert(crate::interrupt::Interrupt::NMI);
+        crate::interrupt::handle_interrupt(&mut nes);
+        assert_eq!(step(&mut nes), 1);
+        assert_eq!(nes.cpu.pc, 0x0002u16.into());
+        assert!(nes.cpu.jammed());
+
+        nes.interrupt.insert(crate::interrupt::Interrupt::RESET);
+        crate::interrupt::handle_interrupt(&mut nes);
+        assert!(!nes.cpu.jammed());
+        // Only the last instructions are kept
+        let mut nes = Nes::default();
+        for i in 0..20u16 {
+            nes.write_bus(i, 0xEAu8);
+        }
+        nes.write_bus(20u16, 0x02u8);
+        nes.cpu.pc = 0x0000u16.into();
+        for _ in 0..=20 {
+            step(&mut nes);
+        }
+        let trace = nes.cpu.fault.clone().unwrap().trace;
+        assert_eq!(trace.len(), RECENT_INSTRUCTIONS);
+        assert_eq!(trace[0].pc, 20 - RECENT_INSTRUCTIONS as u16 + 1);
+        assert_eq!(trace[RECENT_INSTRUCTIONS - 1].opcode, 0x02);
+    }
 }
@@ -53,27 +53,27 @@ impl From<Byte> for usize {
     }
 }
 
-impl Into<i8> for Byte {
-    fn into(self) -> i8 {
-        self.0 as i8
+impl From<Byte> for i8 {
+    fn from(value: Byte) -> Self {
+        value.0 as Self
     }
 }
 
-impl Into<i16> for Byte {
-    fn into(self) -> i16 {
-        self.0 as i16
+impl From<Byte> for i16 {
+    fn from(value: Byte) -> Self {
+        value.0 as Self
     }
 }
 
-impl Into<i32> for Byte {
-    fn into(self) -> i32 {
-        self.0 as i32
+impl From<Byte> for i32 {
+    fn from(value: Byte) -> Self {
+        value.0 as Self
     }
 }
 
-impl Into<i64> for Byte {
-    fn into(self) -> i64 {
-        self.0 as i64
+impl From<Byte> for i64 {
+    fn from(value: Byte) -> Self {
+        value.0 as Self
     }
 }
 
@@ -296,21 +296,21 @@ impl From<Byte> for Word {
     }
 }
 
-impl Into<i16> for Word {
-    fn into(self) -> i16 {
-        self.0 as i16
+impl From<Word> for i16 {
+    fn from(value: Word) -> Self {
+        value.0 as Self
     }
 }
 
-impl Into<i32> for Word {
-    fn into(self) -> i32 {
-        self.0 as i32
+impl From<Word> for i32 {
+    fn from(value: Word) -> Self {
+        value.0 as Self
     }
 }
 
-impl Into<i64> for Word {
-    fn into(self) -> i64 {
-        self.0 as i64
+impl From<Word> for i64 {
+    fn from(value: Word) -> Self {
+        value.0 as Self
     }
 }
 
@@ -1,23 +1,51 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::apu::*;
 use crate::controller::*;
-use crate::nes::Nes;
-use crate::rom::Rom;
+use crate::interrupt::Interrupt;
+use crate::nes::{CpuFault, Nes, Region};
+use crate::rom::{
+    apply_patch, disk_diff, extract_rom, find_patch, ips_patch, CartridgeError, FDSFile,
+    MapperRegistry, Rom,
+};
 
 pub struct Emulator {
     nes: Nes,
+    // .sav file of the ROM loaded by `load_rom`
+    save_path: Option<PathBuf>,
+    mapper_registry: MapperRegistry,
+    fds_bios: Option<Vec<u8>>,
+    // Original .fds file to take the difference of the disk from
+    disk_image: Option<Vec<u8>>,
+    // Forced by `set_region` instead of the TV system of the ROM
+    region_override: Option<Region>,
+    rom_region: Region,
 }
 
 impl Emulator {
-    pub fn new(sampling_rate: u32, frame_period: u32) -> Self {
+    // Frame timing follows the region of the loaded ROM or `set_region`,
+    // so only the audio sampling rate is given
+    pub fn with_sampling_rate(sampling_rate: u32) -> Self {
         Self {
-            nes: Nes::new(sampling_rate, frame_period),
+            nes: Nes::new(sampling_rate),
+            save_path: None,
+            mapper_registry: MapperRegistry::default(),
+            fds_bios: None,
+            disk_image: None,
+            region_override: None,
+            rom_region: Region::default(),
         }
     }
 
+    // The APU frame counter period is derived from the region now
+    #[deprecated(note = "use `Emulator::with_sampling_rate`; the frame period follows the region")]
+    pub fn new(sampling_rate: u32, _frame_period: u32) -> Self {
+        Self::with_sampling_rate(sampling_rate)
+    }
+
     pub fn set_audio_buffer(&mut self, audio_buffer: Box<dyn AudioBuffer>) {
         self.nes.apu.audio_buffer = audio_buffer;
     }
@@ -26,16 +54,234 @@ impl Emulator {
         self.nes.step_frame();
     }
 
+    // Set when the CPU has halted on a JAM opcode; frames keep running without CPU until `reset`
+    pub fn cpu_fault(&self) -> Option<&CpuFault> {
+        self.nes.cpu.fault.as_ref()
+    }
+
+    // Presses the reset button
+    pub fn reset(&mut self) {
+        self.nes.interrupt.insert(Interrupt::RESET);
+    }
+
     pub fn set_controllers(&mut self, c1: Box<dyn Controller>, c2: Box<dyn Controller>) {
         self.nes.controller_1 = c1;
         self.nes.controller_2 = c2;
     }
 
+    // Mappers available to `load_rom`
+    pub fn set_mapper_registry(&mut self, registry: MapperRegistry) {
+        self.mapper_registry = registry;
+    }
+
+    // BIOS ROM of the Famicom Disk System RAM adapter, required to load .fds files
+    pub fn set_fds_bios(&mut self, bios: Vec<u8>) {
+        self.fds_bios = Some(bios);
+    }
+
+    // Runs as the region regardless of the ROM; `None` follows the header or the game database
+    pub fn set_region(&mut self, region: Option<Region>) {
+        self.region_override = region;
+        self.nes.set_region(self.region());
+    }
+
+    pub fn region(&self) -> Region {
+        self.region_override.unwrap_or(self.rom_region)
+    }
+
+    // Loads the battery-backed RAM from the .sav file next to the ROM if it exists.
+    // For disk images, the .sav file is an IPS patch to the original image instead.
+    // ROMs in zip, gzip and 7z are extracted.
+    // IPS, BPS or UPS patch with the same name next to the ROM is applied if any
     pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        let rom = Rom::load_file(path)?;
+        self.load(path.as_ref(), None, None)
+    }
+
+    pub fn load_rom_with_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        path: P,
+        patch: Q,
+    ) -> Result<()> {
+        self.load(path.as_ref(), None, Some(patch.as_ref()))
+    }
+
+    // Loads the file named `entry` in the archive
+    pub fn load_archive_entry<P: AsRef<Path>>(&mut self, path: P, entry: &str) -> Result<()> {
+        self.load(path.as_ref(), Some(entry), None)
+    }
+
+    // The new ROM is loaded even if saving the previous cartridge fails,
+    // and then the error is returned
+    fn load(&mut self, path: &Path, entry: Option<&str>, patch: Option<&Path>) -> Result<()> {
+        // Flush the previous cartridge before swapping
+        let saved = self.save_battery_ram();
+
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to open ROM file: {}", path.display()))?;
+        let mut data = extract_rom(data, entry)?;
+        if let Some(patch) = patch.map(Path::to_path_buf).or_else(|| find_patch(path)) {
+            let patch = fs::read(&patch)
+                .with_context(|| format!("Failed to open patch: {}", patch.display()))?;
+            data = apply_patch(data, &patch)?;
+        }
+        let save_path = save_path(path, entry);
+
+        // Everything that can fail is read before the previous cartridge is swapped out
+        let (rom, disk_image, battery_ram) = if FDSFile::is_fds(&data) {
+            let bios = self
+                .fds_bios
+                .clone()
+                .ok_or(CartridgeError::FdsBiosRequired)?;
+            let mut image = data.clone();
+            if save_path.exists() {
+                ips_patch(&mut image, &fs::read(&save_path)?)?;
+            }
+            (Rom::from_fds_data(image, bios)?, Some(data), None)
+        } else {
+            let rom = Rom::from_data_with(data, &self.mapper_registry)?;
+            let battery_ram = if save_path.exists() {
+                Some(fs::read(&save_path)?)
+            } else {
+                None
+            };
+            (rom, None, battery_ram)
+        };
+        self.disk_image = disk_image;
+        self.rom_region = Region::from(rom.timing());
         self.nes.set_rom(rom);
+        self.nes.set_region(self.region());
         self.nes.power_on();
         self.nes.clear();
+
+        if let Some(data) = battery_ram {
+            self.load_battery_ram(&data);
+        }
+        self.save_path = Some(save_path);
+        saved.context("Loaded the ROM but failed to save the previous cartridge")
+    }
+
+    // Number of sides of the inserted disk; 0 for cartridges
+    pub fn disk_sides(&self) -> usize {
+        self.nes.mapper.disk_sides()
+    }
+
+    // Swaps the disk side; the side index counts from 0
+    pub fn insert_disk(&mut self, side: usize) {
+        self.nes.mapper.insert_disk(Some(side));
+    }
+
+    pub fn eject_disk(&mut self) {
+        self.nes.mapper.insert_disk(None);
+    }
+
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.nes.mapper.battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if let Some(ram) = self.nes.mapper.battery_ram_mut() {
+            let len = ram.len().min(data.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    // Writes the battery-backed RAM, or the changes of the disk, into the .sav file next to the ROM
+    pub fn save_battery_ram(&self) -> Result<()> {
+        let path = match &self.save_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let (Some(original), Some(sides)) = (&self.disk_image, self.nes.mapper.disk_image()) {
+            fs::write(path, disk_diff(original, &sides))?;
+        } else if let Some(ram) = self.battery_ram() {
+            fs::write(path, ram)?;
+        }
         Ok(())
     }
 }
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.save_battery_ram();
+    }
+}
+
+// Battery RAM or disk changes next to the ROM; each entry of an archive gets its own file
+fn save_path(path: &Path, entry: Option<&str>) -> PathBuf {
+    let entry = entry
+        .and_then(|e| Path::new(e).file_stem())
+        .and_then(|e| e.to_str());
+    match (entry, path.file_stem().and_then(|s| s.to_str())) {
+        (Some(entry), Some(stem)) => path.with_file_name(format!("{}.{}.sav", stem, entry)),
+        _ => path.with_extension("sav"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_rom(path: &Path, battery: bool) {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1, (battery as u8) << 1];
+        data.resize(16 + 0x6000, 0);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn load_despite_failed_save() {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (battery, other) = (dir.join("battery.nes"), dir.join("other.nes"));
+        write_rom(&battery, true);
+        write_rom(&other, false);
+
+        let mut emulator = Emulator::with_sampling_rate(0);
+        emulator.load_rom(&battery).unwrap();
+        assert!(emulator.battery_ram().is_some());
+
+        // Writing to a directory fails
+        emulator.save_path = Some(dir.clone());
+        assert_matches!(emulator.load_rom(&other), Err(_));
+        assert!(emulator.battery_ram().is_none());
+        assert_eq!(emulator.save_path, Some(dir.join("other.sav")));
+
+        drop(emulator);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_cartridge_if_save_is_unreadable() {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-sav-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (battery, other) = (dir.join("battery.nes"), dir.join("other.nes"));
+        write_rom(&battery, true);
+        write_rom(&other, true);
+        // Reading a directory fails
+        fs::create_dir_all(dir.join("other.sav")).unwrap();
+
+        let mut emulator = Emulator::with_sampling_rate(0);
+        emulator.load_rom(&battery).unwrap();
+        emulator.load_battery_ram(&[0x12; 0x2000]);
+
+        assert_matches!(emulator.load_rom(&other), Err(_));
+        assert_eq!(emulator.battery_ram(), Some(&[0x12; 0x2000][..]));
+        assert_eq!(emulator.save_path, Some(dir.join("battery.sav")));
+
+        drop(emulator);
+        assert_eq!(
+            fs::read(dir.join("battery.sav")).unwrap(),
+            vec![0x12; 0x2000]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_path_per_entry() {
+        let path = Path::new("roms/games.zip");
+        assert_eq!(save_path(path, None), Path::new("roms/games.sav"));
+        assert_eq!(
+            save_path(path, Some("dir/game.nes")),
+            Path::new("roms/games.game.sav")
+        );
+    }
+}
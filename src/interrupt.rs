@@ -32,6 +32,10 @@ pub fn handle_interrupt(nes: &mut Nes) -> u128 {
     let before = nes.cpu.cycles;
 
     let current = nes.interrupt.get();
+    if nes.cpu.jammed() && current != Interrupt::RESET {
+        // Only RESET recovers the CPU
+        return 0;
+    }
     match current {
         Interrupt::RESET => {
             nes.reset();
@@ -41,17 +45,13 @@ pub fn handle_interrupt(nes: &mut Nes) -> u128 {
             nes.non_markable_interrupt();
             nes.interrupt.remove(current)
         }
-        Interrupt::IRQ => {
-            if nes.cpu.interrupted() {
-                nes.interrupt_request();
-                nes.interrupt.remove(current)
-            }
+        Interrupt::IRQ if !nes.cpu.interrupted() => {
+            nes.interrupt_request();
+            nes.interrupt.remove(current)
         }
-        Interrupt::BRK => {
-            if nes.cpu.interrupted() {
-                nes.break_interrupt();
-                nes.interrupt.remove(current)
-            }
+        Interrupt::BRK if nes.cpu.interrupted() => {
+            nes.break_interrupt();
+            nes.interrupt.remove(current)
         }
         _ => {}
     }
@@ -1,3 +1,6 @@
+// `is_multiple_of` on integers needs Rust 1.87
+#![allow(clippy::manual_is_multiple_of)]
+
 #[macro_use]
 extern crate bitflags;
 #[macro_use]
@@ -10,6 +13,7 @@ extern crate assert_matches;
 extern crate anyhow;
 extern crate thiserror;
 
+pub mod controller;
 pub mod emulator;
 pub mod nes;
 pub mod rom;
@@ -25,5 +29,3 @@ mod trace;
 
 mod apu;
 mod ppu;
-
-mod controller;
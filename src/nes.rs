@@ -6,7 +6,8 @@ use crate::interrupt::*;
 use crate::ppu::{self, *};
 use crate::rom::*;
 
-const HEIGHT: usize = 240;
+pub use crate::cpu::{CpuFault, InstructionTrace};
+
 const WIDTH: usize = 256;
 
 const FRAME_BUFFER_LEN: usize = (ppu::MAX_LINE as usize) * (ppu::MAX_DOT as usize);
@@ -28,18 +29,105 @@ pub struct Nes {
     pub(crate) controller_1: Box<dyn Controller>,
     pub(crate) controller_2: Box<dyn Controller>,
 
+    pub(crate) region: Region,
+    // CPU cycle which PPU, APU and the cartridge have been run up to
+    synced_cycles: u128,
+    // PPU dots left over from the previous CPU cycle, in fifths of a dot
+    ppu_dot_fraction: u128,
+
     buffers: [FrameBuffer; 2],
     buffer_index: usize,
 }
 
+// Console timing of each TV system
+// https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclone with PAL video and NTSC-like CPU/PPU clock ratio
+    Dendy,
+}
+
+impl From<Timing> for Region {
+    fn from(timing: Timing) -> Self {
+        match timing {
+            Timing::Pal => Self::Pal,
+            Timing::Dendy => Self::Dendy,
+            // Multiple-region games run on NTSC
+            Timing::Ntsc | Timing::MultipleRegion => Self::Ntsc,
+        }
+    }
+}
+
+impl Region {
+    // CPU cycles per second
+    pub fn cpu_clock_rate(&self) -> u32 {
+        match self {
+            Self::Ntsc => 1789773,
+            Self::Pal => 1662607,
+            Self::Dendy => 1773448,
+        }
+    }
+
+    // PPU dots per 5 CPU cycles; 3 dots per cycle on NTSC and Dendy, 3.2 on PAL
+    pub(crate) fn ppu_dots_per_5_cycles(&self) -> u128 {
+        match self {
+            Self::Ntsc | Self::Dendy => 15,
+            Self::Pal => 16,
+        }
+    }
+
+    // Including the pre-render scanline, which is the last one
+    pub(crate) fn scanlines(&self) -> i16 {
+        match self {
+            Self::Ntsc => 262,
+            Self::Pal | Self::Dendy => 312,
+        }
+    }
+
+    pub(crate) fn pre_render_line(&self) -> i16 {
+        self.scanlines() - 1
+    }
+
+    // Vblank lasts until the pre-render scanline; 20 scanlines on NTSC and Dendy, 70 on PAL.
+    // Dendy has 50 post-render scanlines instead.
+    pub(crate) fn vblank_line(&self) -> i16 {
+        match self {
+            Self::Ntsc | Self::Pal => 241,
+            Self::Dendy => 291,
+        }
+    }
+
+    // The first dot of the pre-render scanline is skipped on odd frames
+    pub(crate) fn skips_odd_frame_dot(&self) -> bool {
+        *self == Self::Ntsc
+    }
+
+    // CPU cycles per quarter frame of the APU frame counter
+    // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    pub(crate) fn frame_counter_period(&self) -> u32 {
+        match self {
+            Self::Ntsc | Self::Dendy => 7457,
+            Self::Pal => 8313,
+        }
+    }
+}
+
 impl Nes {
-    pub(crate) fn new(sampling_rate: u32, frame_period: u32) -> Self {
+    pub(crate) fn new(sampling_rate: u32) -> Self {
         Self {
-            apu: Apu::new(sampling_rate, frame_period),
+            apu: Apu::new(sampling_rate),
             ..Default::default()
         }
     }
 
+    pub(crate) fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.apu.set_region(region);
+    }
+
     pub(crate) fn clear(&mut self) {
         self.interrupt.remove(Interrupt::NMI | Interrupt::IRQ);
         self.interrupt.insert(Interrupt::RESET);
@@ -59,9 +147,12 @@ impl Default for Nes {
             name_table: [Default::default(); 0x1000],
             pallete_ram_idx: [Default::default(); 0x0020],
             mapper: Box::new(MapperDefault {}),
-            apu: Apu::new(0, 0),
+            apu: Apu::new(0),
             controller_1: Box::new(controller::Empty {}),
             controller_2: Box::new(controller::Empty {}),
+            region: Region::default(),
+            synced_cycles: 0,
+            ppu_dot_fraction: 0,
             buffers: [[0; FRAME_BUFFER_LEN], [0; FRAME_BUFFER_LEN]],
             buffer_index: 0,
         }
@@ -78,27 +169,36 @@ impl Nes {
     }
 
     pub fn step(&mut self) {
-        let mut cycles = if self.cpu.interrupted() {
-            handle_interrupt(self)
-        } else {
-            cpu::step(self)
-        };
+        // IRQ is level-triggered, so it follows the lines asserted by the APU and the cartridge
+        self.interrupt
+            .set(Interrupt::IRQ, self.apu.irq() || self.mapper.irq());
+
+        handle_interrupt(self);
+        cpu::step(self);
+        // Internal cycles after the last bus access
+        self.catch_up();
+    }
 
-        for _ in 0..cycles {
-            let cpu_steel = apu::step(self);
-            cycles += cpu_steel;
-        }
+    // Runs the other components up to the current CPU cycle.
+    // Called on every bus access so that registers are read and written at the exact dot.
+    pub(crate) fn catch_up(&mut self) {
+        while self.synced_cycles < self.cpu.cycles {
+            self.synced_cycles += 1;
 
-        let mut ppu_cycles = cycles * 3;
-        while 0 < ppu_cycles {
-            ppu::step(self);
-            ppu_cycles -= 1;
+            // DMC DMA stalls CPU
+            self.cpu.cycles += apu::step(self);
+            self.mapper.cpu_clock();
+
+            let dots = self.region.ppu_dots_per_5_cycles() + self.ppu_dot_fraction;
+            self.ppu_dot_fraction = dots % 5;
+            for _ in 0..dots / 5 {
+                ppu::step(self);
+            }
         }
     }
 
     pub(crate) fn set_rom(&mut self, rom: Rom) {
         self.mapper = rom.mapper;
-        self.ppu.mirroring = self.mapper.mirroring();
     }
 }
 
@@ -112,7 +212,7 @@ impl Nes {
             0x4000..=0x4013 | 0x4015 => self.apu.read_status(),
             0x4016 => self.controller_1.read(),
             0x4017 => self.controller_2.read(),
-            0x4020..=0xFFFF => self.mapper.read(addr),
+            0x4020..=0xFFFF => self.mapper.cpu_read(addr),
             _ => 0u8.into(),
         }
     }
@@ -123,14 +223,17 @@ impl Nes {
         let v = value.into();
         match a {
             0x0000..=0x1FFF => self.wram[a as usize] = v.into(),
-            0x2000..=0x3FFF => self.write_ppu_register(to_ppu_addr(a), v),
+            0x2000..=0x3FFF => {
+                self.mapper.ppu_register_write(to_ppu_addr(a).into(), v);
+                self.write_ppu_register(to_ppu_addr(a), v);
+            }
             0x4000..=0x4013 | 0x4015 => self.apu.write(addr, v),
             0x4016 => self.controller_1.write(v),
             0x4017 => {
                 self.controller_2.write(v);
                 self.apu.write(addr, v);
             }
-            0x4020..=0xFFFF => self.mapper.write(addr, v),
+            0x4020..=0xFFFF => self.mapper.cpu_write(addr, v),
             _ => {
                 //NOP
             }
@@ -140,7 +243,7 @@ impl Nes {
 
 fn to_ppu_addr(addr: u16) -> u16 {
     // repears every 8 bytes
-    0x2000u16.wrapping_add(addr) % 8
+    0x2000u16 + addr % 8
 }
 
 // frame buffers
@@ -177,11 +280,8 @@ mod test {
             // https://wiki.nesdev.com/w/index.php/CPU_power_up_state#cite_ref-1
             self.cpu.p = cpu::Status::from_bits_truncate(0x24);
             self.cpu.cycles = 7;
-            for _ in 0..7 {
-                ppu::step(self);
-                ppu::step(self);
-                ppu::step(self);
-            }
+            self.synced_cycles = 0;
+            self.catch_up();
 
             loop {
                 handle_interrupt(self);
@@ -189,13 +289,8 @@ mod test {
                 let trace = Trace::new(self);
                 f(&trace);
 
-                let cycles = cpu::step(self);
-
-                let mut ppu_cycles = cycles * 3;
-                while 0 < ppu_cycles {
-                    ppu::step(self);
-                    ppu_cycles -= 1;
-                }
+                cpu::step(self);
+                self.catch_up();
 
                 if 26554 < self.cpu.cycles {
                     break;
@@ -211,10 +306,11 @@ mod test {
         let rom_path = Path::new(nes_dir).join("roms/nes-test-roms/other/nestest.nes");
         let rom = Rom::load_file(rom_path).unwrap();
 
-        let mut nes: Nes = Default::default();
-
-        nes.controller_1 = Box::new(StandardController::default());
-        nes.controller_2 = Box::new(StandardController::default());
+        let mut nes = Nes {
+            controller_1: Box::new(StandardController::default()),
+            controller_2: Box::new(StandardController::default()),
+            ..Default::default()
+        };
 
         nes.set_rom(rom);
         nes.power_on();
@@ -229,4 +325,110 @@ mod test {
             assert_eq!(format!("{}", trace), line);
         });
     }
+
+    // Runs a test ROM reporting through $6000 until it finishes, and asserts it passed.
+    // $6000 is 0x80 while running, 0x81 when it needs a reset, or the result code
+    // once $6001-$6003 hold DE B0 61. The message is a C string from $6004.
+    fn run_test_rom(path: &str) {
+        let nes_dir = env!("CARGO_MANIFEST_DIR");
+
+        let rom_path = Path::new(nes_dir).join("roms/nes-test-roms").join(path);
+        let rom = Rom::load_file(rom_path).unwrap();
+
+        let mut nes = Nes {
+            controller_1: Box::new(StandardController::default()),
+            controller_2: Box::new(StandardController::default()),
+            ..Default::default()
+        };
+
+        nes.set_rom(rom);
+        nes.power_on();
+        nes.clear();
+
+        let mut reset_delay = None;
+        // 60 seconds at most
+        for _ in 0..3600 {
+            nes.step_frame();
+
+            let signature: Vec<u8> = (0x6001..=0x6003u16).map(|a| nes.read_bus(a).u8()).collect();
+            if signature != [0xDE, 0xB0, 0x61] {
+                continue;
+            }
+            match nes.read_bus(0x6000u16).u8() {
+                0x80 => {}
+                0x81 => {
+                    // Pressing reset takes at least 100 msec
+                    let delay = reset_delay.get_or_insert(6);
+                    *delay -= 1;
+                    if *delay == 0 {
+                        nes.interrupt.insert(Interrupt::RESET);
+                        reset_delay = None;
+                    }
+                }
+                result => {
+                    let message: String = (0x6004u16..0x8000)
+                        .map(|a| nes.read_bus(a).u8())
+                        .take_while(|&c| c != 0)
+                        .map(char::from)
+                        .collect();
+                    assert_eq!(result, 0, "{}: {}", path, message);
+                    return;
+                }
+            }
+        }
+        panic!("{} did not finish", path);
+    }
+
+    #[test]
+    fn instr_timing() {
+        run_test_rom("instr_timing/instr_timing.nes");
+    }
+
+    #[test]
+    fn cpu_dummy_writes() {
+        run_test_rom("cpu_dummy_writes/cpu_dummy_writes_oam.nes");
+        run_test_rom("cpu_dummy_writes/cpu_dummy_writes_ppumem.nes");
+    }
+
+    #[test]
+    fn ppu_vbl_nmi() {
+        run_test_rom("ppu_vbl_nmi/ppu_vbl_nmi.nes");
+    }
+
+    #[test]
+    fn mmc3_test() {
+        run_test_rom("mmc3_test_2/rom_singles/1-clocking.nes");
+        run_test_rom("mmc3_test_2/rom_singles/2-details.nes");
+        run_test_rom("mmc3_test_2/rom_singles/3-A12_clocking.nes");
+        run_test_rom("mmc3_test_2/rom_singles/4-scanline_timing.nes");
+        run_test_rom("mmc3_test_2/rom_singles/5-MMC3.nes");
+    }
+
+    #[test]
+    fn all_instrs() {
+        run_test_rom("instr_test-v5/all_instrs.nes");
+    }
+
+    #[test]
+    fn cpu_exec_space() {
+        run_test_rom("cpu_exec_space/test_cpu_exec_space_ppuio.nes");
+        run_test_rom("cpu_exec_space/test_cpu_exec_space_apu.nes");
+    }
+
+    #[test]
+    fn apu_irq_is_kept_with_mapper() {
+        let mut nes = Nes::default();
+        // JMP $0000 with IRQ disabled
+        for (i, b) in [0x4C, 0x00, 0x00].iter().enumerate() {
+            nes.write_bus(i as u16, *b);
+        }
+        nes.cpu.p.insert(cpu::Status::I);
+
+        // The frame counter asserts IRQ at the end of the 4-step sequence
+        while nes.cpu.cycles < 30000 {
+            nes.step();
+        }
+        assert!(!nes.mapper.irq());
+        assert!(nes.interrupt.contains(Interrupt::IRQ));
+    }
 }
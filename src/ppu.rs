@@ -3,9 +3,10 @@ use std::ops;
 use crate::data_unit::*;
 use crate::interrupt::*;
 use crate::nes::*;
-use crate::rom::Mirroring;
+use crate::rom::{Mirroring, PpuFetch};
 
 pub const MAX_DOT: u16 = 340;
+// Pre-render scanline on NTSC
 pub const MAX_LINE: i16 = 261;
 
 const SPRITE_LIMIT: usize = 8;
@@ -48,8 +49,6 @@ pub(crate) struct Ppu {
     sprites: [Spr; SPRITE_LIMIT],
     sprite_zero_on_line: bool,
 
-    pub mirroring: Mirroring,
-
     scan: Scan,
 
     pub(crate) frames: u64,
@@ -138,14 +137,14 @@ impl Scan {
         self.dot += 1;
     }
 
-    fn next(&mut self) -> bool {
+    fn next(&mut self, scanlines: i16) -> bool {
         self.dot = self.dot.wrapping_add(1);
-        if MAX_DOT <= self.dot {
-            self.dot %= MAX_DOT;
+        if MAX_DOT < self.dot {
+            self.dot = 0;
 
             self.line += 1;
 
-            if MAX_LINE < self.line {
+            if scanlines <= self.line {
                 self.line = 0;
                 return true;
             }
@@ -162,17 +161,26 @@ pub(crate) fn step(nes: &mut Nes) {
     let dot = scan.dot;
     let line = scan.line;
     let v = nes.ppu.v;
+    let pre_render_line = nes.region.pre_render_line();
+    // Pre-render and visible scanlines
+    let rendering_line = line == pre_render_line || (0..=239).contains(&line);
 
     match (dot, line) {
-        (1..=256 | 322..=336, 261 | 0..=239) => {
+        (1..=256 | 322..=336, _) if rendering_line => {
             nes.ppu.bg_shift();
             match dot % 8 {
                 1 => {
                     // Fetch nametable byte
+                    if render_enabled {
+                        nes.mapper.ppu_fetch(PpuFetch::NameTable, dot, line);
+                    }
                     nes.ppu.nt_latch = nes.read_ppu(v.tile_addr());
                 }
                 3 => {
                     // Fetch attribute table byte
+                    if render_enabled {
+                        nes.mapper.ppu_fetch(PpuFetch::Attribute, dot, line);
+                    }
                     nes.ppu.at_latch = nes.read_ppu(v.attr_addr());
                 }
                 5 => {
@@ -180,20 +188,31 @@ pub(crate) fn step(nes: &mut Nes) {
                     let base = nes.ppu.ctrl.bg_table();
                     let index = nes.ppu.nt_latch * TILE_HEIGHT * 1;
                     let addr = base + index + v.fine_y_scroll();
+                    if render_enabled {
+                        nes.mapper.ppu_fetch(PpuFetch::BackgroundPattern, dot, line);
+                        nes.mapper.ppu_address(addr);
+                    }
                     nes.ppu.bg.low = nes.read_ppu(addr).into();
                 }
                 7 => {
                     // Fetch tile bitmap high byte
                     let base = nes.ppu.ctrl.bg_table();
                     let index = nes.ppu.nt_latch * TILE_HEIGHT * 1;
-                    let addr = base + index + v.fine_y_scroll();
-                    nes.ppu.bg.high = nes.read_ppu(addr + TILE_HEIGHT).into();
+                    let addr = base + index + v.fine_y_scroll() + TILE_HEIGHT;
+                    if render_enabled {
+                        nes.mapper.ppu_fetch(PpuFetch::BackgroundPattern, dot, line);
+                        nes.mapper.ppu_address(addr);
+                    }
+                    nes.ppu.bg.high = nes.read_ppu(addr).into();
                 }
                 _ => {}
             }
         }
-        (337 | 339, 261 | 0..=239) => {
+        (337 | 339, _) if rendering_line => {
             // Unused name table fetches
+            if render_enabled {
+                nes.mapper.ppu_fetch(PpuFetch::NameTable, dot, line);
+            }
             nes.ppu.nt_latch = nes.read_ppu(v.tile_addr());
         }
         _ => {}
@@ -203,7 +222,7 @@ pub(crate) fn step(nes: &mut Nes) {
         let mut v = nes.ppu.v;
         let t = nes.ppu.t;
         match (dot, line) {
-            (256, 261 | 0..=239) => {
+            (256, _) if rendering_line => {
                 // https://wiki.nesdev.com/w/index.php?title=PPU_scrolling#Y_increment
                 if (v & 0x07000) != 7.into() {
                     // fine Y < 7
@@ -222,30 +241,30 @@ pub(crate) fn step(nes: &mut Nes) {
                     nes.ppu.v = (v & !0x03E0) | (y << 5);
                 }
             }
-            (257, 261 | 0..=239) => {
+            (257, _) if rendering_line => {
                 // http://wiki.nesdev.com/w/index.php/PPU_scrolling#At_dot_257_of_each_scanline
                 // v: ....F.. ...EDCBA = t: ....F.. ...EDCBA
                 nes.ppu.v = (v & !0b100_00011111u16) | (t & 0b100_00011111u16);
             }
-            (280..=304, 261) => {
+            (280..=304, _) if line == pre_render_line => {
                 // http://wiki.nesdev.com/w/index.php/PPU_scrolling#During_dots_280_to_304_of_the_pre-render_scanline_.28end_of_vblank.29
                 // v: IHGF.ED CBA..... = t: IHGF.ED CBA.....
                 nes.ppu.v = (v & !0b1111011_11100000) | (t & 0b1111011_11100000);
             }
-            (9..=256, 261 | 0..=239) => {
+            (9..=256, _) if rendering_line => {
                 nes.ppu.bg_reload_shift();
             }
-            (1..=256 | 322..=336, 261 | 0..=239) if dot % 8 == 7 => {
+            (1..=256 | 322..=336, _) if rendering_line && dot % 8 == 7 => {
                 // http://wiki.nesdev.com/w/index.php/PPU_scrolling#Coarse_X_increment
                 if v.coarse_x_scroll() == 31u16.into() {
-                    v = v & !0b11111; // coarse X = 0
+                    v &= !0b11111; // coarse X = 0
                     v ^= 0x0400; // switch horizontal nametable
                 } else {
                     v += 1;
                 }
                 nes.ppu.v = v;
             }
-            (1..=256 | 322..=336, 261 | 0..=239) if dot % 8 == 3 => {
+            (1..=256 | 322..=336, _) if rendering_line && dot % 8 == 3 => {
                 if v.coarse_x_scroll().nth(0) == 1 {
                     nes.ppu.at_latch >>= 1
                 }
@@ -260,15 +279,22 @@ pub(crate) fn step(nes: &mut Nes) {
     // sprites
     match (dot, line) {
         // Pre-render/Visible scanline
-        (0, 261 | 0..=239) => {
+        (0, _) if rendering_line => {
             nes.oam.clear();
             nes.ppu.sprite_zero_on_line = false;
 
             nes.oam.eval_sprites(&scan, &mut nes.ppu);
         }
-        (257..=320, 261 | 0..=239) => {
+        (257..=320, _) if rendering_line => {
             let (i, spr) = nes.oam.fetch_sprite(&scan);
             nes.ppu.sprites[i] = spr;
+            if render_enabled && (dot % 8 == 5 || dot % 8 == 7) {
+                // Sprite tile fetches; only observed by mappers, pixels are read while rendering
+                let addr =
+                    nes.ppu.sprite_pattern_addr(&spr, line) + if dot % 8 == 7 { 8 } else { 0 };
+                nes.mapper.ppu_fetch(PpuFetch::SpritePattern, dot, line);
+                nes.mapper.ppu_address(addr.into());
+            }
         }
         _ => {}
     }
@@ -298,13 +324,23 @@ pub(crate) fn step(nes: &mut Nes) {
     }
 
     match (dot, line) {
-        (_, 261) => {
-            if render_enabled && nes.ppu.frames % 2 == 0 {
+        (_, _) if line == pre_render_line => {
+            if dot == 1 {
+                // End of VBLANK
+                nes.ppu
+                    .status
+                    .remove(Status::VBLANK | Status::SPRITE_ZERO_HIT | Status::SPRITE_OVERFLOW);
+            }
+            if dot == MAX_DOT - 1
+                && render_enabled
+                && nes.region.skips_odd_frame_dot()
+                && nes.ppu.frames % 2 == 0
+            {
                 // Skip 0 cycle on visible frame
                 scan.skip();
             }
         }
-        (1, 241) => {
+        (1, _) if line == nes.region.vblank_line() => {
             // begin VBLANK
             nes.ppu.status.insert(Status::VBLANK);
             if nes.ppu.ctrl.contains(Controller::NMI) {
@@ -315,9 +351,10 @@ pub(crate) fn step(nes: &mut Nes) {
         _ => {}
     }
 
-    if scan.next() {
+    if scan.next(nes.region.scanlines()) {
         nes.ppu.frames += 1;
     }
+    nes.ppu.scan = scan;
 }
 
 fn get_bg_pixel(nes: &Nes) -> u16 {
@@ -357,25 +394,11 @@ fn get_sprite_pixel(nes: &mut Nes, x: i32, bg_addr: u16, scan: &Scan) -> (u16, S
         if (spr.x as i32) < x - 7 && x < spr.x as i32 {
             continue;
         }
-        let mut row = spr.row(y, nes.ppu.sprite_size());
         let col = spr.col(x as u16);
-        let mut tile_idx = spr.tile_index as u16;
 
-        let base = if nes.ppu.ctrl.contains(Controller::SPRITE_SIZE) {
-            // 8x16 pixels
-            tile_idx &= 0xFE;
-            if 7 < row {
-                tile_idx += 1;
-                row -= 8;
-            }
-            tile_idx & 1
-        } else if nes.ppu.ctrl.contains(Controller::SPR_TABLE_ADDR) {
-            0x1000
-        } else {
-            0x0000
-        };
-
-        let tile_addr = base + tile_idx * 16 + row;
+        let tile_addr = nes.ppu.sprite_pattern_addr(spr, y);
+        nes.mapper
+            .ppu_fetch(PpuFetch::SpritePattern, scan.dot, scan.line);
         let low = nes.read_ppu(tile_addr);
         let high = nes.read_ppu(tile_addr + 8);
 
@@ -405,11 +428,11 @@ impl Nes {
         let addr = addr.into();
         let a: u16 = addr.into();
         match a {
-            0x0000..=0x1FFF => self.mapper.read(addr),
-            0x2000..=0x2FFF => self.name_table[to_name_table_addr(a, &self.ppu.mirroring)],
-            0x3000..=0x3EFF => {
-                self.name_table[to_name_table_addr(addr - 0x1000u16, &self.ppu.mirroring)]
-            }
+            0x0000..=0x1FFF => match self.mapper.chr_ciram_page(addr) {
+                Some(page) => self.name_table[page * 0x0400 + (a as usize & 0x03FF)],
+                None => self.mapper.ppu_read(addr),
+            },
+            0x2000..=0x3EFF => self.read_name_table(a),
             0x3F00..=0x3FFF => self.pallete_ram_idx[to_pallete_addr(a)],
             _ => Default::default(),
         }
@@ -419,14 +442,11 @@ impl Nes {
         let addr = addr.into();
         let a: u16 = addr.into();
         match a {
-            0x0000..=0x1FFF => unimplemented!("mapper"),
-            0x2000..=0x2FFF => {
-                self.name_table[to_name_table_addr(a, &self.ppu.mirroring)] = value.into();
-            }
-            0x3000..=0x3EFF => {
-                self.name_table[to_name_table_addr(addr - 0x1000u16, &self.ppu.mirroring)] =
-                    value.into();
-            }
+            0x0000..=0x1FFF => match self.mapper.chr_ciram_page(addr) {
+                Some(page) => self.name_table[page * 0x0400 + (a as usize & 0x03FF)] = value.into(),
+                None => self.mapper.ppu_write(addr, value.into()),
+            },
+            0x2000..=0x3EFF => self.write_name_table(a, value.into()),
             0x3F00..=0x3FFF => {
                 self.pallete_ram_idx[to_pallete_addr(a)] = value.into();
             }
@@ -435,17 +455,40 @@ impl Nes {
     }
 }
 
+impl Nes {
+    fn read_name_table(&mut self, addr: u16) -> Byte {
+        // $3000-$3EFF mirrors $2000-$2EFF
+        let addr = 0x2000 | (addr & 0x0FFF);
+        match self.mapper.mirroring() {
+            Mirroring::Custom => self
+                .mapper
+                .read_nametable(addr.into(), &self.name_table[..0x0800]),
+            mirroring => self.name_table[to_name_table_addr(addr, &mirroring)],
+        }
+    }
+
+    fn write_name_table(&mut self, addr: u16, value: Byte) {
+        let addr = 0x2000 | (addr & 0x0FFF);
+        match self.mapper.mirroring() {
+            Mirroring::Custom => {
+                self.mapper
+                    .write_nametable(addr.into(), value, &mut self.name_table[..0x0800])
+            }
+            mirroring => self.name_table[to_name_table_addr(addr, &mirroring)] = value,
+        }
+    }
+}
+
 fn to_name_table_addr(base: impl Into<u16>, mirroring: &Mirroring) -> usize {
     let base = base.into();
     match mirroring {
         Mirroring::Vertical => base % 0x0800,
-        Mirroring::Horizontal => {
-            if 0x2000 <= base {
-                0x0800u16.wrapping_sub(base) % 0x0400
-            } else {
-                base % 0x0400
-            }
-        }
+        Mirroring::Horizontal => ((base >> 1) & 0x0400) | (base % 0x0400),
+        Mirroring::SingleScreenLower => base % 0x0400,
+        Mirroring::SingleScreenUpper => 0x0400 | (base % 0x0400),
+        // Use the whole of `Nes::name_table` as CIRAM and VRAM on the cartridge
+        Mirroring::FourScreen => base % 0x1000,
+        Mirroring::Custom => unreachable!("nametables are mapped by the mapper"),
     }
     .into()
 }
@@ -462,7 +505,7 @@ impl Nes {
         let result = match addr.into() {
             0x2002u16 => {
                 let result = self.ppu.read_status() | (self.ppu.internal_data_bus & 0b11111);
-                if self.ppu.scan.line == 241 && self.ppu.scan.dot < 2 {
+                if self.ppu.scan.line == self.region.vblank_line() && self.ppu.scan.dot < 2 {
                     result & !0x80
                 } else {
                     result
@@ -479,6 +522,7 @@ impl Nes {
                 .into()
             }
             0x2007u16 => {
+                self.mapper.ppu_address(self.ppu.v.into());
                 let v: u16 = self.ppu.v.into();
                 let result = if v <= 0x3EFFu16 {
                     let data = self.ppu.data;
@@ -500,8 +544,8 @@ impl Nes {
     pub(crate) fn write_ppu_register(&mut self, addr: impl Into<Word>, value: Byte) {
         let addr = addr.into();
         let addr: u16 = addr.into();
-        match addr.into() {
-            0x2000u16 => self.ppu.write_controller(value.into()),
+        match addr {
+            0x2000 => self.ppu.write_controller(value.into()),
             0x2001 => self.ppu.mask = Mask::from_bits_truncate(value.into()),
             0x2003 => {
                 let addr: u16 = value.into();
@@ -509,11 +553,12 @@ impl Nes {
             }
             0x2004 => {
                 self.oam.primary[self.ppu.oam_address] = value.into();
-                self.ppu.oam_address = self.ppu.oam_address.wrapping_add(1);
+                self.ppu.oam_address = (self.ppu.oam_address + 1) & 0xFF;
             }
             0x2005 => self.ppu.write_scroll(value),
             0x2006 => self.ppu.write_vram_address(value),
             0x2007 => {
+                self.mapper.ppu_address(self.ppu.v.into());
                 self.write_ppu(self.ppu.v, value);
                 self.ppu.incr_v();
             }
@@ -689,26 +734,24 @@ impl Oam {
 
     fn eval_sprites(&mut self, scan: &Scan, ppu: &mut Ppu) {
         let sprite_size = ppu.sprite_size() as i16;
-        let mut iter = self.secondary.iter_mut();
 
         let mut n = 0;
         for i in 0..SPRITE_COUNT {
             let first = i * 4;
-            let y = self.primary[first];
-
-            if let Some(p) = iter.next() {
-                let row = scan.line.wrapping_sub(self.primary[first] as i16);
-                if row < sprite_size {
-                    if n == 0 {
-                        ppu.sprite_zero_on_line = true;
-                    }
-                    *p = y;
-                    *iter.next().unwrap() = self.primary[first + 1];
-                    *iter.next().unwrap() = self.primary[first + 2];
-                    *iter.next().unwrap() = self.primary[first + 3];
-                    n += 1;
-                }
+            let row = scan.line - self.primary[first] as i16;
+            if row < 0 || sprite_size <= row {
+                continue;
+            }
+            if n == SPRITE_LIMIT {
+                ppu.status.insert(Status::SPRITE_OVERFLOW);
+                break;
+            }
+            if i == 0 {
+                ppu.sprite_zero_on_line = true;
             }
+            let slot = n * 4;
+            self.secondary[slot..slot + 4].copy_from_slice(&self.primary[first..first + 4]);
+            n += 1;
         }
     }
 
@@ -720,14 +763,31 @@ impl Oam {
             Spr {
                 y: self.secondary[n],
                 tile_index: self.secondary[n + 1],
-                attr: SprAttr::from_bits_truncate(self.secondary[n + 1]),
-                x: self.secondary[n + 1],
+                attr: SprAttr::from_bits_truncate(self.secondary[n + 2]),
+                x: self.secondary[n + 3],
             },
         )
     }
 }
 
 impl Ppu {
+    fn sprite_pattern_addr(&self, spr: &Spr, line: i16) -> u16 {
+        let row = spr.row(line, self.sprite_size()) & 0x0F;
+        let tile = spr.tile_index as u16;
+        if self.ctrl.contains(Controller::SPRITE_SIZE) {
+            // 8x16 pixels
+            let table = (tile & 1) * 0x1000;
+            table + (tile & 0xFE) * 16 + if 7 < row { 16 + row - 8 } else { row }
+        } else {
+            let table = if self.ctrl.contains(Controller::SPR_TABLE_ADDR) {
+                0x1000
+            } else {
+                0x0000
+            };
+            table + tile * 16 + (row & 0x07)
+        }
+    }
+
     fn sprite_size(&self) -> i8 {
         if self.ctrl.contains(Controller::SPRITE_SIZE) {
             16
@@ -854,3 +914,132 @@ impl ops::BitXorAssign<u16> for VramAddr {
         *self = Self(self.0 ^ rhs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_table_mirroring() {
+        let addrs = [0x2000u16, 0x2400, 0x2800, 0x2C00];
+        let mapped = |mirroring: Mirroring| {
+            addrs
+                .iter()
+                .map(|&a| to_name_table_addr(a + 0x10, &mirroring))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(mapped(Mirroring::Vertical), [0x010, 0x410, 0x010, 0x410]);
+        assert_eq!(mapped(Mirroring::Horizontal), [0x010, 0x010, 0x410, 0x410]);
+        assert_eq!(mapped(Mirroring::SingleScreenLower), [0x010; 4]);
+        assert_eq!(mapped(Mirroring::SingleScreenUpper), [0x410; 4]);
+        assert_eq!(mapped(Mirroring::FourScreen), [0x010, 0x410, 0x810, 0xC10]);
+    }
+
+    #[test]
+    fn scan_position_is_kept_between_steps() {
+        let mut nes = Nes::default();
+        for _ in 0..3 {
+            step(&mut nes);
+        }
+        assert_eq!(nes.ppu.scan, Scan { dot: 3, line: 0 });
+
+        for _ in 3..=MAX_DOT {
+            step(&mut nes);
+        }
+        assert_eq!(nes.ppu.scan, Scan { dot: 0, line: 1 });
+    }
+
+    #[test]
+    fn frame_length_by_region() {
+        for (region, scanlines) in [
+            (Region::Ntsc, 262),
+            (Region::Pal, 312),
+            (Region::Dendy, 312),
+        ] {
+            let mut nes = Nes::default();
+            nes.set_region(region);
+
+            let mut dots = 0;
+            let mut vblank_line = None;
+            while nes.ppu.frames == 0 {
+                step(&mut nes);
+                dots += 1;
+                if vblank_line.is_none() && nes.ppu.status.contains(Status::VBLANK) {
+                    vblank_line = Some(nes.ppu.scan.line);
+                }
+            }
+            assert_eq!(dots, scanlines * (MAX_DOT as i32 + 1), "{:?}", region);
+            // VBLANK has been set at dot 1 and cleared on the pre-render scanline
+            assert_eq!(vblank_line, Some(region.vblank_line()), "{:?}", region);
+            assert!(!nes.ppu.status.contains(Status::VBLANK));
+        }
+    }
+
+    #[test]
+    fn register_read_at_current_dot() {
+        let mut nes = Nes::default();
+        // LDA $2002
+        for (i, b) in [0xAD, 0x02, 0x20].iter().enumerate() {
+            nes.write_bus(i as u16, *b);
+        }
+        // VBLANK begins at the 2nd dot of the first CPU cycle of the instruction
+        nes.ppu.scan = Scan {
+            dot: MAX_DOT - 1,
+            line: nes.region.vblank_line() - 1,
+        };
+
+        crate::cpu::step(&mut nes);
+        // The read on the 4th cycle sees 12 dots later
+        assert_eq!(nes.ppu.scan.dot, 10);
+        assert_eq!(nes.cpu.a.u8() & 0x80, 0x80);
+    }
+
+    #[test]
+    fn sprite_evaluation_with_mixed_oam() {
+        let mut nes = Nes::default();
+        nes.ppu.ctrl.insert(Controller::SPRITE_SIZE);
+        nes.ppu.mask.insert(Mask::SPRITE | Mask::BG);
+
+        // Off-screen by default
+        for i in 0..SPRITE_COUNT {
+            nes.oam.primary[i * 4] = 0xF0;
+        }
+        // (sprite, Y): 9 sprites hit line 0x40, sprite 8 is 16 rows above it
+        let sprites = [
+            (1, 0x40),
+            (2, 0x40),
+            (3, 0x40),
+            (4, 0x40),
+            (5, 0x40),
+            (6, 0x40),
+            (7, 0x31),
+            (8, 0x30),
+            (9, 0x40),
+            (10, 0x40),
+        ];
+        for &(i, y) in sprites.iter() {
+            nes.oam.primary[i * 4..i * 4 + 4].copy_from_slice(&[y, i as u8 * 2, 0x40, i as u8 * 8]);
+        }
+
+        let mut overflow_line = None;
+        let mut line_0x41 = None;
+        while nes.ppu.frames == 0 {
+            step(&mut nes);
+            if overflow_line.is_none() && nes.ppu.status.contains(Status::SPRITE_OVERFLOW) {
+                overflow_line = Some(nes.ppu.scan.line);
+            }
+            if nes.ppu.scan == (Scan { dot: 0, line: 0x41 }) {
+                line_0x41 = Some(nes.ppu.sprites);
+            }
+        }
+        assert_eq!(overflow_line, Some(0x40));
+
+        let sprites = line_0x41.unwrap();
+        let xs: Vec<_> = sprites.iter().map(|s| s.x).collect();
+        assert_eq!(xs, [8, 16, 24, 32, 40, 48, 56, 72]);
+        assert!(sprites.iter().all(|s| s.attr == SprAttr::FLIP_HORIZONTALLY));
+        assert_eq!(sprites[6].y, 0x31);
+        assert_eq!(sprites[7].tile_index, 18);
+        assert!(!nes.ppu.sprite_zero_on_line);
+    }
+}
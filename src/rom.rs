@@ -1,101 +1,637 @@
+mod archive;
+mod eeprom;
+mod fds;
+mod fdsfile;
+mod gamedb;
 mod inesfile;
 mod mapper_0;
+mod mapper_1;
+mod mapper_11;
+mod mapper_16;
+mod mapper_18;
+mod mapper_19;
+mod mapper_2;
+mod mapper_206;
+mod mapper_21;
+mod mapper_24;
+mod mapper_3;
+mod mapper_34;
+mod mapper_4;
+mod mapper_5;
+mod mapper_66;
+mod mapper_69;
+mod mapper_7;
+// Included by build.rs; compiled into the crate only for its tests
+#[cfg(test)]
+mod nes20db;
+mod patch;
+mod rominfo;
+mod uniffile;
+mod vrc_irq;
 
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 
 use anyhow::{Context, Result};
 use thiserror::Error;
 
-use crate::data_unit::*;
+pub use crate::data_unit::{Byte, Word};
+
+pub use self::archive::{extract_rom, is_archive};
+pub(crate) use self::fdsfile::disk_diff;
+pub use self::fdsfile::FDSFile;
+use self::gamedb::checksums;
+pub use self::gamedb::{GameDatabase, GameInfo};
+pub use self::inesfile::{ConsoleType, INESFile, Timing};
+pub(crate) use self::patch::ips_patch;
+pub use self::patch::{apply_patch, find_patch, PATCH_EXTENSIONS};
+pub use self::rominfo::RomInfo;
+pub use self::uniffile::UNIFFile;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum Mirroring {
+    #[default]
     Vertical,
     Horizontal,
+    SingleScreenLower,
+    SingleScreenUpper,
+    // Extra 2KB VRAM on the cartridge gives 4 unique nametables
+    FourScreen,
+    // Nametables are mapped by the mapper through `Mapper::read_nametable`/`write_nametable`
+    Custom,
 }
 
-impl Default for Mirroring {
-    fn default() -> Self {
-        Self::Vertical
-    }
+// Memory fetches by the PPU while rendering
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PpuFetch {
+    NameTable,
+    Attribute,
+    BackgroundPattern,
+    SpritePattern,
 }
 
 pub trait Mapper {
-    fn read(&mut self, addr: Word) -> Byte;
-    fn write(&mut self, addr: Word, value: Byte);
+    // CPU address space at $4020-$FFFF
+    fn cpu_read(&mut self, addr: Word) -> Byte;
+    fn cpu_write(&mut self, addr: Word, value: Byte);
+
+    // PPU address space at $0000-$1FFF
+    fn ppu_read(&mut self, addr: Word) -> Byte;
+    // CHR-ROM is read-only
+    fn ppu_write(&mut self, _addr: Word, _value: Byte) {}
+
     fn mirroring(&self) -> Mirroring;
+
+    // Called on every CPU cycle (M2)
+    fn cpu_clock(&mut self) {}
+
+    // Observes addresses on the PPU bus while rendering or accessing $2007
+    fn ppu_address(&mut self, _addr: Word) {}
+
+    // Called before each fetch while rendering with the current dot and scanline
+    fn ppu_fetch(&mut self, _fetch: PpuFetch, _dot: u16, _line: i16) {}
+
+    // Snoops CPU writes to PPU registers at $2000-$2007
+    fn ppu_register_write(&mut self, _addr: Word, _value: Byte) {}
+
+    // Accesses to $2000-$2FFF in Mirroring::Custom.
+    // `ciram` is the 2KB VRAM in the console.
+    fn read_nametable(&mut self, addr: Word, ciram: &[Byte]) -> Byte {
+        let addr: u16 = addr.into();
+        ciram[addr as usize % 0x0800]
+    }
+
+    fn write_nametable(&mut self, addr: Word, value: Byte, ciram: &mut [Byte]) {
+        let addr: u16 = addr.into();
+        ciram[addr as usize % 0x0800] = value;
+    }
+
+    // 1KB page of CIRAM mapped into $0000-$1FFF instead of CHR
+    fn chr_ciram_page(&self, _addr: Word) -> Option<usize> {
+        None
+    }
+
+    // IRQ line to the CPU; Interrupt::IRQ is kept asserted while this returns true
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Expansion audio mixed into the APU output
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Battery-backed RAM to be kept across power cycles, if the cartridge has one
+    fn battery_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Number of disk sides on Famicom Disk System
+    fn disk_sides(&self) -> usize {
+        0
+    }
+
+    // `None` ejects the disk
+    fn insert_disk(&mut self, _side: Option<usize>) {}
+
+    // Current content of each disk side in .fds format
+    fn disk_image(&self) -> Option<Vec<Vec<u8>>> {
+        None
+    }
 }
 
 pub struct MapperDefault {}
 
 impl Mapper for MapperDefault {
-    fn read(&mut self, _: Word) -> Byte {
+    fn cpu_read(&mut self, _: Word) -> Byte {
         Default::default()
     }
 
-    fn write(&mut self, _: Word, _: Byte) {
+    fn cpu_write(&mut self, _: Word, _: Byte) {
         // NOP
     }
 
+    fn ppu_read(&mut self, _: Word) -> Byte {
+        Default::default()
+    }
+
     fn mirroring(&self) -> Mirroring {
         Mirroring::Vertical
     }
 }
 
+// Rejects ROMs the board cannot map, e.g. by `INESFile::require_prg_rom`
+pub type MapperConstructor = fn(INESFile) -> Result<Box<dyn Mapper>, CartridgeError>;
+
+// Constructors of mappers keyed by mapper number and NES 2.0 submapper.
+// `Default` gives the built-in mappers.
+pub struct MapperRegistry {
+    constructors: HashMap<(u16, Option<u8>), MapperConstructor>,
+}
+
+impl MapperRegistry {
+    pub fn new() -> Self {
+        Self {
+            constructors: HashMap::new(),
+        }
+    }
+
+    // `None` as submapper matches any submapper which is not registered explicitly.
+    // Registering the same key again overrides the previous constructor.
+    pub fn register(
+        &mut self,
+        mapper_no: u16,
+        submapper_no: Option<u8>,
+        constructor: MapperConstructor,
+    ) {
+        self.constructors
+            .insert((mapper_no, submapper_no), constructor);
+    }
+
+    pub fn find(&self, mapper_no: u16, submapper_no: u8) -> Option<MapperConstructor> {
+        self.constructors
+            .get(&(mapper_no, Some(submapper_no)))
+            .or_else(|| self.constructors.get(&(mapper_no, None)))
+            .copied()
+    }
+}
+
+impl Default for MapperRegistry {
+    fn default() -> Self {
+        use self::mapper_0::Mapper0;
+        use self::mapper_1::Mapper1;
+        use self::mapper_11::Mapper11;
+        use self::mapper_16::Mapper16;
+        use self::mapper_18::Mapper18;
+        use self::mapper_19::Mapper19;
+        use self::mapper_2::Mapper2;
+        use self::mapper_206::Mapper206;
+        use self::mapper_21::Mapper21;
+        use self::mapper_24::Mapper24;
+        use self::mapper_3::Mapper3;
+        use self::mapper_34::Mapper34;
+        use self::mapper_4::Mapper4;
+        use self::mapper_5::Mapper5;
+        use self::mapper_66::Mapper66;
+        use self::mapper_69::Mapper69;
+        use self::mapper_7::Mapper7;
+
+        let mut r = Self::new();
+        r.register(0, None, |rom| Ok(Box::new(Mapper0::new(rom))));
+        r.register(1, None, |rom| Ok(Box::new(Mapper1::new(rom))));
+        r.register(2, None, |rom| {
+            // Last 16KB bank
+            rom.require_prg_rom(0x4000)?;
+            Ok(Box::new(Mapper2::new(rom)))
+        });
+        r.register(3, None, |rom| Ok(Box::new(Mapper3::new(rom))));
+        r.register(4, None, |rom| {
+            // Second last 8KB bank
+            rom.require_prg_rom(0x4000)?;
+            Ok(Box::new(Mapper4::new(rom)))
+        });
+        r.register(5, None, |rom| Ok(Box::new(Mapper5::new(rom))));
+        r.register(7, None, |rom| Ok(Box::new(Mapper7::new(rom))));
+        r.register(11, None, |rom| Ok(Box::new(Mapper11::new(rom))));
+        for n in [16, 159] {
+            r.register(n, None, |rom| {
+                // Last 16KB bank
+                rom.require_prg_rom(0x4000)?;
+                // $6000-$7FFF is taken by the registers and the EEPROM
+                if !rom.trainer.is_empty() {
+                    return Err(CartridgeError::TrainerNotSupported(rom.mapper));
+                }
+                Ok(Box::new(Mapper16::new(rom)))
+            });
+        }
+        r.register(18, None, |rom| {
+            // Last 8KB bank
+            rom.require_prg_rom(0x2000)?;
+            Ok(Box::new(Mapper18::new(rom)))
+        });
+        r.register(19, None, |rom| {
+            // Last 8KB bank
+            rom.require_prg_rom(0x2000)?;
+            Ok(Box::new(Mapper19::new(rom)))
+        });
+        for n in [21, 22, 23, 25] {
+            r.register(n, None, |rom| {
+                // Second last 8KB bank
+                rom.require_prg_rom(0x4000)?;
+                Ok(Box::new(Mapper21::new(rom)))
+            });
+        }
+        for n in [24, 26] {
+            r.register(n, None, |rom| {
+                // Last 8KB bank
+                rom.require_prg_rom(0x2000)?;
+                Ok(Box::new(Mapper24::new(rom)))
+            });
+        }
+        r.register(34, None, |rom| Ok(Box::new(Mapper34::new(rom))));
+        r.register(66, None, |rom| Ok(Box::new(Mapper66::new(rom))));
+        r.register(69, None, |rom| {
+            // Last 8KB bank
+            rom.require_prg_rom(0x2000)?;
+            Ok(Box::new(Mapper69::new(rom)))
+        });
+        r.register(206, None, |rom| {
+            // Second last 8KB bank
+            rom.require_prg_rom(0x4000)?;
+            Ok(Box::new(Mapper206::new(rom)))
+        });
+        r
+    }
+}
+
 pub struct Rom {
-    mapper_no: u8,
+    mapper_no: u16,
+    submapper_no: u8,
+    timing: Timing,
+    console_type: ConsoleType,
+    info: Option<RomInfo>,
     pub(crate) mapper: Box<dyn Mapper>,
 }
 
 impl Rom {
     pub fn from_data(data: Vec<u8>) -> Result<Self> {
-        use binread::{io::Cursor, BinRead};
-
-        use self::inesfile::INESFile;
-        use self::mapper_0::Mapper0;
+        Self::from_data_with(data, &MapperRegistry::default())
+    }
 
-        let mut cur = Cursor::new(data);
-        let ines = INESFile::read(&mut cur)?;
+    // Reads iNES or UNIF, which may be compressed in zip, gzip or 7z
+    pub fn from_data_with(data: Vec<u8>, registry: &MapperRegistry) -> Result<Self> {
+        let (ines, info) = read_cartridge(extract_rom(data, None)?)?;
 
         let mapper_no = ines.mapper;
-        let mapper = match ines.mapper {
-            0 => Ok(Mapper0::new(ines)),
-            no @ _ => Err(CartridgeError::NotSupportedMapper(no)),
-        }?;
+        let submapper_no = ines.submapper;
+        let timing = ines.timing;
+        let console_type = ines.console_type;
+        let constructor =
+            registry
+                .find(mapper_no, submapper_no)
+                .ok_or(CartridgeError::NotSupportedMapper(
+                    mapper_no,
+                    mapper_name(mapper_no),
+                ))?;
+        let mapper = constructor(ines)?;
 
         Ok(Rom {
             mapper_no,
-            mapper: Box::new(mapper),
+            submapper_no,
+            timing,
+            console_type,
+            info: Some(info),
+            mapper,
         })
     }
 
+    // Reads the metadata without constructing the mapper
+    pub fn inspect(data: Vec<u8>) -> Result<RomInfo> {
+        let (_, info) = read_cartridge(extract_rom(data, None)?)?;
+        Ok(info)
+    }
+
+    // Famicom Disk System image with the BIOS ROM of the RAM adapter
+    pub fn from_fds_data(data: Vec<u8>, bios: Vec<u8>) -> Result<Self> {
+        if bios.len() != FDS_BIOS_LEN {
+            return Err(CartridgeError::InvalidFdsBios(bios.len()).into());
+        }
+        let fds = FDSFile::parse(&data)?;
+        Ok(Rom {
+            mapper_no: FDS_MAPPER_NO,
+            submapper_no: 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            info: None,
+            mapper: Box::new(self::fds::Fds::new(fds, bios)),
+        })
+    }
+
+    pub fn mapper_no(&self) -> u16 {
+        self.mapper_no
+    }
+
+    // Always 0 unless NES 2.0 header
+    pub fn submapper_no(&self) -> u8 {
+        self.submapper_no
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn console_type(&self) -> ConsoleType {
+        self.console_type
+    }
+
+    // None for disk images
+    pub fn info(&self) -> Option<&RomInfo> {
+        self.info.as_ref()
+    }
+
+    // Title, region and board of the game identified by the built-in database
+    pub fn game(&self) -> Option<&GameInfo> {
+        self.info.as_ref().and_then(|i| i.game.as_ref())
+    }
+
     pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        use std::io::Read;
+        Self::load_file_with(path, &MapperRegistry::default())
+    }
+
+    pub fn load_file_with<P: AsRef<Path>>(path: P, registry: &MapperRegistry) -> Result<Self> {
+        let buf = fs::read(path.as_ref()).with_context(|| {
+            format!(
+                "Failed to open INES file: {}",
+                path.as_ref().to_str().unwrap_or("unknown")
+            )
+        })?;
+        Self::from_data_with(buf, registry)
+    }
 
-        let mut f = File::open(path.as_ref()).with_context(|| {
+    // Applies IPS, BPS or UPS patch to the extracted ROM image before parsing it
+    pub fn load_file_with_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        patch: Q,
+        registry: &MapperRegistry,
+    ) -> Result<Self> {
+        let buf = fs::read(path.as_ref()).with_context(|| {
             format!(
                 "Failed to open INES file: {}",
                 path.as_ref().to_str().unwrap_or("unknown")
             )
         })?;
+        let patch = fs::read(patch.as_ref()).with_context(|| {
+            format!(
+                "Failed to open patch: {}",
+                patch.as_ref().to_str().unwrap_or("unknown")
+            )
+        })?;
+        Self::from_data_with(apply_patch(extract_rom(buf, None)?, &patch)?, registry)
+    }
 
-        let mut buf = Vec::new();
-        let _ = f.read_to_end(&mut buf);
-        Self::from_data(buf)
+    // Loads the file named `entry` in the archive
+    pub fn load_archive_entry<P: AsRef<Path>>(
+        path: P,
+        entry: &str,
+        registry: &MapperRegistry,
+    ) -> Result<Self> {
+        let buf = fs::read(path.as_ref()).with_context(|| {
+            format!(
+                "Failed to open archive: {}",
+                path.as_ref().to_str().unwrap_or("unknown")
+            )
+        })?;
+        Self::from_data_with(extract_rom(buf, Some(entry))?, registry)
+    }
+
+    // All problems found in the ROM image without loading it, to be shown to users.
+    // Loading succeeds if none of them is fatal.
+    pub fn validate(data: &[u8], registry: &MapperRegistry) -> Vec<CartridgeError> {
+        if is_archive(data) {
+            return match extract_rom(data.to_vec(), None) {
+                Ok(rom) => Self::validate(&rom, registry),
+                Err(e) => vec![into_issue(e)],
+            };
+        }
+        if FDSFile::is_fds(data) {
+            return FDSFile::parse(data)
+                .err()
+                .map(into_issue)
+                .into_iter()
+                .collect();
+        }
+        let (ines, mut issues) = if UNIFFile::is_unif(data) {
+            match UNIFFile::parse(data).and_then(UNIFFile::into_ines) {
+                Ok(ines) => (ines, Vec::new()),
+                Err(e) => return vec![into_issue(e)],
+            }
+        } else {
+            let issues = INESFile::check(data);
+            if issues.iter().any(CartridgeError::is_fatal) {
+                return issues;
+            }
+            match INESFile::parse(data.to_vec()) {
+                Ok(ines) => (ines, issues),
+                Err(e) => return vec![into_issue(e)],
+            }
+        };
+
+        match registry.find(ines.mapper, ines.submapper) {
+            Some(constructor) => issues.extend(constructor(ines).err()),
+            None => issues.push(CartridgeError::NotSupportedMapper(
+                ines.mapper,
+                mapper_name(ines.mapper),
+            )),
+        }
+        issues
     }
 }
 
+// iNES or UNIF corrected by the game database
+fn read_cartridge(data: Vec<u8>) -> Result<(INESFile, RomInfo)> {
+    let mut ines = if UNIFFile::is_unif(&data) {
+        UNIFFile::parse(&data)?.into_ines()?
+    } else {
+        INESFile::parse(data)?
+    };
+    // Header corrections don't change the ROM data, so the checksums are computed once
+    let (crc32, sha1) = checksums(&ines.prg_rom, &ines.chr_rom);
+    let game = GameDatabase::builtin().correct(&mut ines, crc32, &sha1);
+    let info = RomInfo::new(&ines, game, crc32, sha1);
+    Ok((ines, info))
+}
+
+fn into_issue(e: anyhow::Error) -> CartridgeError {
+    e.downcast()
+        .unwrap_or_else(|e| CartridgeError::Malformed(e.to_string()))
+}
+
+// 128KB PRG-ROM and 1KB CHR-ROM banks filled with the number of each bank, for mapper tests
+#[cfg(test)]
+pub(crate) fn test_ines(mapper: u8, prg_banks: usize, chr_banks: usize) -> INESFile {
+    use binread::{io::Cursor, BinRead};
+
+    let prg_bank_len = 0x20000 / prg_banks;
+    let mut data = vec![
+        b'N',
+        b'E',
+        b'S',
+        0x1A,
+        8,
+        (chr_banks / 8) as u8,
+        mapper << 4,
+        mapper & 0xF0,
+        0,
+        0,
+        0,
+    ];
+    data.extend(vec![0; 5]);
+    for bank in 0..prg_banks {
+        data.extend(vec![bank as u8; prg_bank_len]);
+    }
+    for bank in 0..chr_banks {
+        data.extend(vec![bank as u8; 0x0400]);
+    }
+    INESFile::read(&mut Cursor::new(data)).unwrap()
+}
+
+// Common name of iNES mapper number
+// https://wiki.nesdev.com/w/index.php/Mapper
+pub fn mapper_name(mapper_no: u16) -> Option<&'static str> {
+    let name = match mapper_no {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        11 => "Color Dreams",
+        13 => "CPROM",
+        16 => "Bandai FCG",
+        18 => "Jaleco SS88006",
+        19 => "Namco 163",
+        20 => "FDS",
+        21 | 23 | 25 => "VRC4",
+        22 => "VRC2",
+        24 | 26 => "VRC6",
+        32 => "Irem G-101",
+        33 => "Taito TC0190",
+        34 => "BNROM/NINA-001",
+        64 => "RAMBO-1",
+        66 => "GxROM",
+        68 => "Sunsoft-4",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica",
+        73 => "VRC3",
+        75 => "VRC1",
+        79 => "NINA-03/06",
+        85 => "VRC7",
+        118 => "TxSROM",
+        119 => "TQROM",
+        159 => "Bandai LZ93D50",
+        206 => "Namco 108",
+        210 => "Namco 175/340",
+        _ => return None,
+    };
+    Some(name)
+}
+
 impl std::fmt::Debug for Rom {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(fmt, "Cartridge {{ mapper: {:?} }}", self.mapper_no)
+        write!(
+            fmt,
+            "Cartridge {{ mapper: {:?}, submapper: {:?} }}",
+            self.mapper_no, self.submapper_no
+        )
     }
 }
 
+// iNES mapper 20 is reserved for disk images
+const FDS_MAPPER_NO: u16 = 20;
+const FDS_BIOS_LEN: usize = 0x2000;
+
 #[derive(Debug, Error)]
 pub enum CartridgeError {
-    #[error("mapper `{0}` are not supported")]
-    NotSupportedMapper(u8),
+    #[error("mapper `{0}`{} is not supported", .1.map(|n| format!(" ({})", n)).unwrap_or_default())]
+    NotSupportedMapper(u16, Option<&'static str>),
+    #[error("not an iNES file")]
+    InvalidMagic,
+    #[error("header is truncated to {0} bytes")]
+    TruncatedHeader(usize),
+    #[error("PRG-ROM is truncated; expected {expected} bytes but {actual}")]
+    TruncatedPrgRom { expected: usize, actual: usize },
+    #[error("CHR-ROM is truncated; expected {expected} bytes but {actual}")]
+    TruncatedChrRom { expected: usize, actual: usize },
+    // Ignored on loading
+    #[error("garbage in header bytes 11-15: {0:02X?}")]
+    HeaderGarbage([u8; 5]),
+    #[error("malformed ROM: {0}")]
+    Malformed(String),
+    #[error("PRG-ROM of {actual} bytes is too small for mapper `{mapper}`, which needs {expected} bytes")]
+    PrgRomTooSmall {
+        mapper: u16,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("mapper `{0}` has no PRG-RAM to load the trainer into")]
+    TrainerNotSupported(u16),
+    #[error("broken archive: {0}")]
+    Archive(String),
+    #[error("no ROM file in the archive")]
+    NoRomInArchive,
+    #[error("`{0}` is not found in the archive")]
+    ArchiveEntryNotFound(String),
+    #[error("FDS BIOS must be 8KB but {0} bytes")]
+    InvalidFdsBios(usize),
+    #[error("FDS BIOS is required to load disk images")]
+    FdsBiosRequired,
+    #[error("invalid disk image of {0} bytes")]
+    InvalidDiskImage(usize),
+    #[error("invalid patch")]
+    InvalidPatch,
+    #[error("checksum of the {0} does not match the patch")]
+    PatchChecksum(&'static str),
+    #[error("patched ROM of {0} bytes is too large")]
+    PatchedRomTooLarge(usize),
+    #[error("UNIF board `{0}` is not supported")]
+    NotSupportedBoard(String),
+    #[error("invalid UNIF file")]
+    InvalidUnif,
+}
+
+impl CartridgeError {
+    // Whether the ROM can't be loaded
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::HeaderGarbage(_))
+    }
 }
 
 #[cfg(test)]
@@ -110,4 +646,261 @@ mod tests {
         let result = Rom::load_file(path);
         assert_matches!(result, Ok(_));
     }
+
+    struct Dummy(Mirroring);
+
+    impl Mapper for Dummy {
+        fn cpu_read(&mut self, _: Word) -> Byte {
+            0xEA.into()
+        }
+
+        fn cpu_write(&mut self, _: Word, _: Byte) {}
+
+        fn ppu_read(&mut self, _: Word) -> Byte {
+            Default::default()
+        }
+
+        fn mirroring(&self) -> Mirroring {
+            self.0.clone()
+        }
+    }
+
+    fn nes2_rom(mapper: u16, submapper: u8) -> Vec<u8> {
+        let mut data = vec![
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            1,
+            1,
+            ((mapper & 0x0F) << 4) as u8,
+            (mapper & 0xF0) as u8 | 0b1000,
+            (submapper << 4) | (mapper >> 8) as u8,
+        ];
+        data.extend(vec![0; 7]);
+        data.extend(vec![0; 0x6000]);
+        data
+    }
+
+    #[test]
+    fn register_mapper() {
+        let mut registry = MapperRegistry::default();
+        registry.register(0x123, None, |_| Ok(Box::new(Dummy(Mirroring::Vertical))));
+        registry.register(0x123, Some(1), |_| {
+            Ok(Box::new(Dummy(Mirroring::Horizontal)))
+        });
+
+        let mut rom = Rom::from_data_with(nes2_rom(0x123, 0), &registry).unwrap();
+        assert_eq!(rom.mapper_no(), 0x123);
+        assert_eq!(rom.mapper.cpu_read(0x8000u16.into()), 0xEA.into());
+        assert_matches!(rom.mapper.mirroring(), Mirroring::Vertical);
+
+        let rom = Rom::from_data_with(nes2_rom(0x123, 1), &registry).unwrap();
+        assert_eq!(rom.submapper_no(), 1);
+        assert_matches!(rom.mapper.mirroring(), Mirroring::Horizontal);
+
+        let result = Rom::from_data(nes2_rom(0x123, 0));
+        assert_matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(CartridgeError::NotSupportedMapper(0x123, None))
+        );
+    }
+
+    #[test]
+    fn registered_constructor_checks_rom() {
+        // Replaces MMC3 with a board which needs 32KB PRG-ROM
+        let mut registry = MapperRegistry::default();
+        registry.register(4, None, |rom| {
+            rom.require_prg_rom(0x8000)?;
+            Ok(Box::new(Dummy(Mirroring::Vertical)))
+        });
+
+        let data = nes2_rom_with_prg(4, 0x4000);
+        assert_matches!(
+            Rom::validate(&data, &registry)[..],
+            [CartridgeError::PrgRomTooSmall {
+                mapper: 4,
+                expected: 0x8000,
+                actual: 0x4000
+            }]
+        );
+        assert_matches!(
+            Rom::from_data_with(data, &registry)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(CartridgeError::PrgRomTooSmall {
+                expected: 0x8000,
+                ..
+            })
+        );
+
+        // The built-in check of MMC3 is replaced as well
+        let data = nes2_rom_with_prg(4, 0x2000);
+        assert_matches!(Rom::validate(&data, &MapperRegistry::default())[..], [_]);
+        registry.register(4, None, |_| Ok(Box::new(Dummy(Mirroring::Vertical))));
+        assert_matches!(Rom::validate(&data, &registry)[..], []);
+        assert!(Rom::from_data_with(data, &registry).is_ok());
+    }
+
+    #[test]
+    fn inspect_identifies_by_checksums() {
+        let data = nes2_rom(0, 0);
+        let info = Rom::inspect(data.clone()).unwrap();
+        assert_eq!(info.crc32, crc32fast::hash(&data[16..]));
+        assert_eq!(
+            info.sha1,
+            sha1_smol::Sha1::from(&data[16..]).digest().to_string()
+        );
+        assert_eq!(
+            info.game.as_ref(),
+            GameDatabase::builtin().find_by_checksums(info.crc32, &info.sha1)
+        );
+    }
+
+    // NES 2.0 ROM with PRG-ROM of power of two bytes in the exponent-multiplier notation
+    fn nes2_rom_with_prg(mapper: u16, prg_size: usize) -> Vec<u8> {
+        let mut data = nes2_rom(mapper, 0);
+        data[4] = (prg_size.trailing_zeros() << 2) as u8;
+        data[9] = 0x0F;
+        data.truncate(16);
+        data.extend(vec![0; prg_size]);
+        data.extend(vec![0; 0x2000]);
+        data
+    }
+
+    #[test]
+    fn min_prg_rom() {
+        let registry = MapperRegistry::default();
+        for (mapper, min) in [
+            (2, 0x4000),
+            (4, 0x4000),
+            (16, 0x4000),
+            (18, 0x2000),
+            (19, 0x2000),
+            (21, 0x4000),
+            (22, 0x4000),
+            (23, 0x4000),
+            (24, 0x2000),
+            (25, 0x4000),
+            (26, 0x2000),
+            (69, 0x2000),
+            (159, 0x4000),
+            (206, 0x4000),
+        ] {
+            let data = nes2_rom_with_prg(mapper, min / 2);
+            assert_matches!(
+                Rom::validate(&data, &registry)[..],
+                [CartridgeError::PrgRomTooSmall { expected, .. }] if expected == min,
+                "mapper {}", mapper
+            );
+            assert_matches!(
+                Rom::from_data(data).unwrap_err().downcast_ref(),
+                Some(CartridgeError::PrgRomTooSmall { .. })
+            );
+
+            // Every bank is readable at the minimum size
+            let mut rom = Rom::from_data(nes2_rom_with_prg(mapper, min)).unwrap();
+            for addr in (0x8000..=0xFFFFu16).step_by(0x1000) {
+                rom.mapper.cpu_read(addr.into());
+            }
+        }
+    }
+
+    fn nes2_rom_with_trainer(mapper: u16, submapper: u8) -> Vec<u8> {
+        let mut data = nes2_rom(mapper, submapper);
+        data[6] |= 0x04;
+        data.splice(16..16, vec![0x4C; 0x200]);
+        data
+    }
+
+    #[test]
+    fn load_trainer_on_any_mapper() {
+        let registry = MapperRegistry::default();
+        // Bandai FCG has registers at $6000-$7FFF
+        let data = nes2_rom_with_trainer(16, 4);
+        assert_matches!(
+            Rom::validate(&data, &registry)[..],
+            [CartridgeError::TrainerNotSupported(16)]
+        );
+        assert_matches!(
+            Rom::from_data(data).unwrap_err().downcast_ref(),
+            Some(CartridgeError::TrainerNotSupported(16))
+        );
+
+        // Boards without PRG-RAM have it only for the trainer
+        for (mapper, submapper) in [(2, 0), (3, 0), (7, 0), (11, 0), (34, 2), (66, 0), (206, 0)] {
+            let data = nes2_rom_with_trainer(mapper, submapper);
+            assert_matches!(Rom::validate(&data, &registry)[..], [], "mapper {}", mapper);
+            let mut rom = Rom::from_data(data).unwrap();
+            assert_eq!(rom.mapper.cpu_read(0x6FFFu16.into()), 0x00.into());
+            assert_eq!(rom.mapper.cpu_read(0x7000u16.into()), 0x4C.into());
+            rom.mapper.cpu_write(0x6000u16.into(), 0x12.into());
+            assert_eq!(rom.mapper.cpu_read(0x6000u16.into()), 0x12.into());
+        }
+
+        // A registered board loads it as well
+        let mut registry = MapperRegistry::new();
+        registry.register(0x123, None, |rom| {
+            assert_eq!(rom.trainer.len(), 0x200);
+            Ok(Box::new(Dummy(Mirroring::Vertical)))
+        });
+        let data = nes2_rom_with_trainer(0x123, 0);
+        assert!(Rom::from_data_with(data, &registry).is_ok());
+    }
+
+    #[test]
+    fn validate_rom() {
+        let registry = MapperRegistry::default();
+        assert_matches!(Rom::validate(&nes2_rom(0, 0), &registry)[..], []);
+
+        let issues = Rom::validate(&nes2_rom(9, 0), &registry);
+        assert_matches!(
+            issues[..],
+            [CartridgeError::NotSupportedMapper(9, Some("MMC2"))]
+        );
+        assert_eq!(issues[0].to_string(), "mapper `9` (MMC2) is not supported");
+
+        let mut data = nes2_rom(0, 0);
+        data.truncate(0x100);
+        assert_matches!(
+            Rom::validate(&data, &registry)[..],
+            [
+                CartridgeError::TruncatedPrgRom { .. },
+                CartridgeError::TruncatedChrRom { .. }
+            ]
+        );
+        assert_matches!(
+            Rom::validate(b"UNIF", &registry)[..],
+            [CartridgeError::InvalidUnif]
+        );
+        assert_matches!(
+            Rom::validate(b"", &registry)[..],
+            [CartridgeError::InvalidMagic]
+        );
+    }
+
+    #[test]
+    fn inspect_rom() {
+        let mut data = nes2_rom(4, 1);
+        // Battery, vertical mirroring and 8KB PRG-NVRAM
+        data[6] |= 0b0011;
+        data[10] = 0x70;
+        let info = Rom::inspect(data.clone()).unwrap();
+        assert_eq!(info.mapper, 4);
+        assert_eq!(info.submapper, 1);
+        assert!(info.nes2);
+        assert_eq!(info.prg_rom_size, 0x4000);
+        assert_eq!(info.chr_rom_size, 0x2000);
+        assert_eq!(info.prg_nvram_size, 0x2000);
+        assert_eq!(info.mirroring, Mirroring::Vertical);
+        assert!(info.battery);
+        assert!(!info.trainer);
+        assert_eq!(info.timing, Timing::Ntsc);
+        assert_eq!(info.console_type, ConsoleType::Nes);
+        assert_eq!(info.sha1.len(), 40);
+        assert_eq!(info.crc32, crc32fast::hash(&data[16..]));
+
+        let rom = Rom::from_data(data).unwrap();
+        assert_eq!(rom.info(), Some(&info));
+    }
 }
@@ -0,0 +1,185 @@
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::CartridgeError;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+// Far larger than any cartridge or disk image; guards against decompression bombs
+const MAX_ROM_LEN: usize = 0x200_0000;
+
+// Extensions of the entries picked from archives
+const ROM_EXTENSIONS: [&str; 4] = ["nes", "fds", "unf", "unif"];
+
+pub fn is_archive(data: &[u8]) -> bool {
+    [ZIP_MAGIC, GZIP_MAGIC, SEVEN_ZIP_MAGIC]
+        .iter()
+        .any(|magic| data.starts_with(magic))
+}
+
+// Decompresses the ROM image in zip, gzip or 7z; other data is returned as it is.
+// `entry` chooses the file in zip and 7z by its name; the first ROM file is taken otherwise.
+pub fn extract_rom(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    if data.starts_with(ZIP_MAGIC) {
+        extract_zip(data, entry)
+    } else if data.starts_with(GZIP_MAGIC) {
+        read_rom(flate2::read::GzDecoder::new(data.as_slice()), MAX_ROM_LEN)
+    } else if data.starts_with(SEVEN_ZIP_MAGIC) {
+        extract_7z(data, entry)
+    } else {
+        Ok(data)
+    }
+}
+
+// Reads up to `limit` bytes and fails rather than reading more
+fn read_rom<R: Read>(r: R, limit: usize) -> Result<Vec<u8>> {
+    let mut rom = Vec::new();
+    r.take(limit as u64 + 1)
+        .read_to_end(&mut rom)
+        .map_err(|e| CartridgeError::Archive(e.to_string()))?;
+    if limit < rom.len() {
+        return Err(CartridgeError::Archive(format!("ROM image exceeds {} bytes", limit)).into());
+    }
+    Ok(rom)
+}
+
+fn is_chosen(name: &str, entry: Option<&str>) -> bool {
+    match entry {
+        Some(entry) => name == entry,
+        None => Path::new(name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| ROM_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str())),
+    }
+}
+
+fn not_found(entry: Option<&str>) -> CartridgeError {
+    match entry {
+        Some(entry) => CartridgeError::ArchiveEntryNotFound(entry.to_string()),
+        None => CartridgeError::NoRomInArchive,
+    }
+}
+
+fn extract_zip(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    let archive_error = |e: zip::result::ZipError| CartridgeError::Archive(e.to_string());
+
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+    for i in 0..zip.len() {
+        let file = zip.by_index(i).map_err(archive_error)?;
+        if file.is_dir() || !is_chosen(file.name(), entry) {
+            continue;
+        }
+        return read_rom(file, MAX_ROM_LEN);
+    }
+    Err(not_found(entry).into())
+}
+
+fn extract_7z(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    let archive_error = |e: sevenz_rust::Error| CartridgeError::Archive(e.to_string());
+
+    let len = data.len() as u64;
+    let mut reader =
+        sevenz_rust::SevenZReader::new(Cursor::new(data), len, sevenz_rust::Password::empty())
+            .map_err(archive_error)?;
+    let mut rom = None;
+    reader
+        .for_each_entries(|file, r| {
+            if file.is_directory() || !is_chosen(file.name(), entry) {
+                // Entries in a solid block have to be read through
+                std::io::copy(r, &mut std::io::sink())?;
+                return Ok(true);
+            }
+            rom = Some(read_rom(r, MAX_ROM_LEN));
+            Ok(false)
+        })
+        .map_err(archive_error)?;
+    rom.unwrap_or_else(|| Err(not_found(entry).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut w = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            w.start_file(*name, Default::default()).unwrap();
+            w.write_all(data).unwrap();
+        }
+        w.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn extract_from_zip() {
+        let data = zip(&[
+            ("readme.txt", b"readme"),
+            ("game.NES", b"NES\x1A"),
+            ("other.fds", b"FDS\x1A"),
+        ]);
+        assert!(is_archive(&data));
+        assert_eq!(extract_rom(data.clone(), None).unwrap(), b"NES\x1A");
+        assert_eq!(
+            extract_rom(data.clone(), Some("other.fds")).unwrap(),
+            b"FDS\x1A"
+        );
+        assert_matches!(
+            extract_rom(data, Some("none.nes")).unwrap_err().downcast_ref(),
+            Some(CartridgeError::ArchiveEntryNotFound(name)) if name == "none.nes"
+        );
+
+        let data = zip(&[("readme.txt", b"readme")]);
+        assert_matches!(
+            extract_rom(data, None).unwrap_err().downcast_ref(),
+            Some(CartridgeError::NoRomInArchive)
+        );
+        assert_matches!(
+            extract_rom(b"PK\x03\x04broken".to_vec(), None)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(CartridgeError::Archive(_))
+        );
+    }
+
+    #[test]
+    fn extract_from_gzip() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(b"NES\x1A").unwrap();
+        let data = gz.finish().unwrap();
+        assert_eq!(extract_rom(data, None).unwrap(), b"NES\x1A");
+
+        // Not an archive
+        assert_eq!(extract_rom(b"NES\x1A".to_vec(), None).unwrap(), b"NES\x1A");
+    }
+
+    #[test]
+    fn limit_decompressed_size() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&[0; 0x100]).unwrap();
+        let data = gz.finish().unwrap();
+        let r = || flate2::read::GzDecoder::new(data.as_slice());
+        assert_eq!(read_rom(r(), 0x100).unwrap().len(), 0x100);
+        assert_matches!(
+            read_rom(r(), 0xFF).unwrap_err().downcast_ref(),
+            Some(CartridgeError::Archive(_))
+        );
+    }
+
+    #[test]
+    fn extract_from_7z() {
+        let mut w = sevenz_rust::SevenZWriter::new(Cursor::new(Vec::new())).unwrap();
+        for (name, data) in [("readme.txt", &b"readme"[..]), ("game.unf", b"UNIF")] {
+            let mut entry = sevenz_rust::SevenZArchiveEntry::new();
+            entry.name = name.to_string();
+            entry.has_stream = true;
+            w.push_archive_entry(entry, Some(data)).unwrap();
+        }
+        let data = w.finish().unwrap().into_inner();
+        assert_eq!(extract_rom(data.clone(), None).unwrap(), b"UNIF");
+        assert_eq!(extract_rom(data, Some("readme.txt")).unwrap(), b"readme");
+    }
+}
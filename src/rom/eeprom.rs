@@ -0,0 +1,271 @@
+// I2C serial EEPROM on Bandai boards
+// https://wiki.nesdev.com/w/index.php/Bandai_FCG_board#Serial_EEPROM
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum EepromChip {
+    // 128 bytes; 7-bit address and R/W bit follow the start condition, LSB first
+    X24C01,
+    // 256 bytes; device address then word address, MSB first
+    X24C02,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    DeviceAddress,
+    Address,
+    Read,
+    Write,
+    SendAck,
+    WaitAck,
+}
+
+pub(super) struct Eeprom {
+    chip: EepromChip,
+    pub(super) data: Vec<u8>,
+
+    mode: Mode,
+    next_mode: Mode,
+    address: u8,
+    shift: u8,
+    bit: u8,
+    // SDA driven by the EEPROM
+    output: bool,
+
+    scl: bool,
+    sda: bool,
+}
+
+impl Eeprom {
+    pub(super) fn new(chip: EepromChip) -> Self {
+        let len = match chip {
+            EepromChip::X24C01 => 0x80,
+            EepromChip::X24C02 => 0x100,
+        };
+        Self {
+            chip,
+            data: vec![0; len],
+            mode: Mode::Idle,
+            next_mode: Mode::Idle,
+            address: 0,
+            shift: 0,
+            bit: 0,
+            output: true,
+            scl: false,
+            sda: false,
+        }
+    }
+
+    pub(super) fn output(&self) -> bool {
+        self.output
+    }
+
+    // Lines driven by the CPU
+    pub(super) fn write(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && self.sda && !sda {
+            // Start condition
+            self.mode = match self.chip {
+                EepromChip::X24C01 => Mode::Address,
+                EepromChip::X24C02 => Mode::DeviceAddress,
+            };
+            self.bit = 0;
+            self.shift = 0;
+            self.output = true;
+        } else if self.scl && scl && !self.sda && sda {
+            // Stop condition
+            self.mode = Mode::Idle;
+            self.output = true;
+        } else if !self.scl && scl {
+            self.clock_rise(sda);
+        } else if self.scl && !scl {
+            self.clock_fall();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn lsb_first(&self) -> bool {
+        self.chip == EepromChip::X24C01
+    }
+
+    fn receive(&mut self, sda: bool) -> bool {
+        if self.lsb_first() {
+            self.shift |= (sda as u8) << self.bit;
+        } else {
+            self.shift = (self.shift << 1) | sda as u8;
+        }
+        self.bit += 1;
+        self.bit == 8
+    }
+
+    fn index(&self) -> usize {
+        self.address as usize % self.data.len()
+    }
+
+    fn clock_rise(&mut self, sda: bool) {
+        let receiving = matches!(self.mode, Mode::DeviceAddress | Mode::Address | Mode::Write);
+        if receiving && !self.receive(sda) {
+            return;
+        }
+        match self.mode {
+            Mode::DeviceAddress if self.shift & 0xF0 != 0xA0 => self.mode = Mode::Idle,
+            Mode::DeviceAddress => {
+                self.next_mode = if self.shift & 1 != 0 {
+                    Mode::Read
+                } else {
+                    Mode::Address
+                };
+                self.mode = Mode::SendAck;
+            }
+            Mode::Address => {
+                self.next_mode = match self.chip {
+                    // The last bit is R/W
+                    EepromChip::X24C01 => {
+                        self.address = self.shift & 0x7F;
+                        if self.shift & 0x80 != 0 {
+                            Mode::Read
+                        } else {
+                            Mode::Write
+                        }
+                    }
+                    EepromChip::X24C02 => {
+                        self.address = self.shift;
+                        Mode::Write
+                    }
+                };
+                self.mode = Mode::SendAck;
+            }
+            Mode::Write => {
+                let i = self.index();
+                self.data[i] = self.shift;
+                self.address = self.address.wrapping_add(1);
+                self.next_mode = Mode::Write;
+                self.mode = Mode::SendAck;
+            }
+            Mode::Read => {
+                self.bit += 1;
+                if self.bit == 8 {
+                    self.address = self.address.wrapping_add(1);
+                    self.mode = Mode::WaitAck;
+                }
+            }
+            Mode::SendAck => {
+                self.mode = self.next_mode;
+                self.bit = 0;
+                self.shift = 0;
+            }
+            // Continue reading while the CPU acknowledges
+            Mode::WaitAck if !sda => {
+                self.mode = Mode::Read;
+                self.bit = 0;
+            }
+            Mode::WaitAck => self.mode = Mode::Idle,
+            _ => {}
+        }
+    }
+
+    fn clock_fall(&mut self) {
+        self.output = match self.mode {
+            Mode::SendAck => false,
+            Mode::Read => {
+                let value = self.data[self.index()];
+                let n = if self.lsb_first() {
+                    self.bit
+                } else {
+                    7 - self.bit
+                };
+                (value >> n) & 1 != 0
+            }
+            _ => true,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Bus<'a>(&'a mut Eeprom);
+
+    impl Bus<'_> {
+        fn start(&mut self) {
+            self.0.write(false, true);
+            self.0.write(true, true);
+            self.0.write(true, false);
+            self.0.write(false, false);
+        }
+
+        fn stop(&mut self) {
+            self.0.write(false, false);
+            self.0.write(true, false);
+            self.0.write(true, true);
+        }
+
+        fn clock(&mut self, sda: bool) -> bool {
+            self.0.write(false, sda);
+            self.0.write(true, sda);
+            let bit = self.0.output();
+            self.0.write(false, sda);
+            bit
+        }
+
+        fn send(&mut self, value: u8, lsb_first: bool) -> bool {
+            for i in 0..8 {
+                let n = if lsb_first { i } else { 7 - i };
+                self.clock((value >> n) & 1 != 0);
+            }
+            // ACK
+            !self.clock(true)
+        }
+
+        fn receive(&mut self, lsb_first: bool, ack: bool) -> u8 {
+            let mut value = 0;
+            for i in 0..8 {
+                let n = if lsb_first { i } else { 7 - i };
+                value |= (self.clock(true) as u8) << n;
+            }
+            self.clock(!ack);
+            value
+        }
+    }
+
+    #[test]
+    fn x24c02() {
+        let mut eeprom = Eeprom::new(EepromChip::X24C02);
+        let mut bus = Bus(&mut eeprom);
+        bus.start();
+        assert!(bus.send(0xA0, false));
+        assert!(bus.send(0x10, false));
+        assert!(bus.send(0x12, false));
+        assert!(bus.send(0x34, false));
+        bus.stop();
+
+        // Random read
+        bus.start();
+        assert!(bus.send(0xA0, false));
+        assert!(bus.send(0x10, false));
+        bus.start();
+        assert!(bus.send(0xA1, false));
+        assert_eq!(bus.receive(false, true), 0x12);
+        assert_eq!(bus.receive(false, false), 0x34);
+        bus.stop();
+
+        assert_eq!(eeprom.data[0x10..0x12], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn x24c01() {
+        let mut eeprom = Eeprom::new(EepromChip::X24C01);
+        let mut bus = Bus(&mut eeprom);
+        bus.start();
+        assert!(bus.send(0x05, true));
+        assert!(bus.send(0x56, true));
+        bus.stop();
+
+        bus.start();
+        assert!(bus.send(0x85, true));
+        assert_eq!(bus.receive(true, false), 0x56);
+        bus.stop();
+
+        assert_eq!(eeprom.data[0x05], 0x56);
+    }
+}
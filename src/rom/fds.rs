@@ -0,0 +1,680 @@
+use super::fdsfile::{from_raw_side, to_raw_side, FDSFile};
+use super::*;
+use crate::data_unit::*;
+
+// Famicom Disk System RAM adapter and disk drive
+// https://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    // Disk sides with gaps and CRC as the drive reads them
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    // Cycles the disk stays ejected while swapping so that the BIOS notices it
+    insert_delay: u32,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+
+    control: DiskControl,
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+
+    // Drive head
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+
+    audio: FdsAudio,
+}
+
+bitflags! {
+    // $4025
+    struct DiskControl: u8 {
+        const MOTOR_ON = 0x01;
+        const TRANSFER_RESET = 0x02;
+        const READ_MODE = 0x04;
+        const HORIZONTAL_MIRRORING = 0x08;
+        const CRC_CONTROL = 0x10;
+        const READ_WRITE_START = 0x40;
+        const IRQ_ON_TRANSFER = 0x80;
+    }
+}
+
+// The drive transfers a byte in about 150 CPU cycles
+const BYTE_TRANSFER_CYCLES: u32 = 150;
+// Head returns to the start of the disk
+const HEAD_REWIND_CYCLES: u32 = 50000;
+// About a half second
+const INSERT_DELAY_CYCLES: u32 = 900000;
+
+impl Fds {
+    pub(super) fn new(fds: FDSFile, bios: Vec<u8>) -> Self {
+        let sides = fds.sides.iter().map(|s| to_raw_side(s)).collect();
+        Self {
+            bios,
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+            sides,
+            side: Some(0),
+            insert_delay: 0,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            control: DiskControl::empty(),
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    fn inserted_side(&self) -> Option<usize> {
+        if 0 < self.insert_delay {
+            None
+        } else {
+            self.side
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0b01 != 0;
+                self.timer_enabled = value & 0b10 != 0 && self.disk_io_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = value & 0b01 != 0;
+                self.sound_io_enabled = value & 0b10 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_io_enabled => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_io_enabled => {
+                self.control = DiskControl::from_bits_truncate(value);
+                self.disk_irq = false;
+            }
+            0x4040..=0x4092 if self.sound_io_enabled => self.audio.write(addr, value),
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.disk_io_enabled => {
+                let status = self.timer_irq as u8
+                    | (self.transfer_complete as u8) << 1
+                    | (self.end_of_head as u8) << 6;
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                status
+            }
+            0x4031 if self.disk_io_enabled => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            0x4032 if self.disk_io_enabled => {
+                let ejected = self.inserted_side().is_none();
+                ejected as u8 | ((ejected || !self.scanning) as u8) << 1 | (ejected as u8) << 2
+            }
+            // Battery is good
+            0x4033 if self.disk_io_enabled => 0x80,
+            0x4040..=0x4092 if self.sound_io_enabled => self.audio.read(addr),
+            _ => 0,
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if 0 < self.insert_delay {
+            self.insert_delay -= 1;
+        }
+        let side = match self.inserted_side() {
+            Some(side) if self.control.contains(DiskControl::MOTOR_ON) => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.control.contains(DiskControl::TRANSFER_RESET) && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if 0 < self.delay {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let started = self.control.contains(DiskControl::READ_WRITE_START);
+        let crc_control = self.control.contains(DiskControl::CRC_CONTROL);
+        let mut irq = self.control.contains(DiskControl::IRQ_ON_TRANSFER);
+        if self.control.contains(DiskControl::READ_MODE) {
+            let data = self.sides[side][self.position];
+            if !started {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // Gap end mark is consumed silently
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let mut data = 0;
+            if !crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= irq;
+            }
+            // CRC is not verified; two bytes are written as placeholders
+            if !started || crc_control {
+                data = 0;
+            }
+            self.sides[side][self.position] = data;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.sides[side].len() <= self.position {
+            self.control.remove(DiskControl::MOTOR_ON);
+        } else {
+            self.delay = BYTE_TRANSFER_CYCLES;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x4030..=0x4092 => self.read_register(addr),
+            0x6000..=0xDFFF => self.prg_ram[addr as usize - 0x6000],
+            0xE000..=0xFFFF => self.bios[(addr as usize - 0xE000) % self.bios.len()],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        self.chr_ram[addr as usize % self.chr_ram.len()].into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x4020..=0x4092 => self.write_register(addr, value),
+            0x6000..=0xDFFF => self.prg_ram[addr as usize - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let len = self.chr_ram.len();
+        self.chr_ram[addr as usize % len] = value.into();
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.control.contains(DiskControl::HORIZONTAL_MIRRORING) {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn disk_sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    fn insert_disk(&mut self, side: Option<usize>) {
+        self.side = side.filter(|&s| s < self.sides.len());
+        self.insert_delay = INSERT_DELAY_CYCLES;
+    }
+
+    fn disk_image(&self) -> Option<Vec<Vec<u8>>> {
+        Some(self.sides.iter().map(|s| from_raw_side(s)).collect())
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/FDS_audio
+struct FdsAudio {
+    // 6-bit samples at $4040-$407F
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u32,
+
+    volume: Envelope,
+    modulation: Envelope,
+
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+    // 7-bit signed
+    mod_counter: i8,
+
+    master_volume: u8,
+    envelope_speed: u8,
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    // $4080/$4084
+    control: u8,
+    gain: u8,
+    divider: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.control = value;
+        // Direct mode sets the gain immediately
+        if value & 0x80 != 0 {
+            self.gain = value & 0x3F;
+        }
+        self.divider = 0;
+    }
+
+    fn clock(&mut self, envelope_speed: u8) {
+        if self.control & 0x80 != 0 || envelope_speed == 0 {
+            return;
+        }
+        self.divider += 1;
+        let period = 8 * (((self.control & 0x3F) as u32) + 1) * envelope_speed as u32;
+        if self.divider < period {
+            return;
+        }
+        self.divider = 0;
+        if self.control & 0x40 != 0 {
+            if self.gain < 32 {
+                self.gain += 1;
+            }
+        } else if 0 < self.gain {
+            self.gain -= 1;
+        }
+    }
+}
+
+// Relative level of the full volume wave to the APU output
+const FDS_LEVEL: f32 = 0.4;
+
+impl FdsAudio {
+    fn new() -> Self {
+        Self {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            wave_accumulator: 0,
+            volume: Default::default(),
+            modulation: Default::default(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+            mod_counter: 0,
+            master_volume: 0,
+            envelope_speed: 0xE8,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[addr as usize - 0x4040] = value & 0x3F
+            }
+            0x4080 => self.volume.write(value),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.modulation.write(value),
+            0x4085 => {
+                // Sign-extend 7 bits
+                self.mod_counter = ((value << 1) as i8) >> 1;
+            }
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Modulation table is written in pairs while halted
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position] = value & 0b111;
+                self.mod_table[self.mod_position + 1] = value & 0b111;
+                self.mod_position = (self.mod_position + 2) % 64;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0x80 != 0;
+                self.master_volume = value & 0b11;
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave_table[addr as usize - 0x4040],
+            0x4090 => self.volume.gain,
+            0x4092 => self.modulation.gain,
+            _ => 0,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if 0xFFFF < self.mod_accumulator {
+                self.mod_accumulator &= 0xFFFF;
+                self.clock_modulator();
+            }
+        }
+
+        if !self.wave_halted && !self.wave_write_enabled {
+            self.wave_accumulator = (self.wave_accumulator + self.pitch()) & 0x3F_FFFF;
+        }
+    }
+
+    fn clock_modulator(&mut self) {
+        let step = self.mod_table[self.mod_position];
+        self.mod_counter = match step {
+            4 => 0,
+            _ => {
+                let delta = [0, 1, 2, 4, 0, -4, -2, -1][step as usize];
+                // Wrap in 7 bits
+                (self.mod_counter.wrapping_add(delta) << 1) >> 1
+            }
+        };
+        self.mod_position = (self.mod_position + 1) % 64;
+    }
+
+    // Wave frequency changed by the modulator
+    fn pitch(&self) -> u32 {
+        let frequency = self.wave_frequency as i32;
+        if self.mod_halted {
+            return frequency as u32;
+        }
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if 0 < remainder && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if 192 <= temp {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= frequency;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if 32 <= remainder {
+            temp += 1;
+        }
+        (frequency + temp).max(0) as u32
+    }
+
+    fn output(&self) -> f32 {
+        let sample = self.wave_table[(self.wave_accumulator >> 16) as usize & 0x3F] as f32;
+        let gain = self.volume.gain.min(32) as f32;
+        let master = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0][self.master_volume as usize];
+        sample * gain / (63.0 * 32.0) * master * FDS_LEVEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fds() -> Fds {
+        let mut side = b"\x01*NINTENDO-HVC*".to_vec();
+        side.resize(0x38, 0);
+        side.extend([2, 0]);
+        side.resize(super::super::fdsfile::SIDE_LEN, 0);
+        let file = FDSFile {
+            sides: vec![side.clone(), side],
+        };
+        let mut bios = vec![0; 0x2000];
+        bios[0x1FFC] = 0x12;
+        let mut m = Fds::new(file, bios);
+        m.cpu_write(0x4023u16.into(), 0b11.into());
+        m
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut m = fds();
+        assert_eq!(m.cpu_read(0xFFFCu16.into()), 0x12.into());
+        m.cpu_write(0xDFFFu16.into(), 0x34.into());
+        assert_eq!(m.cpu_read(0xDFFFu16.into()), 0x34.into());
+        // BIOS is read-only
+        m.cpu_write(0xFFFCu16.into(), 0x56.into());
+        assert_eq!(m.cpu_read(0xFFFCu16.into()), 0x12.into());
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut m = fds();
+        m.cpu_write(0x4020u16.into(), 2.into());
+        m.cpu_write(0x4021u16.into(), 0.into());
+        m.cpu_write(0x4022u16.into(), 0b10.into());
+        for _ in 0..2 {
+            m.cpu_clock();
+        }
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+        assert_eq!(m.cpu_read(0x4030u16.into()).nth(0), 1);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn read_disk() {
+        let mut m = fds();
+        assert_eq!(m.cpu_read(0x4032u16.into()).nth(0), 0);
+
+        // Motor on, read mode, start reading with IRQ
+        m.cpu_write(0x4025u16.into(), 0xC5.into());
+        let mut read: Vec<u8> = Vec::new();
+        for _ in 0..(HEAD_REWIND_CYCLES + 4000 * BYTE_TRANSFER_CYCLES) {
+            m.cpu_clock();
+            if m.irq() {
+                read.push(m.cpu_read(0x4031u16.into()).into());
+            }
+            if read.len() == 15 {
+                break;
+            }
+        }
+        assert_eq!(read, b"\x01*NINTENDO-HVC*");
+    }
+
+    #[test]
+    fn swap_disk() {
+        let mut m = fds();
+        assert_eq!(m.disk_sides(), 2);
+        m.insert_disk(Some(1));
+        assert_eq!(m.cpu_read(0x4032u16.into()).nth(0), 1);
+        for _ in 0..INSERT_DELAY_CYCLES {
+            m.cpu_clock();
+        }
+        assert_eq!(m.cpu_read(0x4032u16.into()).nth(0), 0);
+
+        m.insert_disk(None);
+        for _ in 0..INSERT_DELAY_CYCLES {
+            m.cpu_clock();
+        }
+        assert_eq!(m.cpu_read(0x4032u16.into()).nth(0), 1);
+    }
+
+    #[test]
+    fn write_disk() {
+        let mut m = fds();
+        // Second byte of the disk info block
+        let i = m.sides[0].iter().position(|&b| b == 0x80).unwrap() + 2;
+        m.sides[0][i] = b'#';
+        let image = m.disk_image().unwrap();
+        assert_eq!(image.len(), 2);
+        assert_eq!(image[0][..3], *b"\x01#N");
+        assert_eq!(image[1][..3], *b"\x01*N");
+    }
+
+    #[test]
+    fn write_disk_through_registers() {
+        use super::super::fdsfile::{GAP_END_MARK, LEAD_IN_LEN};
+
+        let mut m = fds();
+        let block = b"\x01#NINTENDO-HVC#";
+        // Gap is written before the start bit is set, followed by the gap end mark and the block
+        let mut data = vec![0; LEAD_IN_LEN];
+        data.push(GAP_END_MARK);
+        data.extend(block);
+
+        // Motor on, write mode with IRQ
+        m.cpu_write(0x4024u16.into(), data[0].into());
+        m.cpu_write(0x4025u16.into(), 0x81.into());
+        let mut transferred = 0;
+        for _ in 0..(HEAD_REWIND_CYCLES + (data.len() as u32 + 1) * (BYTE_TRANSFER_CYCLES + 1)) {
+            m.cpu_clock();
+            if !m.irq() {
+                continue;
+            }
+            transferred += 1;
+            if transferred == LEAD_IN_LEN {
+                m.cpu_write(0x4025u16.into(), 0xC1.into());
+            }
+            if transferred == data.len() {
+                // CRC follows the block
+                m.cpu_write(0x4025u16.into(), 0xD1.into());
+                break;
+            }
+            m.cpu_write(0x4024u16.into(), data[transferred].into());
+        }
+        assert_eq!(transferred, data.len());
+        for _ in 0..2 * (BYTE_TRANSFER_CYCLES + 1) {
+            m.cpu_clock();
+        }
+        m.cpu_write(0x4025u16.into(), 0x00.into());
+        assert!(!m.irq());
+
+        let image = m.disk_image().unwrap();
+        assert_eq!(image[0][..block.len()], *block);
+        assert!(image[0][block.len()..0x38].iter().all(|&b| b == 0));
+        assert_eq!(image[1][..3], *b"\x01*N");
+    }
+
+    #[test]
+    fn wave_output() {
+        let mut m = fds();
+        m.cpu_write(0x4089u16.into(), 0x80.into());
+        for i in 0..64u16 {
+            m.cpu_write((0x4040 + i).into(), 63.into());
+        }
+        m.cpu_write(0x4089u16.into(), 0.into());
+        m.cpu_write(0x4080u16.into(), 0xA0.into());
+        m.cpu_write(0x4082u16.into(), 0xFF.into());
+        m.cpu_write(0x4083u16.into(), 0x0F.into());
+        m.cpu_clock();
+        assert_eq!(m.cpu_read(0x4090u16.into()), 0x20.into());
+        assert!(0.0 < m.audio_output());
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::Result;
+
+use super::CartridgeError;
+
+// Famicom Disk System image in .fds format with or without fwNES header
+// https://wiki.nesdev.com/w/index.php/FDS_file_format
+#[derive(Debug)]
+pub struct FDSFile {
+    pub sides: Vec<Vec<u8>>,
+}
+
+pub(super) const SIDE_LEN: usize = 65500;
+
+const HEADER_MAGIC: &[u8] = b"FDS\x1A";
+const HEADER_LEN: usize = 16;
+// Disk info block starts with the block code and "*NINTENDO-HVC*"
+const DISK_VERIFICATION: &[u8] = b"\x01*NINTENDO-HVC*";
+
+impl FDSFile {
+    pub fn is_fds(data: &[u8]) -> bool {
+        data.starts_with(HEADER_MAGIC) || data.starts_with(DISK_VERIFICATION)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let body = if data.starts_with(HEADER_MAGIC) {
+            &data[HEADER_LEN.min(data.len())..]
+        } else {
+            data
+        };
+        if body.is_empty() || body.len() % SIDE_LEN != 0 {
+            return Err(CartridgeError::InvalidDiskImage(body.len()).into());
+        }
+        let sides: Vec<Vec<u8>> = body.chunks(SIDE_LEN).map(|s| s.to_vec()).collect();
+        if !sides
+            .iter()
+            .all(|s| s.starts_with(DISK_VERIFICATION) && side_blocks(s).is_some())
+        {
+            return Err(CartridgeError::InvalidDiskImage(body.len()).into());
+        }
+        Ok(Self { sides })
+    }
+}
+
+// Disk side as the drive reads it; each block is preceded by a gap and followed by CRC
+// https://wiki.nesdev.com/w/index.php/FDS_disk_format
+pub(super) const RAW_SIDE_LEN: usize = 0x14000;
+
+// 28300 bits of lead-in and 976 bits between blocks
+pub(super) const LEAD_IN_LEN: usize = 28300 / 8;
+const GAP_LEN: usize = 976 / 8;
+pub(super) const GAP_END_MARK: u8 = 0x80;
+// Not checked by the drive emulation
+const DUMMY_CRC: [u8; 2] = [0x4D, 0x62];
+
+// Length of the block starting with `code` including the code itself
+fn block_len(code: u8, last_file_size: usize) -> Option<usize> {
+    match code {
+        1 => Some(0x38),
+        2 => Some(0x02),
+        3 => Some(0x10),
+        4 => Some(1 + last_file_size),
+        _ => None,
+    }
+}
+
+fn file_size(block3: &[u8]) -> usize {
+    block3[13] as usize | (block3[14] as usize) << 8
+}
+
+// Blocks up to the first unknown block code, or None if a block runs past the end of the side
+fn side_blocks(side: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    let mut last_file_size = 0;
+    while let Some(len) = side.get(pos).and_then(|&c| block_len(c, last_file_size)) {
+        let block = side.get(pos..pos + len)?;
+        if block[0] == 3 {
+            last_file_size = file_size(block);
+        }
+        blocks.push(block);
+        pos += len;
+    }
+    Some(blocks)
+}
+
+pub(super) fn to_raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_LEN];
+    // Sides are checked by FDSFile::parse
+    for block in side_blocks(side).unwrap_or_default() {
+        raw.push(GAP_END_MARK);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&DUMMY_CRC);
+        raw.extend(vec![0; GAP_LEN]);
+    }
+    raw.resize(raw.len().max(RAW_SIDE_LEN), 0);
+    raw
+}
+
+pub(super) fn from_raw_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_LEN);
+    let mut pos = 0;
+    let mut last_file_size = 0;
+    // Skip the gap
+    while let Some(n) = raw[pos..].iter().position(|&b| b == GAP_END_MARK) {
+        pos += n + 1;
+        let len = match raw.get(pos).and_then(|&c| block_len(c, last_file_size)) {
+            Some(len) => len,
+            None => break,
+        };
+        let block = &raw[pos..(pos + len).min(raw.len())];
+        if block[0] == 3 && block.len() == 0x10 {
+            last_file_size = file_size(block);
+        }
+        side.extend_from_slice(block);
+        pos += len + DUMMY_CRC.len();
+        if raw.len() <= pos {
+            break;
+        }
+    }
+    side.resize(SIDE_LEN, 0);
+    side
+}
+
+// Changes of the disk sides from the original .fds file as IPS patch
+pub(crate) fn disk_diff(original: &[u8], sides: &[Vec<u8>]) -> Vec<u8> {
+    let header_len = original.len().saturating_sub(sides.len() * SIDE_LEN);
+    let mut modified = original[..header_len].to_vec();
+    for side in sides {
+        modified.extend_from_slice(side);
+    }
+    ips_diff(original, &modified)
+}
+
+// Changes between two images of the same length in IPS format
+// https://zerosoft.zophar.net/ips.php
+fn ips_diff(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    const MAX_RECORD_LEN: usize = 0xFFFF;
+
+    let mut patch = b"PATCH".to_vec();
+    let mut i = 0;
+    while i < modified.len() {
+        if original.get(i) == Some(&modified[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < modified.len()
+            && original.get(i) != Some(&modified[i])
+            && i - start < MAX_RECORD_LEN
+        {
+            i += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((i - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..i]);
+    }
+    patch.extend_from_slice(b"EOF");
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::{ips_patch, MapperRegistry, Rom};
+
+    fn disk_side() -> Vec<u8> {
+        let mut side = DISK_VERIFICATION.to_vec();
+        side.resize(0x38, 0);
+        // 1 file with 4 bytes of data
+        side.extend([2, 1]);
+        let mut header = vec![3; 0x10];
+        header[13] = 4;
+        header[14] = 0;
+        side.extend(header);
+        side.extend([4, 0xA, 0xB, 0xC, 0xD]);
+        side.resize(SIDE_LEN, 0);
+        side
+    }
+
+    #[test]
+    fn parse_fds_file() {
+        let mut data = b"FDS\x1A\x02".to_vec();
+        data.resize(HEADER_LEN, 0);
+        data.extend(disk_side());
+        data.extend(disk_side());
+        let fds = FDSFile::parse(&data).unwrap();
+        assert_eq!(fds.sides.len(), 2);
+
+        // Without header
+        let fds = FDSFile::parse(&disk_side()).unwrap();
+        assert_eq!(fds.sides.len(), 1);
+
+        assert_matches!(FDSFile::parse(&data[..100]), Err(_));
+    }
+
+    #[test]
+    fn truncated_side() {
+        // File header cut off by the end of the side
+        let mut side = disk_side();
+        side.truncate(0x3A);
+        side.resize(SIDE_LEN - 8, 0);
+        side.push(3);
+        side.resize(SIDE_LEN, 0);
+        // Chain the blocks up to the last file header
+        side[0x3A..SIDE_LEN - 8].fill(2);
+        let e = FDSFile::parse(&side).unwrap_err();
+        assert_matches!(
+            e.downcast_ref::<CartridgeError>(),
+            Some(CartridgeError::InvalidDiskImage(SIDE_LEN))
+        );
+        assert_matches!(
+            Rom::validate(&side, &MapperRegistry::default())[..],
+            [CartridgeError::InvalidDiskImage(SIDE_LEN)]
+        );
+
+        // File data longer than the rest of the side
+        let mut side = disk_side();
+        side[0x38 + 2 + 13] = 0xFF;
+        side[0x38 + 2 + 14] = 0xFF;
+        assert_matches!(FDSFile::parse(&side), Err(_));
+    }
+
+    #[test]
+    fn raw_side_round_trip() {
+        let side = disk_side();
+        let raw = to_raw_side(&side);
+        assert_eq!(raw.len(), RAW_SIDE_LEN);
+        assert!(raw[..LEAD_IN_LEN].iter().all(|&b| b == 0));
+        assert_eq!(raw[LEAD_IN_LEN], GAP_END_MARK);
+        assert_eq!(raw[LEAD_IN_LEN + 1], 1);
+        assert_eq!(from_raw_side(&raw), side);
+    }
+
+    #[test]
+    fn diff_and_patch() {
+        let original = vec![0u8; 0x100];
+        let mut modified = original.clone();
+        modified[0x10] = 1;
+        modified[0x11] = 2;
+        modified[0xFF] = 3;
+
+        let d = ips_diff(&original, &modified);
+        let mut data = original.clone();
+        ips_patch(&mut data, &d).unwrap();
+        assert_eq!(data, modified);
+
+        assert_matches!(ips_patch(&mut data, b"PATCH\x00"), Err(_));
+
+        // Header is kept as it is
+        let mut image = b"FDS\x1A\x01".to_vec();
+        image.resize(HEADER_LEN, 0);
+        image.extend(disk_side());
+        let mut side = disk_side();
+        side[0x100] = 0xFF;
+        let d = disk_diff(&image, &[side.clone()]);
+        ips_patch(&mut image, &d).unwrap();
+        assert_eq!(FDSFile::parse(&image).unwrap().sides, vec![side]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+
+use super::inesfile::{Flag6, INESFile};
+use super::Timing;
+
+// Built-in database generated by build.rs
+const BUILTIN: &str = include_str!(concat!(env!("OUT_DIR"), "/gamedb.txt"));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameInfo {
+    pub title: String,
+    pub region: Timing,
+    // Board name such as "NES-TLROM"
+    pub board: String,
+
+    crc32: Option<u32>,
+    sha1: Option<String>,
+    mapper: u16,
+    submapper: u8,
+    mirroring: DbMirroring,
+    battery: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DbMirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+// Known cartridges keyed by CRC32/SHA-1 of PRG-ROM and CHR-ROM
+#[derive(Debug, Default)]
+pub struct GameDatabase {
+    games: Vec<GameInfo>,
+}
+
+impl GameDatabase {
+    // Parsed once on the first use.
+    // Built with `NES20DB=none`, only src/rom/gamedb.txt, which has no games, is built in;
+    // then no ROM is identified and no header is corrected.
+    pub fn builtin() -> &'static Self {
+        static DB: OnceLock<GameDatabase> = OnceLock::new();
+        DB.get_or_init(|| Self::parse(BUILTIN).expect("built-in game database is broken"))
+    }
+
+    // Format is described in gamedb.txt
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut games = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let game = parse_line(line).ok_or_else(|| anyhow!("invalid game at line {}", i + 1))?;
+            games.push(game);
+        }
+        Ok(Self { games })
+    }
+
+    pub fn find(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameInfo> {
+        let (crc, sha1) = checksums(prg_rom, chr_rom);
+        self.find_by_checksums(crc, &sha1)
+    }
+
+    // CRC32 and SHA-1 of PRG-ROM followed by CHR-ROM.
+    // CRC32 is used only if no game has the SHA-1 since different ROMs may share a CRC32.
+    pub fn find_by_checksums(&self, crc32: u32, sha1: &str) -> Option<&GameInfo> {
+        self.games
+            .iter()
+            .find(|g| g.sha1.as_deref() == Some(sha1))
+            .or_else(|| self.games.iter().find(|g| g.crc32 == Some(crc32)))
+    }
+
+    // Identifies the game by the checksums of the ROM and corrects the header fields of iNES 1.0
+    // which old dumps often got wrong. NES 2.0 headers are trusted as they are.
+    pub fn correct(&self, ines: &mut INESFile, crc32: u32, sha1: &str) -> Option<GameInfo> {
+        let game = self.find_by_checksums(crc32, sha1)?.clone();
+        if !ines.nes2 || ines.dirty_header {
+            ines.mapper = game.mapper;
+            ines.submapper = game.submapper;
+            ines.timing = game.region;
+            ines.flag6.remove(
+                Flag6::MIRRORING_VERTICAL | Flag6::FULL_SCREEN_VRAM | Flag6::BATTERY_BACKED_PRG_RAM,
+            );
+            match game.mirroring {
+                DbMirroring::Horizontal => {}
+                DbMirroring::Vertical => ines.flag6.insert(Flag6::MIRRORING_VERTICAL),
+                DbMirroring::FourScreen => ines.flag6.insert(Flag6::FULL_SCREEN_VRAM),
+            }
+            ines.flag6.set(Flag6::BATTERY_BACKED_PRG_RAM, game.battery);
+        }
+        Some(game)
+    }
+}
+
+// CRC32 and SHA-1 in lowercase hex
+pub(super) fn checksums(prg_rom: &[u8], chr_rom: &[u8]) -> (u32, String) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(prg_rom);
+    crc.update(chr_rom);
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(prg_rom);
+    sha1.update(chr_rom);
+    (crc.finalize(), sha1.digest().to_string())
+}
+
+fn parse_line(line: &str) -> Option<GameInfo> {
+    let fields: Vec<&str> = line.splitn(9, '\t').collect();
+    if fields.len() != 9 {
+        return None;
+    }
+    let crc32 = match fields[0] {
+        "-" => None,
+        crc => Some(u32::from_str_radix(crc, 16).ok()?),
+    };
+    let sha1 = match fields[1] {
+        "-" => None,
+        sha1 if sha1.len() == 40 => Some(sha1.to_ascii_lowercase()),
+        _ => return None,
+    };
+    if crc32.is_none() && sha1.is_none() {
+        return None;
+    }
+    let mirroring = match fields[4] {
+        "H" => DbMirroring::Horizontal,
+        "V" => DbMirroring::Vertical,
+        "4" => DbMirroring::FourScreen,
+        _ => return None,
+    };
+    let region = match fields[6] {
+        "NTSC" => Timing::Ntsc,
+        "PAL" => Timing::Pal,
+        "Multi" => Timing::MultipleRegion,
+        "Dendy" => Timing::Dendy,
+        _ => return None,
+    };
+    Some(GameInfo {
+        title: fields[8].to_string(),
+        region,
+        board: fields[7].to_string(),
+        crc32,
+        sha1,
+        mapper: fields[2].parse().ok()?,
+        submapper: fields[3].parse().ok()?,
+        mirroring,
+        battery: fields[5] == "1",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binread::{io::Cursor, BinRead};
+
+    // Mapper 0 with 16KB PRG-ROM of 0xEA and 8KB CHR-ROM of 0x00, horizontal mirroring
+    fn ines() -> INESFile {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0];
+        data.extend(vec![0; 5]);
+        data.extend(vec![0xEA; 0x4000]);
+        data.extend(vec![0; 0x2000]);
+        INESFile::read(&mut Cursor::new(data)).unwrap()
+    }
+
+    fn checksums() -> (String, String) {
+        let ines = ines();
+        let data = [ines.prg_rom, ines.chr_rom].concat();
+        let crc = format!("{:08X}", crc32fast::hash(&data));
+        let sha1 = sha1_smol::Sha1::from(&data).digest().to_string();
+        (crc, sha1)
+    }
+
+    #[test]
+    fn builtin_database() {
+        let db = GameDatabase::builtin();
+        // gamedb.txt has no games, which is all with `NES20DB=none`
+        if cfg!(not(nes20db)) {
+            assert!(db.games.is_empty());
+        }
+    }
+
+    #[test]
+    fn correct_header() {
+        let (crc, _) = checksums();
+        let text = format!(
+            "# comment\n{}\t-\t4\t0\tV\t1\tPAL\tNES-TLROM\tTest Game\n",
+            crc
+        );
+        let db = GameDatabase::parse(&text).unwrap();
+
+        let mut ines = ines();
+        let (crc32, sha1) = super::checksums(&ines.prg_rom, &ines.chr_rom);
+        let game = db.correct(&mut ines, crc32, &sha1).unwrap();
+        assert_eq!(game.title, "Test Game");
+        assert_eq!(game.board, "NES-TLROM");
+        assert_eq!(game.region, Timing::Pal);
+        assert_eq!(ines.mapper, 4);
+        assert_eq!(ines.timing, Timing::Pal);
+        assert!(ines.battery_backed());
+        assert_matches!(ines.mirroring(), crate::rom::Mirroring::Vertical);
+    }
+
+    #[test]
+    fn sha1_takes_precedence_over_crc32() {
+        let (crc, sha1) = checksums();
+        let text = format!(
+            "{crc}\t-\t4\t0\tV\t0\tNTSC\tNES-TLROM\tCRC32 Collision\n\
+             -\t{sha1}\t0\t0\tH\t0\tNTSC\tNES-NROM-128\tTest Game\n\
+             {crc}\t{other}\t1\t0\tH\t0\tNTSC\tNES-SNROM\tOther Game\n",
+            crc = crc,
+            sha1 = sha1,
+            other = "0".repeat(40),
+        );
+        let db = GameDatabase::parse(&text).unwrap();
+        let crc32 = u32::from_str_radix(&crc, 16).unwrap();
+
+        let mut ines = ines();
+        let game = db.correct(&mut ines, crc32, &sha1).unwrap();
+        assert_eq!(game.title, "Test Game");
+        assert_eq!(ines.mapper, 0);
+
+        // Falls back to CRC32 for an unknown SHA-1
+        let game = db.find_by_checksums(crc32, &"f".repeat(40)).unwrap();
+        assert_eq!(game.title, "CRC32 Collision");
+    }
+
+    #[test]
+    fn find_by_sha1() {
+        let (_, sha1) = checksums();
+        let text = format!("-\t{}\t0\t0\tH\t0\tNTSC\tNES-NROM-128\tTest Game\n", sha1);
+        let db = GameDatabase::parse(&text).unwrap();
+        let ines = ines();
+        assert_eq!(
+            db.find(&ines.prg_rom, &ines.chr_rom).unwrap().title,
+            "Test Game"
+        );
+        assert_eq!(db.find(&ines.prg_rom, &[]), None);
+
+        assert_matches!(
+            GameDatabase::parse("-\t-\t0\t0\tH\t0\tNTSC\tNROM\tNo key"),
+            Err(_)
+        );
+    }
+}
@@ -1,24 +1,324 @@
-#[derive(BinRead, Debug)]
+use anyhow::Result;
+use binread::io::Cursor;
+
+use super::{CartridgeError, Mirroring};
+
+// https://wiki.nesdev.com/w/index.php/INES
+// https://wiki.nesdev.com/w/index.php/NES_2.0
+// Header bytes only needed while parsing are temporary
+#[derive_binread]
+#[derive(Debug)]
 #[br(magic = b"NES\x1A")]
-pub(super) struct INESFile {
+pub struct INESFile {
+    // Kept for tests on the raw header
+    #[allow(dead_code)]
     prg_rom_unit_size: u8,
+    #[allow(dead_code)]
     chr_rom_unit_size: u8,
     pub(super) flag6: Flag6,
+    #[br(temp)]
     flag7: Flag7,
+    #[br(temp)]
     flag8: u8,
+    #[br(temp)]
     flag9: Flag9,
-    flag10: Flag10,
+    pub(super) flag10: Flag10,
+    #[br(temp)]
+    flag11: u8,
+    #[br(temp)]
+    flag12: u8,
+    #[br(temp)]
+    flag13: u8,
+    #[br(temp, pad_after = 1)]
+    flag14: u8,
+
+    #[br(calc = flag7.is_nes2())]
+    pub nes2: bool,
+
+    #[br(calc = rom_size(nes2, prg_rom_unit_size, flag9.bits() & 0x0F, 0x4000))]
+    #[br(temp)]
+    #[br(assert(prg_rom_size.is_some(), "PRG-ROM size overflows"))]
+    prg_rom_size: Option<usize>,
+    #[br(calc = rom_size(nes2, chr_rom_unit_size, flag9.bits() >> 4, 0x2000))]
+    #[br(temp)]
+    #[br(assert(chr_rom_size.is_some(), "CHR-ROM size overflows"))]
+    chr_rom_size: Option<usize>,
+
+    #[br(count = if flag6.contains(Flag6::TRAINER) { TRAINER_LEN } else { 0 })]
+    pub trainer: Vec<u8>,
+    #[br(count = prg_rom_size.unwrap_or_default())]
+    pub prg_rom: Vec<u8>,
+    #[br(count = chr_rom_size.unwrap_or_default())]
+    pub chr_rom: Vec<u8>,
+
+    #[br(calc = {
+        let mapper = (flag7.bits() & 0b11110000) as u16 + (flag6.bits() >> 4) as u16;
+        if nes2 { ((flag8 as u16 & 0x0F) << 8) | mapper } else { mapper }
+    })]
+    pub mapper: u16,
+    #[br(calc = if nes2 { flag8 >> 4 } else { 0 })]
+    pub submapper: u8,
+
+    #[br(calc = if nes2 {
+        ram_size(flag10.bits() & 0x0F)
+    } else if 0 < flag8 {
+        flag8 as usize * 0x2000
+    } else {
+        0x2000
+    })]
+    pub prg_ram_size: usize,
+    #[br(calc = if nes2 { ram_size(flag10.bits() >> 4) } else { 0 })]
+    pub prg_nvram_size: usize,
+    #[br(calc = if nes2 { ram_size(flag11 & 0x0F) } else { 0 })]
+    pub chr_ram_size: usize,
+    #[br(calc = if nes2 { ram_size(flag11 >> 4) } else { 0 })]
+    pub chr_nvram_size: usize,
+
+    #[br(calc = if nes2 {
+        Timing::from(flag12)
+    } else if flag9.contains(Flag9::TV_SYSTEM_PAL) {
+        Timing::Pal
+    } else {
+        Timing::Ntsc
+    })]
+    pub timing: Timing,
+    #[br(calc = ConsoleType::new(flag7, flag13, nes2))]
+    pub console_type: ConsoleType,
+
+    // Bytes 7-15 were garbage and ignored
+    #[br(default)]
+    pub dirty_header: bool,
+    // Mirroring the header cannot tell, such as single-screen given by the MIRR chunk of UNIF
+    #[br(default)]
+    pub(super) mirroring_override: Option<Mirroring>,
+}
+
+const MAGIC: &[u8] = b"NES\x1A";
+const HEADER_LEN: usize = 16;
+const TRAINER_LEN: usize = 0x200;
+
+// PRG/CHR-ROM size in bytes; NES 2.0 also has the exponent-multiplier notation.
+// None if the exponent is too large to be addressed.
+// https://wiki.nesdev.com/w/index.php/NES_2.0#PRG-ROM_Area
+fn rom_size(nes2: bool, lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if !nes2 {
+        Some(lsb as usize * unit)
+    } else if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+    } else {
+        Some(((msb as usize) << 8 | lsb as usize) * unit)
+    }
+}
+
+// Some dumping tools wrote their name such as "DiskDude!" into the unused bytes of iNES 1.0
+// https://wiki.nesdev.com/w/index.php/INES#Variant_comparison
+fn has_garbage(header: &[u8]) -> bool {
+    let nes2 = Flag7::from_bits_truncate(header[7]).is_nes2();
+    !nes2 && header[11..HEADER_LEN].iter().any(|&b| b != 0)
+}
+
+// https://wiki.nesdev.com/w/index.php/NES_2.0#PRG-.28NV.29RAM.2FEEPROM
+fn ram_size(shift_count: u8) -> usize {
+    if shift_count == 0 {
+        0
+    } else {
+        64 << shift_count
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timing {
+    // RP2C02
+    Ntsc,
+    // RP2C07
+    Pal,
+    MultipleRegion,
+    // UMC 6527P
+    Dendy,
+}
+
+impl From<u8> for Timing {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => Self::Ntsc,
+            1 => Self::Pal,
+            2 => Self::MultipleRegion,
+            _ => Self::Dendy,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    // https://wiki.nesdev.com/w/index.php/NES_2.0#Extended_Console_Type
+    Extended(u8),
+}
+
+impl ConsoleType {
+    fn new(flag7: Flag7, flag13: u8, nes2: bool) -> Self {
+        match (flag7.bits() & 0b11, nes2) {
+            (1, true) => Self::VsSystem {
+                ppu_type: flag13 & 0x0F,
+                hardware_type: flag13 >> 4,
+            },
+            (1, false) => Self::VsSystem {
+                ppu_type: 0,
+                hardware_type: 0,
+            },
+            (2, _) => Self::Playchoice10,
+            (3, true) => Self::Extended(flag13 & 0x0F),
+            _ => Self::Nes,
+        }
+    }
+}
+
+impl INESFile {
+    // Reads an iNES file failing on the first fatal problem found by `check`
+    pub fn parse(mut data: Vec<u8>) -> Result<Self> {
+        let issues = Self::check(&data);
+        if let Some(e) = issues.into_iter().find(CartridgeError::is_fatal) {
+            return Err(e.into());
+        }
+        let dirty_header = has_garbage(&data);
+        if dirty_header {
+            // Only the lower nybble of the mapper number is reliable as iNES 0.7
+            data[7..HEADER_LEN].fill(0);
+        }
+        let mut ines = <Self as binread::BinRead>::read(&mut Cursor::new(data))?;
+        ines.dirty_header = dirty_header;
+        Ok(ines)
+    }
+
+    // Problems in the header and the size of the file
+    pub fn check(data: &[u8]) -> Vec<CartridgeError> {
+        if !data.starts_with(MAGIC) {
+            return vec![CartridgeError::InvalidMagic];
+        }
+        if data.len() < HEADER_LEN {
+            return vec![CartridgeError::TruncatedHeader(data.len())];
+        }
+        let mut issues = Vec::new();
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&data[..HEADER_LEN]);
+        if has_garbage(&header) {
+            let mut garbage = [0; 5];
+            garbage.copy_from_slice(&header[11..HEADER_LEN]);
+            issues.push(CartridgeError::HeaderGarbage(garbage));
+            header[7..].fill(0);
+        }
+
+        let nes2 = Flag7::from_bits_truncate(header[7]).is_nes2();
+        let trainer_len = if Flag6::from_bits_truncate(header[6]).contains(Flag6::TRAINER) {
+            TRAINER_LEN
+        } else {
+            0
+        };
+        let prg_rom_size = rom_size(nes2, header[4], header[9] & 0x0F, 0x4000);
+        let chr_rom_size = rom_size(nes2, header[5], header[9] >> 4, 0x2000);
+        let (prg_rom_size, chr_rom_size) = match (prg_rom_size, chr_rom_size) {
+            (Some(prg), Some(chr)) => (prg, chr),
+            _ => {
+                issues.push(CartridgeError::Malformed("ROM size overflows".to_string()));
+                return issues;
+            }
+        };
+        // Nothing to execute at the reset vector
+        if prg_rom_size == 0 {
+            issues.push(CartridgeError::Malformed("no PRG-ROM".to_string()));
+        }
+
+        let body = data.len().saturating_sub(HEADER_LEN + trainer_len);
+        if body < prg_rom_size {
+            issues.push(CartridgeError::TruncatedPrgRom {
+                expected: prg_rom_size,
+                actual: body,
+            });
+        }
+        let chr_body = body.saturating_sub(prg_rom_size);
+        if chr_body < chr_rom_size {
+            issues.push(CartridgeError::TruncatedChrRom {
+                expected: chr_rom_size,
+                actual: chr_body,
+            });
+        }
+        issues
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if let Some(mirroring) = &self.mirroring_override {
+            mirroring.clone()
+        } else if self.four_screen() {
+            Mirroring::FourScreen
+        } else if self.flag6.contains(Flag6::MIRRORING_VERTICAL) {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    // Mirroring control of mappers is ignored on such boards
+    pub fn four_screen(&self) -> bool {
+        self.flag6.contains(Flag6::FULL_SCREEN_VRAM)
+    }
 
-    #[br(pad_before = 5, count = prg_rom_unit_size as u16 * 0x4000u16)]
-    pub(super) prg_rom: Vec<u8>,
-    #[br(count = chr_rom_unit_size as u16 * 0x2000u16)]
-    pub(super) chr_rom: Vec<u8>,
+    // Written value is ANDed with the ROM byte at the same address on such boards
+    // https://wiki.nesdev.com/w/index.php/Bus_conflict
+    pub fn bus_conflicted(&self) -> bool {
+        if self.nes2 {
+            // submapper 2 of UxROM, CNROM and AxROM
+            self.submapper == 2
+        } else {
+            self.flag10.contains(Flag10::BUS_CONFLICTED)
+        }
+    }
+
+    // Work RAM at $6000-$7FFF including battery-backed one
+    pub fn prg_ram_len(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
 
-    #[br(calc = (flag7.bits() & 0b11110000) + (flag6.bits() >> 4))]
-    pub(super) mapper: u8,
+    pub fn battery_backed(&self) -> bool {
+        self.flag6.contains(Flag6::BATTERY_BACKED_PRG_RAM)
+    }
+
+    // For boards fixing banks at the end of PRG-ROM
+    pub fn require_prg_rom(&self, expected: usize) -> Result<(), CartridgeError> {
+        if self.prg_rom.len() < expected {
+            return Err(CartridgeError::PrgRomTooSmall {
+                mapper: self.mapper,
+                expected,
+                actual: self.prg_rom.len(),
+            });
+        }
+        Ok(())
+    }
 
-    #[br(calc = if 0 < flag8 { flag8 as u16 * 0x2000u16 } else { 0x2000u16 })]
-    prg_ram_size: u16,
+    // CHR-RAM for boards without CHR-ROM; 8KB unless NES 2.0 header specifies its size
+    pub fn new_chr_ram(&self) -> Vec<u8> {
+        if !self.chr_rom.is_empty() {
+            return Vec::new();
+        }
+        let len = self.chr_ram_size + self.chr_nvram_size;
+        vec![0; if 0 < len { len } else { 0x2000 }]
+    }
+
+    // Allocates PRG-RAM at power-on.
+    // The trainer is loaded into $7000-$71FF as copier devices did.
+    pub fn new_prg_ram(&self, len: usize) -> Vec<u8> {
+        if self.trainer.is_empty() {
+            return vec![0; len];
+        }
+        let mut ram = vec![0; len.max(0x2000)];
+        ram[0x1000..0x1000 + TRAINER_LEN].copy_from_slice(&self.trainer);
+        ram
+    }
 }
 
 bitflags! {
@@ -26,7 +326,8 @@ bitflags! {
     pub(super) struct Flag6: u8 {
         const MIRRORING_VERTICAL = 1 << 0;
         const BATTERY_BACKED_PRG_RAM = 1 << 1;
-        const TRAINER_BEFORE_RPM_ROM = 1 << 2;  //TODO how to affect to INESFile.prg_rom's attribute?
+        // 512-byte trainer at $7000-$71FF precedes PRG-ROM
+        const TRAINER = 1 << 2;
         const FULL_SCREEN_VRAM = 1 << 3;
     }
 }
@@ -43,11 +344,15 @@ bitflags! {
     struct Flag7: u8 {
         const VS_UNISYSTEM = 1 << 0;
         const PLAY_CHOICE_10 = 1 << 1;
-        const NES2_FORMAT = 0b1100;
+        const NES2_FORMAT = 0b1000;
     }
 }
 
 impl Flag7 {
+    fn is_nes2(&self) -> bool {
+        self.bits & 0b1100 == Self::NES2_FORMAT.bits
+    }
+
     #[allow(dead_code)]
     fn mapper_upper_nybble(&self) -> u8 {
         self.bits & 0b11110000
@@ -63,7 +368,7 @@ bitflags! {
 
 bitflags! {
     #[derive(BinRead, Default)]
-    struct Flag10: u8 {
+    pub(super) struct Flag10: u8 {
         const TV_SYSTEM_PAL = 1 << 1;
         const TV_SYSTEM_DUAL = 0b11;
         const PRG_RAM = 1 << 4;
@@ -106,4 +411,163 @@ mod tests {
 
         assert_eq!(ines.mapper, 0);
     }
+
+    fn read_header(header: [u8; 16], rom_len: usize) -> INESFile {
+        let mut data = header.to_vec();
+        data.extend(vec![0; rom_len]);
+        INESFile::read(&mut Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn load_nes2_header() {
+        let ines = read_header(
+            [
+                b'N', b'E', b'S', 0x1A, 0x02, 0x01, 0x12, 0x18, 0x31, 0x00, 0x70, 0x07, 0x01, 0x00,
+                0x00, 0x00,
+            ],
+            0xA000,
+        );
+        assert!(ines.nes2);
+        assert_eq!(ines.mapper, 0x111);
+        assert_eq!(ines.submapper, 3);
+        assert_eq!(ines.prg_rom.len(), 0x8000);
+        assert_eq!(ines.chr_rom.len(), 0x2000);
+        assert_eq!(ines.prg_ram_size, 0);
+        assert_eq!(ines.prg_nvram_size, 0x2000);
+        assert_eq!(ines.chr_ram_size, 0x2000);
+        assert_eq!(ines.chr_nvram_size, 0);
+        assert_eq!(ines.timing, Timing::Pal);
+        assert_eq!(ines.console_type, ConsoleType::Nes);
+        assert_eq!(ines.prg_ram_len(), 0x2000);
+    }
+
+    #[test]
+    fn load_nes2_exponent_multiplier_size() {
+        // 2^4 * 3 = 48 bytes
+        let ines = read_header(
+            [
+                b'N', b'E', b'S', 0x1A, 0b00010001, 0x00, 0x00, 0x0B, 0x00, 0x0F, 0x00, 0x00, 0x03,
+                0x02, 0x00, 0x00,
+            ],
+            48,
+        );
+        assert_eq!(ines.prg_rom.len(), 48);
+        assert_eq!(ines.timing, Timing::Dendy);
+        assert_eq!(ines.console_type, ConsoleType::Extended(2));
+    }
+
+    #[test]
+    fn load_trainer() {
+        let mut data = vec![
+            b'N', b'E', b'S', 0x1A, 0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00,
+        ];
+        data.extend(vec![0; 5]);
+        data.extend(vec![0xEA; TRAINER_LEN]);
+        data.extend(vec![0x60; 0x4000]);
+        let ines = INESFile::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(ines.trainer.len(), TRAINER_LEN);
+        assert_eq!(ines.prg_rom[0], 0x60);
+
+        let ram = ines.new_prg_ram(0);
+        assert_eq!(ram.len(), 0x2000);
+        assert_eq!(ram[0x0FFF], 0x00);
+        assert_eq!(ram[0x1000], 0xEA);
+        assert_eq!(ram[0x11FF], 0xEA);
+        assert_eq!(ram[0x1200], 0x00);
+    }
+
+    #[test]
+    fn ines1_header_is_unchanged() {
+        let ines = read_header(
+            [
+                b'N', b'E', b'S', 0x1A, 0x01, 0x01, 0x41, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+            0x6000,
+        );
+        assert!(!ines.nes2);
+        assert_eq!(ines.mapper, 4);
+        assert_eq!(ines.submapper, 0);
+        assert_eq!(ines.prg_ram_size, 0x2000);
+        assert_eq!(ines.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 2, 1];
+        assert_matches!(
+            INESFile::check(&data)[..],
+            [CartridgeError::TruncatedHeader(6)]
+        );
+
+        data.extend(vec![0; 10]);
+        data.extend(vec![0; 0x5000]);
+        assert_matches!(
+            INESFile::check(&data)[..],
+            [
+                CartridgeError::TruncatedPrgRom {
+                    expected: 0x8000,
+                    actual: 0x5000
+                },
+                CartridgeError::TruncatedChrRom {
+                    expected: 0x2000,
+                    actual: 0
+                }
+            ]
+        );
+        assert_matches!(
+            INESFile::parse(data).unwrap_err().downcast_ref(),
+            Some(CartridgeError::TruncatedPrgRom { .. })
+        );
+
+        assert_matches!(
+            INESFile::parse(b"NES\x00".to_vec())
+                .unwrap_err()
+                .downcast_ref(),
+            Some(CartridgeError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn overflowing_rom_size() {
+        // NES 2.0 exponent-multiplier notation of 2^63 * 3
+        let mut data = vec![b'N', b'E', b'S', 0x1A, (63 << 2) | 1, 0, 0, 0x08, 0, 0x0F];
+        data.extend(vec![0; 6]);
+        assert_matches!(INESFile::check(&data)[..], [CartridgeError::Malformed(_)]);
+        assert_matches!(
+            INESFile::parse(data.clone()).unwrap_err().downcast_ref(),
+            Some(CartridgeError::Malformed(_))
+        );
+        assert_matches!(INESFile::read(&mut Cursor::new(data)), Err(_));
+    }
+
+    #[test]
+    fn empty_prg_rom() {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 0, 1];
+        data.extend(vec![0; 10]);
+        data.extend(vec![0; 0x2000]);
+        assert_matches!(INESFile::check(&data)[..], [CartridgeError::Malformed(_)]);
+        assert_matches!(
+            INESFile::parse(data).unwrap_err().downcast_ref(),
+            Some(CartridgeError::Malformed(_))
+        );
+    }
+
+    #[test]
+    fn dirty_header() {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0x41];
+        data.extend(b"DiskDude!");
+        data.extend(vec![0; 0x4000]);
+        assert_matches!(
+            INESFile::check(&data)[..],
+            [CartridgeError::HeaderGarbage([
+                b'D', b'u', b'd', b'e', b'!'
+            ])]
+        );
+        // The mapper number is taken only from flag 6
+        let ines = INESFile::parse(data).unwrap();
+        assert!(ines.dirty_header);
+        assert_eq!(ines.mapper, 4);
+        assert_eq!(ines.timing, Timing::Ntsc);
+    }
 }
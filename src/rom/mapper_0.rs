@@ -1,56 +1,88 @@
-use super::inesfile::{Flag6, INESFile};
+use super::inesfile::INESFile;
 use super::*;
 use crate::data_unit::*;
 
 pub struct Mapper0 {
     rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
 
     mirroring: Mirroring,
-    mirrored: bool,
 }
 
 impl Mapper0 {
     pub(super) fn new(rom: INESFile) -> Self {
-        let mirroring = if rom.flag6.contains(Flag6::MIRRORING_VERTICAL) {
-            Mirroring::Vertical
+        let mirroring = rom.mirroring();
+
+        let chr_ram = rom.new_chr_ram();
+        // Only Family BASIC has PRG-RAM on NROM boards, so it needs to be declared explicitly
+        let prg_ram_len = if rom.nes2 || rom.battery_backed() {
+            rom.prg_ram_len()
         } else {
-            Mirroring::Horizontal
+            0
         };
+        let prg_ram = rom.new_prg_ram(prg_ram_len);
 
-        let mirrored = rom.prg_rom.len() == 0x4000;
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
 
         Self {
-            rom: rom,
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
             mirroring,
-            mirrored,
         }
     }
 
     fn prg_addr(&self, addr: u16) -> usize {
-        if self.mirrored {
-            addr.wrapping_rem(0x4000)
-        } else {
-            addr.wrapping_sub(0x4000)
-        }
-        .into()
+        (addr as usize - 0x8000) % self.rom.prg_rom.len()
     }
 }
 
 impl Mapper for Mapper0 {
-    fn read(&mut self, addr: Word) -> Byte {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
         let addr: u16 = addr.into();
         match addr {
-            0x0000..=0x1FFF => self.rom.chr_rom[addr as usize],
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
             0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
             _ => 0,
         }
         .into()
     }
 
-    fn write(&mut self, addr: Word, value: Byte) {
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => {
+                self.rom.chr_rom[addr as usize % self.rom.chr_rom.len()]
+            }
+            0x0000..=0x1FFF => self.chr_ram[addr as usize % self.chr_ram.len()],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
         let addr: u16 = addr.into();
         match addr {
-            0x0000..=0x1FFF => self.rom.chr_rom[addr as usize] = value.into(),
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into()
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let len = self.chr_ram.len();
+                self.chr_ram[addr as usize % len] = value.into()
+            }
             _ => {}
         }
     }
@@ -58,4 +90,105 @@ impl Mapper for Mapper0 {
     fn mirroring(&self) -> Mirroring {
         self.mirroring.clone()
     }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::inesfile::Flag6;
+    use crate::rom::test_ines;
+
+    // 16KB PRG-ROM banks and 8KB CHR-ROM banks
+    fn nrom(prg_banks: usize, chr_banks: usize) -> Mapper0 {
+        let mut ines = test_ines(0, 8, chr_banks * 8);
+        ines.prg_rom.truncate(prg_banks * 0x4000);
+        Mapper0::new(ines)
+    }
+
+    #[test]
+    fn prg_rom() {
+        let mut m = nrom(1, 1);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 0.into());
+
+        let mut m = nrom(2, 1);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 1.into());
+    }
+
+    #[test]
+    fn small_prg_rom_is_mirrored() {
+        // NES 2.0 with 8KB PRG-ROM
+        let mut ines = test_ines(0, 16, 8);
+        ines.prg_rom.truncate(0x2000);
+        let mut m = Mapper0::new(ines);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+        assert_eq!(m.cpu_read(0xFFFCu16.into()), 0.into());
+
+        let mut ines = test_ines(0, 16, 8);
+        ines.prg_rom.drain(..0x2000);
+        ines.prg_rom.truncate(0x2000);
+        let mut m = Mapper0::new(ines);
+        assert_eq!(m.cpu_read(0xA000u16.into()), 1.into());
+        assert_eq!(m.cpu_read(0xFFFCu16.into()), 1.into());
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut m = nrom(1, 1);
+        m.ppu_write(0x0010u16.into(), 0x12.into());
+        assert_eq!(m.ppu_read(0x0010u16.into()), 0.into());
+    }
+
+    #[test]
+    fn small_chr_rom_is_mirrored() {
+        // 4KB CHR-ROM
+        let mut ines = test_ines(0, 8, 8);
+        ines.chr_rom.truncate(0x1000);
+        let mut m = Mapper0::new(ines);
+        assert_eq!(m.ppu_read(0x0C00u16.into()), 3.into());
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 3.into());
+    }
+
+    #[test]
+    fn chr_ram() {
+        let mut m = nrom(1, 0);
+        m.ppu_write(0x1FFFu16.into(), 0x12.into());
+        assert_eq!(m.ppu_read(0x1FFFu16.into()), 0x12.into());
+    }
+
+    #[test]
+    fn small_chr_ram_is_mirrored() {
+        // NES 2.0 with 2KB CHR-RAM
+        let mut ines = test_ines(0, 8, 0);
+        ines.nes2 = true;
+        ines.chr_ram_size = 0x800;
+        let mut m = Mapper0::new(ines);
+        m.ppu_write(0x1810u16.into(), 0x12.into());
+        assert_eq!(m.ppu_read(0x0010u16.into()), 0x12.into());
+    }
+
+    #[test]
+    fn small_prg_ram_is_mirrored() {
+        // NES 2.0 with 2KB of battery-backed PRG-NVRAM
+        let mut ines = test_ines(0, 8, 8);
+        ines.nes2 = true;
+        ines.flag6.insert(Flag6::BATTERY_BACKED_PRG_RAM);
+        ines.prg_ram_size = 0;
+        ines.prg_nvram_size = 0x800;
+        let mut m = Mapper0::new(ines);
+        assert_eq!(m.battery_ram().unwrap().len(), 0x800);
+
+        m.cpu_write(0x6800u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0x12.into());
+        assert_eq!(m.cpu_read(0x7800u16.into()), 0x12.into());
+    }
 }
@@ -0,0 +1,311 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/MMC1
+pub struct Mapper1 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    // CPU cycles since the last write to the serial port
+    cycles_since_write: u8,
+}
+
+const SHIFT_REGISTER_RESET: u8 = 0b10000;
+
+impl Mapper1 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        // SNROM and friends have no CHR-ROM but CHR-RAM
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(rom.prg_ram_len());
+
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            shift_register: SHIFT_REGISTER_RESET,
+            // PRG ROM bank mode 3 on power-up
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles_since_write: u8::MAX,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        // Writes on consecutive cycles such as the double write of RMW instructions
+        // take only the first one
+        let consecutive = self.cycles_since_write == 1;
+        self.cycles_since_write = 0;
+        if consecutive {
+            return;
+        }
+
+        if value & 0x80 != 0 {
+            self.shift_register = SHIFT_REGISTER_RESET;
+            self.control |= 0x0C;
+            return;
+        }
+
+        let completed = self.shift_register & 1 == 1;
+        self.shift_register = (self.shift_register >> 1) | ((value & 1) << 4);
+        if completed {
+            let data = self.shift_register;
+            match addr {
+                0x8000..=0x9FFF => self.control = data,
+                0xA000..=0xBFFF => self.chr_bank_0 = data,
+                0xC000..=0xDFFF => self.chr_bank_1 = data,
+                _ => self.prg_bank = data,
+            }
+            self.shift_register = SHIFT_REGISTER_RESET;
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        // SUROM selects the 256KB outer bank with the bit 4 of CHR bank 0
+        let outer = if 0x40000 < self.rom.prg_rom.len() {
+            self.chr_bank_0 & 0x10
+        } else {
+            0
+        };
+        let bank = self.prg_bank & 0x0F;
+
+        let bank = match ((self.control >> 2) & 0b11, addr) {
+            // switch 32KB at $8000, ignoring low bit of bank number
+            (0 | 1, 0x8000..=0xBFFF) => bank & 0x0E,
+            (0 | 1, _) => bank | 0x01,
+            // fix first bank at $8000 and switch 16KB bank at $C000
+            (2, 0x8000..=0xBFFF) => 0,
+            (2, _) => bank,
+            // fix last bank at $C000 and switch 16KB bank at $8000
+            (_, 0x8000..=0xBFFF) => bank,
+            (_, _) => 0x0F,
+        } | outer;
+
+        (bank as usize * 0x4000 + (addr as usize & 0x3FFF)) % self.rom.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let bank = if self.control & 0x10 == 0 {
+            // switch 8KB at a time
+            (self.chr_bank_0 & 0x1E) as usize + addr / 0x1000
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        bank * 0x1000 + (addr & 0x0FFF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0 && !self.prg_ram.is_empty()
+    }
+}
+
+impl Mapper for Mapper1 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF => {
+                let i = self.chr_addr(addr);
+                if self.chr_ram.is_empty() {
+                    self.rom.chr_rom[i % self.rom.chr_rom.len()]
+                } else {
+                    self.chr_ram[i % self.chr_ram.len()]
+                }
+            }
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into();
+            }
+            0x8000..=0xFFFF => self.write_register(addr, value.into()),
+            _ => {}
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(1);
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let i = self.chr_addr(addr) % self.chr_ram.len();
+                self.chr_ram[i] = value.into();
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::rom::inesfile::Flag6;
+    use crate::rom::test_ines;
+
+    // 8 PRG-ROM banks of 16KB and 1KB CHR-ROM banks
+    fn mmc1(chr_banks: usize) -> Mapper1 {
+        Mapper1::new(test_ines(1, 8, chr_banks))
+    }
+
+    fn write_serial(m: &mut Mapper1, addr: u16, value: u8) {
+        for i in 0..5 {
+            m.cpu_write(addr.into(), ((value >> i) & 1).into());
+        }
+    }
+
+    #[test]
+    fn power_on_fixes_last_bank() {
+        let mut m = mmc1(8);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 7.into());
+    }
+
+    #[test]
+    fn switch_prg_and_chr_banks() {
+        let mut m = mmc1(16);
+
+        write_serial(&mut m, 0xE000, 3);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xFFFFu16.into()), 7.into());
+
+        // fix first bank at $8000
+        write_serial(&mut m, 0x8000, 0b01011);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 3.into());
+        assert_matches!(m.mirroring(), Mirroring::Horizontal);
+
+        // 4KB CHR mode
+        write_serial(&mut m, 0x8000, 0b11010);
+        write_serial(&mut m, 0xA000, 2);
+        write_serial(&mut m, 0xC000, 1);
+        assert_eq!(m.ppu_read(0x0000u16.into()), 8.into());
+        assert_eq!(m.ppu_read(0x1000u16.into()), 4.into());
+        assert_matches!(m.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn reset_shift_register() {
+        let mut m = mmc1(8);
+        m.cpu_write(0x8000u16.into(), 1.into());
+        m.cpu_write(0x8000u16.into(), 0x80.into());
+        write_serial(&mut m, 0xE000, 5);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 5.into());
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut m = mmc1(0);
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0x12.into());
+
+        // CHR-RAM
+        m.ppu_write(0x0010u16.into(), 0x34.into());
+        assert_eq!(m.ppu_read(0x0010u16.into()), 0x34.into());
+    }
+
+    #[test]
+    fn battery_ram() {
+        let m = mmc1(0);
+        assert!(m.battery_ram().is_none());
+
+        let mut ines = test_ines(1, 8, 0);
+        ines.flag6.insert(Flag6::BATTERY_BACKED_PRG_RAM);
+        let mut m = Mapper1::new(ines);
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.battery_ram().unwrap()[0], 0x12);
+
+        m.battery_ram_mut().unwrap()[1] = 0x34;
+        assert_eq!(m.cpu_read(0x6001u16.into()), 0x34.into());
+    }
+
+    #[test]
+    fn ignore_write_on_consecutive_cycle() {
+        let mut m = mmc1(8);
+        // INC $FFFF on $FF writes $FF back and then $00
+        m.cpu_write(0xFFFFu16.into(), 0xFF.into());
+        m.cpu_clock();
+        m.cpu_write(0xFFFFu16.into(), 0x00.into());
+        m.cpu_clock();
+        m.cpu_clock();
+
+        // The shift register is still empty
+        write_serial(&mut m, 0xE000, 0b0110);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 6.into());
+    }
+
+    #[test]
+    fn rmw_instruction_shifts_one_bit() {
+        let mut nes = Nes::default();
+        nes.mapper = Box::new(mmc1(8));
+        // INC $8000, then LDA #$01 and STA $E000 four times
+        let mut program = vec![0xEE, 0x00, 0x80, 0xA9, 0x01];
+        for _ in 0..4 {
+            program.extend(&[0x8D, 0x00, 0xE0]);
+        }
+        for (i, b) in program.iter().enumerate() {
+            nes.write_bus(i as u16, *b);
+        }
+
+        // INC takes the bit of the original value only, so the fifth bit comes with the last STA
+        for _ in 0..5 {
+            nes.step();
+        }
+        assert_eq!(nes.read_bus(0x8000u16), 0.into());
+        nes.step();
+        assert_eq!(nes.read_bus(0x8000u16), 6.into());
+    }
+}
@@ -0,0 +1,118 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/Color_Dreams
+pub struct Mapper11 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // Only for the trainer
+    prg_ram: Vec<u8>,
+
+    mirroring: Mirroring,
+    bus_conflicted: bool,
+
+    prg_bank: usize,
+    chr_bank: usize,
+}
+
+impl Mapper11 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        Self {
+            chr_ram: rom.new_chr_ram(),
+            prg_ram: rom.new_prg_ram(0),
+            mirroring: rom.mirroring(),
+            bus_conflicted: rom.bus_conflicted(),
+            rom,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        (self.prg_bank * 0x8000 + (addr as usize & 0x7FFF)) % self.rom.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let len = if self.chr_ram.is_empty() {
+            self.rom.chr_rom.len()
+        } else {
+            self.chr_ram.len()
+        };
+        (self.chr_bank * 0x2000 + addr as usize) % len
+    }
+}
+
+impl Mapper for Mapper11 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => self.rom.chr_rom[self.chr_addr(addr)],
+            0x0000..=0x1FFF => self.chr_ram[self.chr_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into()
+            }
+            0x8000..=0xFFFF => {
+                let mut value: u8 = value.into();
+                if self.bus_conflicted {
+                    value &= self.rom.prg_rom[self.prg_addr(addr)];
+                }
+                self.prg_bank = (value & 0b11) as usize;
+                self.chr_bank = (value >> 4) as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let i = self.chr_addr(addr);
+                self.chr_ram[i] = value.into()
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    #[test]
+    fn switch_banks() {
+        let mut m = Mapper11::new(test_ines(11, 4, 128));
+        m.cpu_write(0x8000u16.into(), 0x52.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 2.into());
+        assert_eq!(m.cpu_read(0xFFFFu16.into()), 2.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 40.into());
+        assert_eq!(m.ppu_read(0x1FFFu16.into()), 47.into());
+    }
+}
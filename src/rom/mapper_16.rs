@@ -0,0 +1,227 @@
+use super::eeprom::{Eeprom, EepromChip};
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// Bandai FCG-1/2 and LZ93D50 on mapper 16 and 159
+// https://wiki.nesdev.com/w/index.php/INES_Mapper_016
+pub struct Mapper16 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+
+    board: Board,
+    eeprom: Option<Eeprom>,
+
+    chr_banks: [u8; 8],
+    prg_bank: u8,
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    irq_pending: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Board {
+    // Registers at $6000-$7FFF and IRQ counter written directly
+    Fcg,
+    // Registers at $8000-$FFFF and IRQ counter reloaded from the latch
+    Lz93d50,
+    // Unknown board of iNES mapper 16; registers in both ranges
+    Both,
+}
+
+impl Mapper16 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let board = match (rom.mapper, rom.submapper) {
+            (16, 4) => Board::Fcg,
+            (16, 5) | (159, _) => Board::Lz93d50,
+            (_, _) => Board::Both,
+        };
+        let chip = match (rom.mapper, rom.prg_nvram_size) {
+            _ if board == Board::Fcg => None,
+            (_, 0x80) => Some(EepromChip::X24C01),
+            (_, 0x100) => Some(EepromChip::X24C02),
+            (159, _) => Some(EepromChip::X24C01),
+            (_, _) => Some(EepromChip::X24C02),
+        };
+        let chr_ram = rom.new_chr_ram();
+
+        Self {
+            mirroring: rom.mirroring(),
+            rom,
+            chr_ram,
+            board,
+            eeprom: chip.map(Eeprom::new),
+            chr_banks: [0; 8],
+            prg_bank: 0,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr & 0x000F {
+            n @ 0x0..=0x7 => self.chr_banks[n as usize] = value,
+            0x8 => self.prg_bank = value & 0x0F,
+            0x9 => {
+                self.mirroring = match value & 0b11 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xA => {
+                self.irq_enabled = value & 1 != 0;
+                self.irq_pending = false;
+                if self.board != Board::Fcg {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xB if self.board == Board::Fcg => {
+                self.irq_counter = (self.irq_counter & 0xFF00) | value as u16
+            }
+            0xC if self.board == Board::Fcg => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8
+            }
+            0xB => self.irq_latch = (self.irq_latch & 0xFF00) | value as u16,
+            0xC => self.irq_latch = (self.irq_latch & 0x00FF) | (value as u16) << 8,
+            0xD => {
+                if let Some(eeprom) = &mut self.eeprom {
+                    eeprom.write(value & 0x20 != 0, value & 0x40 != 0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x4000;
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x4000 + (addr as usize & 0x3FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / 0x0400] as usize;
+        bank * 0x0400 + (addr as usize & 0x03FF)
+    }
+}
+
+impl Mapper for Mapper16 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            // EEPROM data out on bit 4
+            0x6000..=0x7FFF => match &self.eeprom {
+                Some(eeprom) => (eeprom.output() as u8) << 4,
+                None => 0,
+            },
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match (addr, self.board) {
+            (0x6000..=0x7FFF, Board::Fcg | Board::Both)
+            | (0x8000..=0xFFFF, Board::Lz93d50 | Board::Both) => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // EEPROM keeps save data without a battery
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.eeprom.as_ref().map(|e| e.data.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.eeprom.as_mut().map(|e| e.data.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    fn bandai(mapper: u8) -> Mapper16 {
+        let ines = test_ines(mapper, 8, 128);
+        Mapper16::new(ines)
+    }
+
+    #[test]
+    fn switch_banks() {
+        let mut m = bandai(16);
+        m.cpu_write(0x6008u16.into(), 3.into());
+        m.cpu_write(0x8003u16.into(), 0x42.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 7.into());
+        assert_eq!(m.ppu_read(0x0C00u16.into()), 0x42.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = bandai(159);
+        m.cpu_write(0x800Bu16.into(), 1.into());
+        m.cpu_write(0x800Cu16.into(), 0.into());
+        m.cpu_write(0x800Au16.into(), 1.into());
+        m.cpu_clock();
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+        m.cpu_write(0x800Au16.into(), 0.into());
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn eeprom_as_save_data() {
+        let m = bandai(159);
+        assert_eq!(m.battery_ram().unwrap().len(), 0x80);
+        let m = bandai(16);
+        assert_eq!(m.battery_ram().unwrap().len(), 0x100);
+    }
+}
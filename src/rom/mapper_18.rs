@@ -0,0 +1,244 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// Jaleco SS88006
+// https://wiki.nesdev.com/w/index.php/INES_Mapper_018
+pub struct Mapper18 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    // $9002; bit 0 enables PRG-RAM and bit 1 allows writes
+    prg_ram_control: u8,
+    mirroring: Mirroring,
+
+    irq_latch: u16,
+    irq_counter: u16,
+    // Bits of the counter that decrement
+    irq_mask: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mapper18 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(rom.prg_ram_len());
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            mirroring: rom.mirroring(),
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            prg_ram_control: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_mask: 0xFFFF,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        let reg = addr & 0xF003;
+        let nibble = value & 0x0F;
+        // Each register pair holds the lower and upper 4 bits
+        let high = reg & 1 != 0;
+        let set = |v: u8| {
+            if high {
+                (v & 0x0F) | nibble << 4
+            } else {
+                (v & 0xF0) | nibble
+            }
+        };
+        match reg {
+            0x8000..=0x8003 => {
+                let i = (reg as usize & 0b10) >> 1;
+                self.prg_banks[i] = set(self.prg_banks[i]);
+            }
+            0x9000..=0x9001 => self.prg_banks[2] = set(self.prg_banks[2]),
+            0x9002 => self.prg_ram_control = value,
+            0xA000..=0xD003 => {
+                let i = (((reg >> 12) - 0xA) * 2 + ((reg >> 1) & 1)) as usize;
+                self.chr_banks[i] = set(self.chr_banks[i]);
+            }
+            0xE000..=0xE003 => {
+                let shift = (reg & 0b11) * 4;
+                self.irq_latch = (self.irq_latch & !(0xF << shift)) | (nibble as u16) << shift;
+            }
+            0xF000 => {
+                self.irq_counter = self.irq_latch;
+                self.irq_pending = false;
+            }
+            0xF001 => {
+                self.irq_enabled = value & 1 != 0;
+                self.irq_mask = if value & 0b1000 != 0 {
+                    0x000F
+                } else if value & 0b0100 != 0 {
+                    0x00FF
+                } else if value & 0b0010 != 0 {
+                    0x0FFF
+                } else {
+                    0xFFFF
+                };
+                self.irq_pending = false;
+            }
+            0xF002 => {
+                self.mirroring = match value & 0b11 {
+                    0 => Mirroring::Horizontal,
+                    1 => Mirroring::Vertical,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            // $F003 drives the uPD7756 ADPCM chip which is not emulated
+            _ => {}
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / 0x0400] as usize;
+        bank * 0x0400 + (addr as usize & 0x03FF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_control & 0b01 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_control & 0b10 != 0
+    }
+}
+
+impl Mapper for Mapper18 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        // Only the selected lower bits count down
+        let counter = self.irq_counter & self.irq_mask;
+        let counter = counter.wrapping_sub(1) & self.irq_mask;
+        self.irq_counter = (self.irq_counter & !self.irq_mask) | counter;
+        if counter == 0 {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    fn ss88006() -> Mapper18 {
+        let ines = test_ines(18, 16, 128);
+        Mapper18::new(ines)
+    }
+
+    #[test]
+    fn switch_banks() {
+        let mut m = ss88006();
+        m.cpu_write(0x8002u16.into(), 0x0B.into());
+        m.cpu_write(0x9000u16.into(), 0x05.into());
+        m.cpu_write(0xD002u16.into(), 0x03.into());
+        m.cpu_write(0xD003u16.into(), 0x04.into());
+        assert_eq!(m.cpu_read(0xA000u16.into()), 11.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 5.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 15.into());
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 0x43.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = ss88006();
+        // 4-bit counter leaves the upper bits as they are
+        for (addr, v) in [(0xE000u16, 2), (0xE001, 0), (0xE002, 0), (0xE003, 1)] {
+            m.cpu_write(addr.into(), v.into());
+        }
+        m.cpu_write(0xF000u16.into(), 0.into());
+        m.cpu_write(0xF001u16.into(), 0b1001.into());
+        m.cpu_clock();
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+        assert_eq!(m.irq_counter, 0x1000);
+
+        m.cpu_write(0xF000u16.into(), 0.into());
+        assert!(!m.irq());
+    }
+}
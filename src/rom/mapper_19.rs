@@ -0,0 +1,430 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// Namco 163
+// https://wiki.nesdev.com/w/index.php/INES_Mapper_019
+pub struct Mapper19 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // PRG-RAM followed by the 128-byte internal RAM, both kept by the battery
+    ram: Vec<u8>,
+    prg_ram_len: usize,
+    battery: bool,
+
+    // $8000-$B800 for pattern tables and $C000-$D800 for nametables
+    chr_banks: [u8; 12],
+    prg_banks: [u8; 3],
+    // $E000 bit 6
+    sound_disabled: bool,
+    // $E800 bits 6-7; disable CIRAM in $0000-$0FFF and $1000-$1FFF
+    ciram_disabled: [bool; 2],
+    // $F800; internal RAM address with auto-increment, also PRG-RAM write protection
+    ram_port: u8,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    audio: Namco163Audio,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ChrPage {
+    Chr(usize),
+    // 1KB page of the 2KB VRAM in the console
+    Ciram(usize),
+}
+
+const INTERNAL_RAM_LEN: usize = 0x80;
+
+impl Mapper19 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram_len = rom.prg_ram_len();
+        let mut ram = rom.new_prg_ram(prg_ram_len);
+        let prg_ram_len = ram.len();
+        ram.extend([0; INTERNAL_RAM_LEN]);
+        let battery = rom.battery_backed();
+
+        Self {
+            rom,
+            chr_ram,
+            ram,
+            prg_ram_len,
+            battery,
+            chr_banks: [0; 12],
+            prg_banks: [0; 3],
+            sound_disabled: false,
+            ciram_disabled: [false; 2],
+            ram_port: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Default::default(),
+        }
+    }
+
+    fn internal_ram(&self) -> &[u8] {
+        &self.ram[self.prg_ram_len..]
+    }
+
+    fn internal_ram_index(&mut self) -> usize {
+        let i = (self.ram_port & 0x7F) as usize;
+        if self.ram_port & 0x80 != 0 {
+            self.ram_port = 0x80 | (self.ram_port.wrapping_add(1) & 0x7F);
+        }
+        self.prg_ram_len + i
+    }
+
+    // Writes are enabled only while $F800 is $40-$4F and the 2KB window is not protected
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr as usize - 0x6000) / 0x0800;
+        self.ram_port & 0xF0 == 0x40 && self.ram_port & (1 << window) == 0
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    // 1KB page of CHR or CIRAM selected by the register
+    fn chr_page(&self, i: usize) -> ChrPage {
+        let value = self.chr_banks[i];
+        let ciram_enabled = match i {
+            0..=3 => !self.ciram_disabled[0],
+            4..=7 => !self.ciram_disabled[1],
+            _ => true,
+        };
+        if 0xE0 <= value && ciram_enabled {
+            ChrPage::Ciram((value & 1) as usize)
+        } else {
+            ChrPage::Chr(value as usize)
+        }
+    }
+
+    fn read_chr(&self, page: usize, addr: u16) -> u8 {
+        let i = page * 0x0400 + (addr as usize & 0x03FF);
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+    }
+}
+
+impl Mapper for Mapper19 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x4800..=0x4FFF => {
+                let i = self.internal_ram_index();
+                self.ram[i]
+            }
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF if 0 < self.prg_ram_len => {
+                self.ram[(addr as usize - 0x6000) % self.prg_ram_len]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match self.chr_page(addr as usize / 0x0400) {
+            ChrPage::Chr(page) => self.read_chr(page, addr),
+            // Mapped by chr_ciram_page
+            ChrPage::Ciram(_) => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x4800..=0x4FFF => {
+                let i = self.internal_ram_index();
+                self.ram[i] = value;
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16 & 0x7F) << 8);
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if 0 < self.prg_ram_len && self.prg_ram_writable(addr) => {
+                self.ram[(addr as usize - 0x6000) % self.prg_ram_len] = value;
+            }
+            0x8000..=0xDFFF => self.chr_banks[(addr as usize - 0x8000) / 0x0800] = value,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = value & 0x3F;
+                self.sound_disabled = value & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = value & 0x3F;
+                self.ciram_disabled = [value & 0x40 != 0, value & 0x80 != 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = value & 0x3F,
+            0xF800..=0xFFFF => self.ram_port = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        if let (ChrPage::Chr(page), false) = (
+            self.chr_page(addr as usize / 0x0400),
+            self.chr_ram.is_empty(),
+        ) {
+            let i = (page * 0x0400 + (addr as usize & 0x03FF)) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Custom
+    }
+
+    fn read_nametable(&mut self, addr: Word, ciram: &[Byte]) -> Byte {
+        let addr: u16 = addr.into();
+        let i = 8 + (addr as usize & 0x0FFF) / 0x0400;
+        match self.chr_page(i) {
+            ChrPage::Chr(page) => self.read_chr(page, addr).into(),
+            ChrPage::Ciram(page) => ciram[page * 0x0400 + (addr as usize & 0x03FF)],
+        }
+    }
+
+    fn write_nametable(&mut self, addr: Word, value: Byte, ciram: &mut [Byte]) {
+        let addr: u16 = addr.into();
+        let i = 8 + (addr as usize & 0x0FFF) / 0x0400;
+        // CHR-ROM pages are read-only
+        if let ChrPage::Ciram(page) = self.chr_page(i) {
+            ciram[page * 0x0400 + (addr as usize & 0x03FF)] = value;
+        }
+    }
+
+    fn chr_ciram_page(&self, addr: Word) -> Option<usize> {
+        let addr: u16 = addr.into();
+        match self.chr_page(addr as usize / 0x0400) {
+            ChrPage::Ciram(page) => Some(page),
+            ChrPage::Chr(_) => None,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+        if !self.sound_disabled {
+            self.audio.clock(&mut self.ram[self.prg_ram_len..]);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output(self.internal_ram())
+        }
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.ram.as_mut_slice())
+    }
+}
+
+// Up to 8 wavetable channels whose registers and samples share the internal RAM
+// https://wiki.nesdev.com/w/index.php/Namco_163_audio
+#[derive(Debug, Default)]
+struct Namco163Audio {
+    // Latest output of each channel
+    outputs: [i16; 8],
+    // Channel being updated
+    current: usize,
+    cycles: u8,
+}
+
+// Each channel is updated in turn every 15 CPU cycles
+const CHANNEL_UPDATE_CYCLES: u8 = 15;
+
+// Full volume of a single channel relative to the APU output
+const NAMCO_163_LEVEL: f32 = 0.15;
+
+impl Namco163Audio {
+    fn enabled_channels(ram: &[u8]) -> usize {
+        ((ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    fn clock(&mut self, ram: &mut [u8]) {
+        self.cycles += 1;
+        if self.cycles < CHANNEL_UPDATE_CYCLES {
+            return;
+        }
+        self.cycles = 0;
+
+        // Channels 8 down to 8 - N + 1 are enabled; their registers are at $78, $70, ...
+        let enabled = Self::enabled_channels(ram);
+        self.current = if 8 - enabled < self.current {
+            self.current - 1
+        } else {
+            7
+        };
+        self.outputs[self.current] = Self::update_channel(self.current, ram);
+    }
+
+    fn update_channel(channel: usize, ram: &mut [u8]) -> i16 {
+        let base = 0x40 + channel * 8;
+        let frequency =
+            ram[base] as u32 | (ram[base + 2] as u32) << 8 | (ram[base + 4] as u32 & 0b11) << 16;
+        let phase =
+            ram[base + 1] as u32 | (ram[base + 3] as u32) << 8 | (ram[base + 5] as u32) << 16;
+        let length = 256 - (ram[base + 4] & 0xFC) as u32;
+
+        // Phase lives in the internal RAM so that the CPU can read it back
+        let phase = (phase + frequency) % (length << 16);
+        ram[base + 1] = phase as u8;
+        ram[base + 3] = (phase >> 8) as u8;
+        ram[base + 5] = (phase >> 16) as u8;
+
+        let sample_addr = ((phase >> 16) as usize + ram[base + 6] as usize) & 0xFF;
+        let byte = ram[sample_addr / 2];
+        let sample = if sample_addr & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        let volume = ram[base + 7] & 0x0F;
+        (sample as i16 - 8) * volume as i16
+    }
+
+    fn output(&self, ram: &[u8]) -> f32 {
+        // Channels are heard one at a time, so each of them is 1/N of the mix
+        let enabled = Self::enabled_channels(ram);
+        let sum: i16 = self.outputs[8 - enabled..].iter().sum();
+        sum as f32 / enabled as f32 / 120.0 * NAMCO_163_LEVEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::inesfile::Flag6;
+    use crate::rom::test_ines;
+
+    fn n163() -> Mapper19 {
+        let mut ines = test_ines(19, 16, 128);
+        // Battery-backed
+        ines.flag6.insert(Flag6::BATTERY_BACKED_PRG_RAM);
+        Mapper19::new(ines)
+    }
+
+    #[test]
+    fn chr_and_ciram_pages() {
+        let mut m = n163();
+        m.cpu_write(0x8800u16.into(), 0x05.into());
+        m.cpu_write(0x9000u16.into(), 0xE1.into());
+        assert_eq!(m.ppu_read(0x0400u16.into()), 0x05.into());
+        assert_eq!(m.chr_ciram_page(0x0800u16.into()), Some(1));
+
+        // $E0-$FF select CHR-ROM while CIRAM is disabled
+        m.cpu_write(0xE800u16.into(), 0x40.into());
+        assert_eq!(m.chr_ciram_page(0x0800u16.into()), None);
+        assert_eq!(m.ppu_read(0x0800u16.into()), (0xE1 % 128).into());
+
+        // Nametables
+        let mut ciram = [Byte::from(0); 0x0800];
+        m.cpu_write(0xC000u16.into(), 0xE1.into());
+        m.cpu_write(0xD000u16.into(), 0x07.into());
+        m.write_nametable(0x2005u16.into(), 0x12.into(), &mut ciram);
+        assert_eq!(ciram[0x0405], 0x12.into());
+        assert_eq!(m.read_nametable(0x2805u16.into(), &ciram), 0x07.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = n163();
+        m.cpu_write(0x5000u16.into(), 0xFE.into());
+        m.cpu_write(0x5800u16.into(), 0xFF.into());
+        m.cpu_clock();
+        assert!(m.irq());
+        assert_eq!(m.cpu_read(0x5800u16.into()), 0xFF.into());
+
+        // Stops at $7FFF
+        m.cpu_clock();
+        assert_eq!(m.cpu_read(0x5000u16.into()), 0xFF.into());
+        m.cpu_write(0x5000u16.into(), 0.into());
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn internal_ram_and_battery() {
+        let mut m = n163();
+        m.cpu_write(0xF800u16.into(), 0x80.into());
+        m.cpu_write(0x4800u16.into(), 0x12.into());
+        m.cpu_write(0x4800u16.into(), 0x34.into());
+        m.cpu_write(0xF800u16.into(), 0x01.into());
+        assert_eq!(m.cpu_read(0x4800u16.into()), 0x34.into());
+
+        // PRG-RAM is protected unless $F800 is $4x
+        m.cpu_write(0x6000u16.into(), 0x56.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0.into());
+        m.cpu_write(0xF800u16.into(), 0x40.into());
+        m.cpu_write(0x6000u16.into(), 0x56.into());
+
+        let ram = m.battery_ram().unwrap();
+        assert_eq!(ram.len(), 0x2000 + INTERNAL_RAM_LEN);
+        assert_eq!(ram[0], 0x56);
+        assert_eq!(ram[0x2000..0x2002], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn wavetable() {
+        let mut m = n163();
+        // Channel 8 playing a square wave at $00 with length 4 and full volume
+        let mut regs = [0u8; 0x80];
+        regs[0x00] = 0xFF;
+        regs[0x01] = 0x00;
+        regs[0x78] = 0x00;
+        regs[0x7A] = 0x00;
+        regs[0x7C] = 0x01 | (256 - 4) as u8;
+        regs[0x7E] = 0x00;
+        regs[0x7F] = 0x0F;
+        m.cpu_write(0xF800u16.into(), 0x80.into());
+        for v in regs {
+            m.cpu_write(0x4800u16.into(), v.into());
+        }
+
+        for _ in 0..CHANNEL_UPDATE_CYCLES {
+            m.cpu_clock();
+        }
+        // Phase advanced by $10000 into the second sample
+        assert_eq!(m.internal_ram()[0x7D], 1);
+        assert_eq!(m.audio.outputs[7], 7 * 15);
+        assert!(0.0 < m.audio_output());
+
+        m.cpu_write(0xE000u16.into(), 0x40.into());
+        assert_eq!(m.audio_output(), 0.0);
+    }
+}
@@ -0,0 +1,139 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/UxROM
+pub struct Mapper2 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // Only for the trainer
+    prg_ram: Vec<u8>,
+
+    mirroring: Mirroring,
+    bus_conflicted: bool,
+
+    prg_bank: usize,
+}
+
+impl Mapper2 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(0);
+
+        Self {
+            mirroring: rom.mirroring(),
+            bus_conflicted: rom.bus_conflicted(),
+            rom,
+            chr_ram,
+            prg_ram,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x4000;
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank % banks,
+            _ => banks - 1,
+        };
+        bank * 0x4000 + (addr as usize & 0x3FFF)
+    }
+}
+
+impl Mapper for Mapper2 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => {
+                self.rom.chr_rom[addr as usize % self.rom.chr_rom.len()]
+            }
+            0x0000..=0x1FFF => self.chr_ram[addr as usize % self.chr_ram.len()],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into()
+            }
+            0x8000..=0xFFFF => {
+                let mut value: u8 = value.into();
+                if self.bus_conflicted {
+                    value &= self.rom.prg_rom[self.prg_addr(addr)];
+                }
+                self.prg_bank = value as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let len = self.chr_ram.len();
+                self.chr_ram[addr as usize % len] = value.into()
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::inesfile::Flag10;
+    use crate::rom::test_ines;
+
+    // 64KB PRG-ROM and CHR-RAM
+    fn uxrom(flag10: Flag10) -> Mapper2 {
+        let mut ines = test_ines(2, 8, 0);
+        ines.prg_rom.truncate(0x10000);
+        ines.flag10 = flag10;
+        Mapper2::new(ines)
+    }
+
+    #[test]
+    fn switch_prg_bank() {
+        let mut m = uxrom(Flag10::empty());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 3.into());
+        m.cpu_write(0x8000u16.into(), 2.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 2.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 3.into());
+
+        // CHR-RAM
+        m.ppu_write(0x1000u16.into(), 0x55.into());
+        assert_eq!(m.ppu_read(0x1000u16.into()), 0x55.into());
+    }
+
+    #[test]
+    fn bus_conflict() {
+        let mut m = uxrom(Flag10::BUS_CONFLICTED);
+        // ROM has 0 at $8000 in the first bank and 3 at $FFFF in the last one
+        m.cpu_write(0x8000u16.into(), 2.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0.into());
+
+        m.cpu_write(0xFFFFu16.into(), 2.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 2.into());
+    }
+}
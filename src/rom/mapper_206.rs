@@ -0,0 +1,124 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// Namco 108 (DxROM); a predecessor of MMC3 without IRQ, mirroring control and PRG/CHR modes
+// https://wiki.nesdev.com/w/index.php/INES_Mapper_206
+pub struct Mapper206 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // Only for the trainer
+    prg_ram: Vec<u8>,
+
+    bank_select: u8,
+    registers: [u8; 8],
+}
+
+impl Mapper206 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(0);
+        Self {
+            rom,
+            chr_ram,
+            prg_ram,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let bank = match addr {
+            0x8000..=0x9FFF => (self.registers[6] & 0x0F) as usize,
+            0xA000..=0xBFFF => (self.registers[7] & 0x0F) as usize,
+            0xC000..=0xDFFF => banks - 2,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let bank = match addr {
+            0x0000..=0x07FF => (self.registers[0] & 0x3E) as usize + addr / 0x0400,
+            0x0800..=0x0FFF => (self.registers[1] & 0x3E) as usize + (addr - 0x0800) / 0x0400,
+            _ => (self.registers[2 + (addr - 0x1000) / 0x0400] & 0x3F) as usize,
+        };
+        bank * 0x0400 + (addr & 0x03FF)
+    }
+}
+
+impl Mapper for Mapper206 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match (addr, addr & 1) {
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => self.registers[(self.bank_select & 0b111) as usize] = value,
+            (0x6000..=0x7FFF, _) if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    #[test]
+    fn switch_banks() {
+        let ines = test_ines(206, 16, 64);
+        let mut m = Mapper206::new(ines);
+
+        assert_eq!(m.cpu_read(0xC000u16.into()), 14.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 15.into());
+
+        for (r, v) in [(0, 0x0B), (2, 0x21), (6, 3), (7, 0x45)] {
+            m.cpu_write(0x8000u16.into(), r.into());
+            m.cpu_write(0x8001u16.into(), v.into());
+        }
+        assert_eq!(m.ppu_read(0x0000u16.into()), 0x0A.into());
+        assert_eq!(m.ppu_read(0x0400u16.into()), 0x0B.into());
+        assert_eq!(m.ppu_read(0x1000u16.into()), 0x21.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xA000u16.into()), 5.into());
+    }
+}
@@ -0,0 +1,274 @@
+use super::inesfile::INESFile;
+use super::vrc_irq::VrcIrq;
+use super::*;
+use crate::data_unit::*;
+
+// Konami VRC2 and VRC4 on mapper 21, 22, 23 and 25
+// https://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+pub struct Mapper21 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    chip: Chip,
+    // CPU address lines connected to A0 and A1 of the chip
+    a0: u16,
+    a1: u16,
+    // VRC2a ignores the lowest bit of CHR banks
+    chr_shift: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    // 1-bit latch at $6000-$6FFF on VRC2 without PRG-RAM
+    latch: u8,
+
+    irq: VrcIrq,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Chip {
+    Vrc2,
+    Vrc4,
+}
+
+impl Mapper21 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        // Without submapper, lines of every variant on the mapper are combined
+        let (chip, a0, a1) = match (rom.mapper, rom.submapper) {
+            // VRC4a
+            (21, 1) => (Chip::Vrc4, 1 << 1, 1 << 2),
+            // VRC4c
+            (21, 2) => (Chip::Vrc4, 1 << 6, 1 << 7),
+            (21, _) => (Chip::Vrc4, 1 << 1 | 1 << 6, 1 << 2 | 1 << 7),
+            // VRC2a
+            (22, _) => (Chip::Vrc2, 1 << 1, 1 << 0),
+            // VRC4f
+            (23, 1) => (Chip::Vrc4, 1 << 0, 1 << 1),
+            // VRC4e
+            (23, 2) => (Chip::Vrc4, 1 << 2, 1 << 3),
+            // VRC2b
+            (23, 3) => (Chip::Vrc2, 1 << 0, 1 << 1),
+            (23, _) => (Chip::Vrc4, 1 << 0 | 1 << 2, 1 << 1 | 1 << 3),
+            // VRC4b
+            (25, 1) => (Chip::Vrc4, 1 << 1, 1 << 0),
+            // VRC4d
+            (25, 2) => (Chip::Vrc4, 1 << 3, 1 << 2),
+            // VRC2c
+            (25, 3) => (Chip::Vrc2, 1 << 1, 1 << 0),
+            (_, _) => (Chip::Vrc4, 1 << 1 | 1 << 3, 1 << 0 | 1 << 2),
+        };
+        let chr_shift = if rom.mapper == 22 { 1 } else { 0 };
+
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram_len = match chip {
+            Chip::Vrc4 => rom.prg_ram_len(),
+            // Most VRC2 boards have no PRG-RAM
+            Chip::Vrc2 if rom.nes2 || rom.battery_backed() => rom.prg_ram_len(),
+            Chip::Vrc2 => 0,
+        };
+        let prg_ram = rom.new_prg_ram(prg_ram_len);
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            mirroring: rom.mirroring(),
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            chip,
+            a0,
+            a1,
+            chr_shift,
+            prg_banks: [0, 1],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            latch: 0,
+            irq: Default::default(),
+        }
+    }
+
+    // Register address normalized to $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        let mut reg = addr & 0xF000;
+        if addr & self.a0 != 0 {
+            reg |= 0b01;
+        }
+        if addr & self.a1 != 0 {
+            reg |= 0b10;
+        }
+        reg
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match (self.chip, self.register(addr)) {
+            (_, 0x8000..=0x8003) => self.prg_banks[0] = value & 0x1F,
+            (Chip::Vrc2, 0x9000..=0x9003) | (Chip::Vrc4, 0x9000..=0x9001) => {
+                let mask = if self.chip == Chip::Vrc2 { 0b01 } else { 0b11 };
+                self.mirroring = match value & mask {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            (Chip::Vrc4, 0x9002..=0x9003) => self.prg_swap = value & 0b10 != 0,
+            (_, 0xA000..=0xA003) => self.prg_banks[1] = value & 0x1F,
+            (_, reg @ 0xB000..=0xE003) => {
+                // Lower 4 bits and upper bits of 1KB banks
+                let i = (((reg >> 12) - 0xB) * 2 + ((reg >> 1) & 1)) as usize;
+                let bank = self.chr_banks[i];
+                self.chr_banks[i] = if reg & 1 == 0 {
+                    (bank & 0x1F0) | (value & 0x0F) as u16
+                } else {
+                    (bank & 0x0F) | ((value & 0x1F) as u16) << 4
+                };
+            }
+            (Chip::Vrc4, 0xF000) => self.irq.write_latch_low(value),
+            (Chip::Vrc4, 0xF001) => self.irq.write_latch_high(value),
+            (Chip::Vrc4, 0xF002) => self.irq.write_control(value),
+            (Chip::Vrc4, 0xF003) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let bank = match (addr, self.prg_swap) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => banks - 2,
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[addr as usize / 0x0400] >> self.chr_shift) as usize;
+        bank * 0x0400 + (addr as usize & 0x03FF)
+    }
+}
+
+impl Mapper for Mapper21 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x6000..=0x6FFF if self.chip == Chip::Vrc2 => self.latch,
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+            0x6000..=0x6FFF if self.chip == Chip::Vrc2 => self.latch = value & 1,
+            0x8000..=0xFFFF => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.occurred
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    // NES 2.0 header with 128KB PRG-ROM and 128KB CHR-ROM
+    fn vrc(mapper: u8, submapper: u8) -> Mapper21 {
+        let mut ines = test_ines(mapper, 16, 128);
+        ines.nes2 = true;
+        ines.submapper = submapper;
+        Mapper21::new(ines)
+    }
+
+    #[test]
+    fn address_lines() {
+        // VRC4b: A1 and A0
+        let mut m = vrc(25, 1);
+        m.cpu_write(0x8000u16.into(), 3.into());
+        m.cpu_write(0x9001u16.into(), 0b10.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 14.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 3.into());
+
+        // VRC4e: A2 and A3
+        let mut m = vrc(23, 2);
+        m.cpu_write(0xB000u16.into(), 0x05.into());
+        m.cpu_write(0xB004u16.into(), 0x02.into());
+        m.cpu_write(0xB008u16.into(), 0x06.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 0x25.into());
+        assert_eq!(m.ppu_read(0x0400u16.into()), 0x06.into());
+    }
+
+    #[test]
+    fn vrc2a_chr_banks() {
+        let mut m = vrc(22, 0);
+        m.cpu_write(0xB000u16.into(), 0x05.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 0x02.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = vrc(21, 1);
+        m.cpu_write(0xF000u16.into(), 0x0D.into());
+        m.cpu_write(0xF002u16.into(), 0x0F.into());
+        m.cpu_write(0xF004u16.into(), 0b110.into());
+        for _ in 0..2 {
+            m.cpu_clock();
+        }
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+
+        m.cpu_write(0xF006u16.into(), 0.into());
+        assert!(!m.irq());
+    }
+}
@@ -0,0 +1,397 @@
+use super::inesfile::INESFile;
+use super::vrc_irq::VrcIrq;
+use super::*;
+use crate::data_unit::*;
+
+// Konami VRC6 on mapper 24 (VRC6a) and 26 (VRC6b)
+// https://wiki.nesdev.com/w/index.php/VRC6
+pub struct Mapper24 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    // VRC6b has A0 and A1 swapped
+    swapped: bool,
+
+    prg_16k_bank: u8,
+    prg_8k_bank: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    ppu_banking: u8,
+
+    irq: VrcIrq,
+
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+    // $9003
+    frequency_control: u8,
+}
+
+impl Mapper24 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let swapped = rom.mapper == 26;
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(rom.prg_ram_len());
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            swapped,
+            prg_16k_bank: 0,
+            prg_8k_bank: 0,
+            chr_banks: [0; 8],
+            ppu_banking: 0,
+            irq: Default::default(),
+            pulse1: Default::default(),
+            pulse2: Default::default(),
+            sawtooth: Default::default(),
+            frequency_control: 0,
+        }
+    }
+
+    // Register address normalized to $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        if self.swapped {
+            (addr & 0xF000) | ((addr & 0b01) << 1) | ((addr & 0b10) >> 1)
+        } else {
+            addr & 0xF003
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match self.register(addr) {
+            0x8000..=0x8003 => self.prg_16k_bank = value & 0x0F,
+            reg @ (0x9000..=0x9002) => self.pulse1.write(reg & 0b11, value),
+            0x9003 => self.frequency_control = value,
+            reg @ (0xA000..=0xA002) => self.pulse2.write(reg & 0b11, value),
+            reg @ (0xB000..=0xB002) => self.sawtooth.write(reg & 0b11, value),
+            0xB003 => self.ppu_banking = value,
+            0xC000..=0xC003 => self.prg_8k_bank = value & 0x1F,
+            reg @ 0xD000..=0xE003 => {
+                let i = (((reg >> 12) - 0xD) * 4 + (reg & 0b11)) as usize;
+                self.chr_banks[i] = value;
+            }
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        !self.prg_ram.is_empty() && self.ppu_banking & 0x80 != 0
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let bank = match addr {
+            0x8000..=0xBFFF => {
+                self.prg_16k_bank as usize * 2 + (addr as usize & 0x2000 != 0) as usize
+            }
+            0xC000..=0xDFFF => self.prg_8k_bank as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let slot = addr as usize / 0x0400;
+        // 2KB banks take PPU A10 in place of the lowest bit
+        let a10 = slot & 1;
+        let half = |r: u8| {
+            if self.ppu_banking & 0x20 != 0 {
+                (r & 0xFE) as usize | a10
+            } else {
+                r as usize
+            }
+        };
+        let bank = match (self.ppu_banking & 0b11, slot) {
+            (0, _) => self.chr_banks[slot] as usize,
+            (1, _) => half(self.chr_banks[slot / 2]),
+            (_, 0..=3) => self.chr_banks[slot] as usize,
+            (_, _) => half(self.chr_banks[4 + (slot - 4) / 2]),
+        };
+        bank * 0x0400 + (addr as usize & 0x03FF)
+    }
+
+    // Frequency divider is shifted by $9003
+    fn frequency_shift(&self) -> u8 {
+        if self.frequency_control & 0b100 != 0 {
+            8
+        } else if self.frequency_control & 0b010 != 0 {
+            4
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mapper24 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Nametables from CHR-ROM ($B003 bit 4) are not supported
+        match (self.ppu_banking >> 2) & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+
+        // Halt
+        if self.frequency_control & 1 != 0 {
+            return;
+        }
+        let shift = self.frequency_shift();
+        self.pulse1.clock(shift);
+        self.pulse2.clock(shift);
+        self.sawtooth.clock(shift);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.occurred
+    }
+
+    fn audio_output(&self) -> f32 {
+        // Linear 6-bit DAC; a full volume pulse is about as loud as the APU pulse
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 * 0.00752
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/VRC6_audio
+#[derive(Debug, Default)]
+struct Pulse {
+    // $9000
+    control: u8,
+    period: u16,
+    enabled: bool,
+
+    divider: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.control = value,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if 0 < self.divider {
+            self.divider -= 1;
+        } else {
+            self.divider = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let volume = self.control & 0x0F;
+        let duty = (self.control >> 4) & 0b111;
+        // Ignore duty in digitized mode
+        let high = self.control & 0x80 != 0 || self.step <= duty;
+        if self.enabled && high {
+            volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+
+    divider: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if 0 < self.divider {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period >> shift;
+
+        // Accumulates on every other step and resets after 7 accumulations
+        self.step += 1;
+        if 14 <= self.step {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    fn vrc6(mapper: u8) -> Mapper24 {
+        let ines = test_ines(mapper, 16, 128);
+        Mapper24::new(ines)
+    }
+
+    #[test]
+    fn switch_prg_banks() {
+        let mut m = vrc6(24);
+        m.cpu_write(0x8000u16.into(), 3.into());
+        m.cpu_write(0xC000u16.into(), 9.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 6.into());
+        assert_eq!(m.cpu_read(0xA000u16.into()), 7.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 9.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 15.into());
+    }
+
+    #[test]
+    fn switch_chr_banks() {
+        let mut m = vrc6(26);
+        // $D001 is R2 on VRC6b
+        m.cpu_write(0xD001u16.into(), 0x12.into());
+        m.cpu_write(0xD002u16.into(), 0x11.into());
+        assert_eq!(m.ppu_read(0x0400u16.into()), 0x11.into());
+        assert_eq!(m.ppu_read(0x0800u16.into()), 0x12.into());
+
+        // 2KB banks with PPU A10
+        m.cpu_write(0xB003u16.into(), 0x21.into());
+        assert_eq!(m.ppu_read(0x0800u16.into()), 0x10.into());
+        assert_eq!(m.ppu_read(0x0C00u16.into()), 0x11.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = vrc6(24);
+        m.cpu_write(0xF000u16.into(), 0xFE.into());
+        m.cpu_write(0xF001u16.into(), 0b110.into());
+        m.cpu_clock();
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+        m.cpu_write(0xF002u16.into(), 0.into());
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn audio() {
+        let mut m = vrc6(24);
+        assert_eq!(m.audio_output(), 0.0);
+
+        // Digitized mode outputs the volume as it is
+        m.cpu_write(0x9000u16.into(), 0x8F.into());
+        m.cpu_write(0x9002u16.into(), 0x80.into());
+        assert_eq!(m.pulse1.output(), 15);
+
+        m.cpu_write(0xB000u16.into(), 0x20.into());
+        m.cpu_write(0xB002u16.into(), 0x80.into());
+        for _ in 0..4 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.sawtooth.output(), 8);
+        assert!(0.0 < m.audio_output());
+
+        // Halt
+        m.cpu_write(0x9003u16.into(), 1.into());
+        for _ in 0..4 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.sawtooth.output(), 8);
+    }
+}
@@ -0,0 +1,127 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/CNROM
+pub struct Mapper3 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // Only for the trainer
+    prg_ram: Vec<u8>,
+
+    mirroring: Mirroring,
+    bus_conflicted: bool,
+
+    chr_bank: usize,
+}
+
+impl Mapper3 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        Self {
+            chr_ram: rom.new_chr_ram(),
+            prg_ram: rom.new_prg_ram(0),
+            mirroring: rom.mirroring(),
+            bus_conflicted: rom.bus_conflicted(),
+            rom,
+            chr_bank: 0,
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        // 16KB PRG-ROM is mirrored into $C000-$FFFF
+        (addr as usize - 0x8000) % self.rom.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let len = if self.chr_ram.is_empty() {
+            self.rom.chr_rom.len()
+        } else {
+            self.chr_ram.len()
+        };
+        (self.chr_bank * 0x2000 + addr as usize) % len
+    }
+}
+
+impl Mapper for Mapper3 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => self.rom.chr_rom[self.chr_addr(addr)],
+            0x0000..=0x1FFF => self.chr_ram[self.chr_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into()
+            }
+            0x8000..=0xFFFF => {
+                let mut value: u8 = value.into();
+                if self.bus_conflicted {
+                    value &= self.rom.prg_rom[self.prg_addr(addr)];
+                }
+                self.chr_bank = value as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let i = self.chr_addr(addr);
+                self.chr_ram[i] = value.into()
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    #[test]
+    fn chr_ram_without_chr_rom() {
+        let mut m = Mapper3::new(test_ines(3, 8, 0));
+
+        m.cpu_write(0x8000u16.into(), 1.into());
+        m.ppu_write(0x0010u16.into(), 0x12.into());
+        assert_eq!(m.ppu_read(0x0010u16.into()), 0x12.into());
+    }
+
+    #[test]
+    fn switch_chr_bank() {
+        // 32KB CHR-ROM
+        let mut m = Mapper3::new(test_ines(3, 4, 32));
+        m.cpu_write(0x8000u16.into(), 2.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 16.into());
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 23.into());
+        // Out of range banks wrap around
+        m.cpu_write(0x8000u16.into(), 5.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 8.into());
+    }
+}
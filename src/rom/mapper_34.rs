@@ -0,0 +1,143 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/INES_Mapper_034
+pub struct Mapper34 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    board: Board,
+    mirroring: Mirroring,
+    bus_conflicted: bool,
+
+    prg_bank: usize,
+    chr_banks: [usize; 2],
+}
+
+enum Board {
+    // https://wiki.nesdev.com/w/index.php/BNROM
+    Bnrom,
+    // https://wiki.nesdev.com/w/index.php/NINA-001
+    Nina001,
+}
+
+impl Mapper34 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let board = match rom.submapper {
+            1 => Board::Nina001,
+            2 => Board::Bnrom,
+            // NINA-001 is the only variant with more than 8KB of CHR-ROM
+            _ if 0x2000 < rom.chr_rom.len() => Board::Nina001,
+            _ => Board::Bnrom,
+        };
+
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = match board {
+            Board::Nina001 => rom.new_prg_ram(0x2000),
+            // Only for the trainer
+            Board::Bnrom => rom.new_prg_ram(0),
+        };
+
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            mirroring: rom.mirroring(),
+            bus_conflicted: rom.bus_conflicted(),
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            board,
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        (self.prg_bank * 0x8000 + (addr as usize & 0x7FFF)) % self.rom.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let bank = self.chr_banks[addr / 0x1000];
+        (bank * 0x1000 + (addr & 0x0FFF)) % self.rom.chr_rom.len()
+    }
+}
+
+impl Mapper for Mapper34 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => self.rom.chr_rom[self.chr_addr(addr)],
+            0x0000..=0x1FFF => self.chr_ram[addr as usize % self.chr_ram.len()],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match (&self.board, addr) {
+            (Board::Nina001, 0x6000..=0x7FFF) => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+                match addr {
+                    0x7FFD => self.prg_bank = (value & 1) as usize,
+                    0x7FFE => self.chr_banks[0] = (value & 0x0F) as usize,
+                    0x7FFF => self.chr_banks[1] = (value & 0x0F) as usize,
+                    _ => {}
+                }
+            }
+            (Board::Bnrom, 0x6000..=0x7FFF) if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+            (Board::Bnrom, 0x8000..=0xFFFF) => {
+                let mut value = value;
+                if self.bus_conflicted {
+                    value &= self.rom.prg_rom[self.prg_addr(addr)];
+                }
+                self.prg_bank = value as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        if let 0x0000..=0x1FFF = addr {
+            if !self.chr_ram.is_empty() {
+                let len = self.chr_ram.len();
+                self.chr_ram[addr as usize % len] = value.into();
+            }
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
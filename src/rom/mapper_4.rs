@@ -0,0 +1,338 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/MMC3
+pub struct Mapper4 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    four_screen: bool,
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_occurred: bool,
+
+    a12: bool,
+    // CPU cycles since PPU A12 went low
+    a12_low_cycles: u8,
+}
+
+// A12 has to stay low this long for the next rise to clock the IRQ counter
+const A12_LOW_CYCLES: u8 = 3;
+
+impl Mapper4 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(rom.prg_ram_len());
+        let mirroring = rom.mirroring();
+        let four_screen = rom.four_screen();
+
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            four_screen,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_occurred: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match (addr, addr & 1) {
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => self.registers[(self.bank_select & 0b111) as usize] = value,
+            (0xA000..=0xBFFF, 0) if !self.four_screen => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                }
+            }
+            (0xA000..=0xBFFF, 0) => {}
+            (0xA000..=0xBFFF, _) => self.prg_ram_protect = value,
+            (0xC000..=0xDFFF, 0) => self.irq_latch = value,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq_occurred = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        // PRG-ROM smaller than 16KB is rejected by its constructor in `MapperRegistry`
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        let second_last = banks.saturating_sub(2);
+        let prg_mode = self.bank_select & 0x40 != 0;
+
+        let bank = match (addr, prg_mode) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.registers[6] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => second_last,
+            (0xA000..=0xBFFF, _) => self.registers[7] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        // CHR A12 inversion
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        } as usize;
+
+        let bank = match addr {
+            0x0000..=0x07FF => (self.registers[0] & 0xFE) as usize + addr / 0x0400,
+            0x0800..=0x0FFF => (self.registers[1] & 0xFE) as usize + (addr - 0x0800) / 0x0400,
+            _ => self.registers[2 + (addr - 0x1000) / 0x0400] as usize,
+        };
+        bank * 0x0400 + (addr & 0x03FF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_occurred = true;
+        }
+    }
+}
+
+impl Mapper for Mapper4 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF => {
+                let i = self.chr_addr(addr);
+                if self.chr_ram.is_empty() {
+                    self.rom.chr_rom[i % self.rom.chr_rom.len()]
+                } else {
+                    self.chr_ram[i % self.chr_ram.len()]
+                }
+            }
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into();
+            }
+            0x8000..=0xFFFF => self.write_register(addr, value.into()),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let i = self.chr_addr(addr) % self.chr_ram.len();
+                self.chr_ram[i] = value.into();
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn ppu_address(&mut self, addr: Word) {
+        // the IRQ counter is clocked on each rising edge of PPU A12,
+        // filtered by M2 so that only the rise after a long enough low period counts
+        let a12 = addr.nth(12) == 1;
+        if !self.a12 && a12 && A12_LOW_CYCLES <= self.a12_low_cycles {
+            self.clock_irq_counter();
+        }
+        if self.a12 && !a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_occurred
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    // 64KB PRG-ROM and 16KB CHR-ROM
+    fn mmc3() -> Mapper4 {
+        let mut ines = test_ines(4, 16, 16);
+        ines.prg_rom.truncate(0x10000);
+        Mapper4::new(ines)
+    }
+
+    fn scanline(m: &mut Mapper4) {
+        m.ppu_address(0x0000u16.into());
+        for _ in 0..A12_LOW_CYCLES {
+            m.cpu_clock();
+        }
+        m.ppu_address(0x1000u16.into());
+    }
+
+    #[test]
+    fn switch_prg_banks() {
+        let mut m = mmc3();
+        m.cpu_write(0x8000u16.into(), 6.into());
+        m.cpu_write(0x8001u16.into(), 3.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 6.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 7.into());
+
+        // PRG ROM bank mode 1
+        m.cpu_write(0x8000u16.into(), 0x46.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 6.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 3.into());
+    }
+
+    #[test]
+    fn small_prg_rom() {
+        // 8KB PRG-ROM in NES 2.0 exponent notation leaves no second last bank
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 13 << 2, 0, 0x40, 0x08, 0, 0x0F];
+        data.extend(vec![0; 6]);
+        data.extend(vec![0xEA; 0x2000]);
+        assert_matches!(
+            Rom::validate(&data, &MapperRegistry::default())[..],
+            [CartridgeError::PrgRomTooSmall {
+                mapper: 4,
+                expected: 0x4000,
+                actual: 0x2000
+            }]
+        );
+    }
+
+    #[test]
+    fn switch_chr_banks() {
+        let mut m = mmc3();
+        m.cpu_write(0x8000u16.into(), 0.into());
+        m.cpu_write(0x8001u16.into(), 5.into());
+        m.cpu_write(0x8000u16.into(), 2.into());
+        m.cpu_write(0x8001u16.into(), 9.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 4.into());
+        assert_eq!(m.ppu_read(0x0400u16.into()), 5.into());
+        assert_eq!(m.ppu_read(0x1000u16.into()), 9.into());
+
+        // CHR A12 inversion
+        m.cpu_write(0x8000u16.into(), 0x80.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 9.into());
+        assert_eq!(m.ppu_read(0x1400u16.into()), 5.into());
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = mmc3();
+        m.cpu_write(0xC000u16.into(), 2.into());
+        m.cpu_write(0xC001u16.into(), 0.into());
+        m.cpu_write(0xE001u16.into(), 0.into());
+
+        scanline(&mut m);
+        scanline(&mut m);
+        assert!(!m.irq());
+        scanline(&mut m);
+        assert!(m.irq());
+
+        // acknowledge
+        m.cpu_write(0xE000u16.into(), 0.into());
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn irq_counter_ignores_rises_close_together() {
+        let mut m = mmc3();
+        m.cpu_write(0xC000u16.into(), 1.into());
+        m.cpu_write(0xC001u16.into(), 0.into());
+        m.cpu_write(0xE001u16.into(), 0.into());
+
+        // Reloaded with 1
+        scanline(&mut m);
+        assert!(!m.irq());
+
+        // e.g. 8x16 sprites fetching from both pattern tables
+        m.ppu_address(0x0000u16.into());
+        m.cpu_clock();
+        m.ppu_address(0x1000u16.into());
+        m.ppu_address(0x0000u16.into());
+        m.cpu_clock();
+        m.cpu_clock();
+        m.ppu_address(0x1000u16.into());
+        assert!(!m.irq());
+
+        scanline(&mut m);
+        assert!(m.irq());
+    }
+}
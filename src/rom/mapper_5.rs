@@ -0,0 +1,708 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/MMC5
+pub struct Mapper5 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    // $5120-$512B with the upper bits of $5130
+    chr_banks: [usize; 12],
+    chr_upper: u8,
+    last_chr_set: ChrSet,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    // Snooped from PPUCTRL
+    large_sprites: bool,
+
+    // Rendering state observed through PPU fetches
+    fetch: PpuFetch,
+    in_frame: bool,
+    idle_cycles: u32,
+    in_split: bool,
+    split_tile: usize,
+    split_y: usize,
+    ex_attr: u8,
+
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    pcm_read_mode: bool,
+    audio_cycles: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ChrSet {
+    // $5120-$5127; sprites in 8x16 mode
+    A,
+    // $5128-$512B; background in 8x16 mode
+    B,
+}
+
+// PPU stops fetching while rendering is disabled or in vblank
+const IDLE_CYCLES_OUT_OF_FRAME: u32 = 114;
+
+// 240Hz frame sequencer for the audio
+const AUDIO_FRAME_PERIOD: u32 = 7457;
+
+impl Mapper5 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        // Up to 64KB; iNES header cannot tell how much is actually on the board
+        let prg_ram_len = if rom.nes2 { rom.prg_ram_len() } else { 0x10000 };
+        let prg_ram = rom.new_prg_ram(prg_ram_len);
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            exram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_set: ChrSet::A,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            large_sprites: false,
+            fetch: PpuFetch::NameTable,
+            in_frame: false,
+            idle_cycles: 0,
+            in_split: false,
+            split_tile: 0,
+            split_y: 0,
+            ex_attr: 0,
+            pulse1: Default::default(),
+            pulse2: Default::default(),
+            pcm: 0,
+            pcm_read_mode: false,
+            audio_cycles: 0,
+        }
+    }
+
+    // Index of the bank register and the bank size for $8000-$FFFF
+    fn prg_register(&self, addr: u16) -> (usize, usize) {
+        let slot = (addr as usize - 0x8000) / 0x2000;
+        match (self.prg_mode, slot) {
+            (0, _) => (4, 0x8000),
+            (1, 0 | 1) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0 | 1) => (2, 0x4000),
+            (2, 2) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            (_, n) => (n + 1, 0x2000),
+        }
+    }
+
+    // ROM or RAM address for $8000-$FFFF
+    fn prg_addr(&self, addr: u16) -> (bool, usize) {
+        let (i, size) = self.prg_register(addr);
+        let value = self.prg_banks[i];
+        // $5117 always selects ROM
+        let rom = i == 4 || value & 0x80 != 0;
+        let bank = if rom { value & 0x7F } else { value & 0x07 } as usize;
+        let bank = bank & !(size / 0x2000 - 1);
+        (rom, bank * 0x2000 + (addr as usize & (size - 1)))
+    }
+
+    fn prg_ram_addr(&self, addr: u16) -> usize {
+        let bank = (self.prg_banks[0] & 0x07) as usize;
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_ram.len()
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] & 0b11 == 0b10 && self.prg_ram_protect[1] & 0b11 == 0b01
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        match self.prg_addr(addr) {
+            (true, i) => self.rom.prg_rom[i % self.rom.prg_rom.len()],
+            (false, _) if self.prg_ram.is_empty() => 0,
+            (false, i) => self.prg_ram[i % self.prg_ram.len()],
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if let (false, i) = self.prg_addr(addr) {
+            if self.prg_ram_writable() && !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[i % len] = value;
+            }
+        }
+    }
+
+    fn chr_set(&self) -> ChrSet {
+        if self.large_sprites && self.in_frame {
+            if self.fetch == PpuFetch::SpritePattern {
+                ChrSet::A
+            } else {
+                ChrSet::B
+            }
+        } else {
+            self.last_chr_set
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        if self.in_frame && self.fetch == PpuFetch::BackgroundPattern {
+            if self.in_split {
+                // 4KB bank with the fine Y scroll of the split region
+                let offset = (addr & 0x0FF8) | (self.split_y & 0x07);
+                return self.split_bank as usize * 0x1000 + offset;
+            }
+            if self.exram_mode == 1 {
+                // 4KB bank selected per tile by ExRAM
+                let bank = (self.ex_attr & 0x3F) as usize | ((self.chr_upper as usize & 0b11) << 6);
+                return bank * 0x1000 + (addr & 0x0FFF);
+            }
+        }
+
+        let size = 0x2000 >> self.chr_mode;
+        let n = 8 >> self.chr_mode;
+        let i = match self.chr_set() {
+            ChrSet::A => addr / size * n + n - 1,
+            // 8KB bank by $512B
+            ChrSet::B if self.chr_mode == 0 => 11,
+            // Lower and upper 4KB share the same banks
+            ChrSet::B => 8 + (addr & 0x0FFF) / size * n + n - 1,
+        };
+        self.chr_banks[i] * size + addr % size
+    }
+
+    fn split_enabled(&self) -> bool {
+        self.split_control & 0x80 != 0 && self.exram_mode <= 1
+    }
+
+    fn in_split_region(&self, tile: usize) -> bool {
+        let threshold = (self.split_control & 0x1F) as usize;
+        if self.split_control & 0x40 == 0 {
+            tile < threshold
+        } else {
+            threshold <= tile
+        }
+    }
+
+    fn clock_scanline(&mut self, line: i16) {
+        match line {
+            0 => self.in_frame = true,
+            1..=239 if self.in_frame && line as u8 == self.irq_compare => self.irq_pending = true,
+            1..=239 => {}
+            _ => self.in_frame = false,
+        }
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let mut v = 0;
+        if self.irq_pending {
+            v |= 0x80;
+        }
+        if self.in_frame {
+            v |= 0x40;
+        }
+        self.irq_pending = false;
+        v
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(addr & 0b11, value),
+            0x5004..=0x5007 => self.pulse2.write(addr & 0b11, value),
+            0x5010 => self.pcm_read_mode = value & 1 != 0,
+            // 0 is ignored
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.set_enabled(value & 0b01 != 0);
+                self.pulse2.set_enabled(value & 0b10 != 0);
+            }
+            0x5100 => self.prg_mode = value & 0b11,
+            0x5101 => self.chr_mode = value & 0b11,
+            0x5102 => self.prg_ram_protect[0] = value,
+            0x5103 => self.prg_ram_protect[1] = value,
+            0x5104 => self.exram_mode = value & 0b11,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attr = value & 0b11,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = value,
+            0x5120..=0x512B => {
+                let i = addr as usize - 0x5120;
+                self.chr_banks[i] = value as usize | ((self.chr_upper as usize & 0b11) << 8);
+                self.last_chr_set = if i < 8 { ChrSet::A } else { ChrSet::B };
+            }
+            0x5130 => self.chr_upper = value,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let i = addr as usize - 0x5C00;
+                match self.exram_mode {
+                    // Writable only while rendering, otherwise 0 is written
+                    0 | 1 => self.exram[i] = if self.in_frame { value } else { 0 },
+                    2 => self.exram[i] = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn product(&self) -> u16 {
+        self.multiplicand as u16 * self.multiplier as u16
+    }
+}
+
+// Attribute byte having the same palette in all 4 quadrants
+fn attribute(palette: u8) -> u8 {
+    let p = palette & 0b11;
+    p | p << 2 | p << 4 | p << 6
+}
+
+impl Mapper for Mapper5 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x5015 => {
+                let mut v = 0;
+                if 0 < self.pulse1.length_counter {
+                    v |= 0b01;
+                }
+                if 0 < self.pulse2.length_counter {
+                    v |= 0b10;
+                }
+                v
+            }
+            0x5204 => self.read_status(),
+            0x5205 => self.product() as u8,
+            0x5206 => (self.product() >> 8) as u8,
+            0x5C00..=0x5FFF if 2 <= self.exram_mode => self.exram[addr as usize - 0x5C00],
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => self.prg_ram[self.prg_ram_addr(addr)],
+            0x8000..=0xFFFF => {
+                let value = self.read_prg(addr);
+                if self.pcm_read_mode && addr <= 0xBFFF && value != 0 {
+                    self.pcm = value;
+                }
+                value
+            }
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        let i = self.chr_addr(addr);
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x5000..=0x5FFF => self.write_register(addr, value),
+            0x6000..=0x7FFF if self.prg_ram_writable() && !self.prg_ram.is_empty() => {
+                let i = self.prg_ram_addr(addr);
+                self.prg_ram[i] = value;
+            }
+            0x8000..=0xFFFF => self.write_prg(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Custom
+    }
+
+    fn read_nametable(&mut self, addr: Word, ciram: &[Byte]) -> Byte {
+        let addr: u16 = addr.into();
+        let offset = addr as usize & 0x03FF;
+
+        if self.in_frame && self.in_split {
+            return match self.fetch {
+                PpuFetch::Attribute => {
+                    let (x, y) = (self.split_tile, self.split_y / 8);
+                    let attr = self.exram[0x03C0 + y / 4 * 8 + x / 4];
+                    let shift = (y & 0b10) << 1 | (x & 0b10);
+                    attribute(attr >> shift)
+                }
+                _ => self.exram[self.split_y / 8 * 32 + self.split_tile],
+            }
+            .into();
+        }
+        if self.in_frame && self.exram_mode == 1 && self.fetch == PpuFetch::Attribute {
+            return attribute(self.ex_attr >> 6).into();
+        }
+
+        let value = match (self.nametable_mapping >> ((addr >> 10 & 0b11) * 2)) & 0b11 {
+            0 => ciram[offset],
+            1 => ciram[0x0400 + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset].into(),
+            2 => 0.into(),
+            _ if offset < 0x03C0 => self.fill_tile.into(),
+            _ => attribute(self.fill_attr).into(),
+        };
+        if self.in_frame && self.exram_mode == 1 && self.fetch == PpuFetch::NameTable {
+            self.ex_attr = self.exram[offset];
+        }
+        value
+    }
+
+    fn write_nametable(&mut self, addr: Word, value: Byte, ciram: &mut [Byte]) {
+        let addr: u16 = addr.into();
+        let offset = addr as usize & 0x03FF;
+        match (self.nametable_mapping >> ((addr >> 10 & 0b11) * 2)) & 0b11 {
+            0 => ciram[offset] = value,
+            1 => ciram[0x0400 + offset] = value,
+            2 if self.exram_mode <= 1 => self.exram[offset] = value.into(),
+            _ => {}
+        }
+    }
+
+    fn ppu_fetch(&mut self, fetch: PpuFetch, dot: u16, line: i16) {
+        self.fetch = fetch;
+        self.idle_cycles = 0;
+        if fetch != PpuFetch::NameTable {
+            return;
+        }
+        if dot == 1 {
+            self.clock_scanline(line);
+        }
+
+        // Dots 321-336 fetch the first 2 tiles of the next scanline
+        let (tile, y) = if 321 <= dot {
+            // The pre-render scanline is the last one of the frame
+            let next = if 239 < line { 0 } else { line as usize + 1 };
+            ((dot as usize - 321) / 8, next)
+        } else {
+            ((dot as usize - 1) / 8 + 2, line as usize)
+        };
+        self.in_split = self.split_enabled() && tile < 32 && self.in_split_region(tile);
+        if self.in_split {
+            self.split_tile = tile;
+            self.split_y = (y + self.split_scroll as usize) % 240;
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x2000 => self.large_sprites = value & 0x20 != 0,
+            // Rendering disabled
+            0x2001 if value & 0x18 == 0 => self.in_frame = false,
+            _ => {}
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.idle_cycles += 1;
+        if IDLE_CYCLES_OUT_OF_FRAME < self.idle_cycles {
+            self.in_frame = false;
+        }
+
+        self.audio_cycles += 1;
+        if self.audio_cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        if AUDIO_FRAME_PERIOD <= self.audio_cycles {
+            self.audio_cycles = 0;
+            self.pulse1.clock_frame();
+            self.pulse2.clock_frame();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        // Mixed in the same way as the APU pulse and DMC channels
+        let p = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let p_out = if p != 0.0 {
+            95.88 / ((8128.0 / p) + 100.0)
+        } else {
+            0.0
+        };
+        let d = self.pcm as f32 / 2.0;
+        let d_out = if d != 0.0 {
+            159.79 / (1.0 / (d / 22638.0) + 100.0)
+        } else {
+            0.0
+        };
+        p_out + d_out
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[rustfmt::skip]
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// Same as the APU pulse without sweep unit
+// https://wiki.nesdev.com/w/index.php/MMC5_audio
+#[derive(Debug, Default)]
+struct Pulse {
+    control: u8,
+    timer_period: u16,
+    timer: u16,
+    sequencer: usize,
+
+    enabled: bool,
+    length_counter: u8,
+
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.control = value,
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+                }
+                self.sequencer = 0;
+                self.envelope_start = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, v: bool) {
+        self.enabled = v;
+        if !v {
+            self.length_counter = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if 0 < self.timer {
+            self.timer -= 1;
+        } else {
+            self.timer = self.timer_period;
+            self.sequencer = (self.sequencer + 1) % 8;
+        }
+    }
+
+    // Envelope and length counter are clocked at 240Hz
+    fn clock_frame(&mut self) {
+        let period = self.control & 0x0F;
+        let halt = self.control & 0x20 != 0;
+
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = period;
+        } else if 0 < self.envelope_divider {
+            self.envelope_divider -= 1;
+        } else {
+            self.envelope_divider = period;
+            if 0 < self.envelope_decay {
+                self.envelope_decay -= 1;
+            } else if halt {
+                // loop
+                self.envelope_decay = 15;
+            }
+        }
+
+        if 0 < self.length_counter && !halt {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let duty = (self.control >> 6) as usize;
+        if self.length_counter == 0 || DUTY_TABLE[duty][self.sequencer] == 0 {
+            return 0;
+        }
+        if self.control & 0x10 != 0 {
+            self.control & 0x0F
+        } else {
+            self.envelope_decay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    fn mmc5() -> Mapper5 {
+        let ines = test_ines(5, 16, 64);
+        Mapper5::new(ines)
+    }
+
+    #[test]
+    fn switch_prg_banks() {
+        let mut m = mmc5();
+        assert_eq!(m.cpu_read(0xE000u16.into()), 15.into());
+
+        m.cpu_write(0x5114u16.into(), 0x83.into());
+        m.cpu_write(0x5115u16.into(), 0x85.into());
+        m.cpu_write(0x5116u16.into(), 0x87.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xA000u16.into()), 5.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 7.into());
+
+        // 32KB
+        m.cpu_write(0x5100u16.into(), 0.into());
+        m.cpu_write(0x5117u16.into(), 0x07.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 4.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 7.into());
+    }
+
+    #[test]
+    fn prg_ram_protect() {
+        let mut m = mmc5();
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0.into());
+
+        m.cpu_write(0x5102u16.into(), 0b10.into());
+        m.cpu_write(0x5103u16.into(), 0b01.into());
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0x12.into());
+
+        // RAM bank at $8000
+        m.cpu_write(0x5114u16.into(), 0x00.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 0x12.into());
+    }
+
+    #[test]
+    fn switch_chr_banks() {
+        let mut m = mmc5();
+        m.cpu_write(0x5101u16.into(), 3.into());
+        m.cpu_write(0x5120u16.into(), 10.into());
+        m.cpu_write(0x5127u16.into(), 20.into());
+        assert_eq!(m.ppu_read(0x0000u16.into()), 10.into());
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 20.into());
+
+        // Background uses $5128-$512B in 8x16 sprite mode while rendering
+        m.cpu_write(0x512Bu16.into(), 30.into());
+        m.ppu_register_write(0x2000u16.into(), 0x20.into());
+        m.ppu_fetch(PpuFetch::NameTable, 1, 0);
+        m.ppu_fetch(PpuFetch::BackgroundPattern, 5, 0);
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 30.into());
+        m.ppu_fetch(PpuFetch::SpritePattern, 261, 0);
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 20.into());
+    }
+
+    #[test]
+    fn nametable_mapping() {
+        let mut m = mmc5();
+        let mut ciram = [Byte::from(0); 0x0800];
+        // CIRAM A, CIRAM B, ExRAM and fill mode
+        m.cpu_write(0x5105u16.into(), 0b11_10_01_00.into());
+        m.cpu_write(0x5106u16.into(), 0x33.into());
+        m.cpu_write(0x5107u16.into(), 0x02.into());
+
+        m.write_nametable(0x2000u16.into(), 1.into(), &mut ciram);
+        m.write_nametable(0x2400u16.into(), 2.into(), &mut ciram);
+        m.write_nametable(0x2800u16.into(), 3.into(), &mut ciram);
+        assert_eq!(ciram[0x0000], 1.into());
+        assert_eq!(ciram[0x0400], 2.into());
+        assert_eq!(m.read_nametable(0x2800u16.into(), &ciram), 3.into());
+        assert_eq!(m.read_nametable(0x2C00u16.into(), &ciram), 0x33.into());
+        assert_eq!(m.read_nametable(0x2FC0u16.into(), &ciram), 0xAA.into());
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut m = mmc5();
+        m.cpu_write(0x5203u16.into(), 2.into());
+        m.cpu_write(0x5204u16.into(), 0x80.into());
+
+        m.ppu_fetch(PpuFetch::NameTable, 1, 0);
+        assert_eq!(m.cpu_read(0x5204u16.into()), 0x40.into());
+        m.ppu_fetch(PpuFetch::NameTable, 1, 1);
+        assert!(!m.irq());
+        m.ppu_fetch(PpuFetch::NameTable, 1, 2);
+        assert!(m.irq());
+
+        // acknowledged by reading the status
+        assert_eq!(m.cpu_read(0x5204u16.into()), 0xC0.into());
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut m = mmc5();
+        m.cpu_write(0x5205u16.into(), 200.into());
+        m.cpu_write(0x5206u16.into(), 100.into());
+        assert_eq!(m.cpu_read(0x5205u16.into()), (20000u16 as u8).into());
+        assert_eq!(m.cpu_read(0x5206u16.into()), ((20000u16 >> 8) as u8).into());
+    }
+}
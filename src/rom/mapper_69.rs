@@ -0,0 +1,431 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// Sunsoft FME-7 and 5B
+// https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+pub struct Mapper69 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000-$7FFF; RAM enable, RAM/ROM select and bank number
+    prg_bank_6000: u8,
+    // $8000-$DFFF
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Sunsoft5b,
+}
+
+impl Mapper69 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(rom.prg_ram_len());
+        let battery = rom.battery_backed() && !prg_ram.is_empty();
+
+        Self {
+            mirroring: rom.mirroring(),
+            rom,
+            chr_ram,
+            prg_ram,
+            battery,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Default::default(),
+        }
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            n @ 0x0..=0x7 => self.chr_banks[n as usize] = value,
+            0x8 => self.prg_bank_6000 = value,
+            n @ 0x9..=0xB => self.prg_banks[n as usize - 0x9] = value & 0x3F,
+            0xC => {
+                self.mirroring = match value & 0b11 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xD => {
+                self.irq_enabled = value & 0x01 != 0;
+                self.irq_counter_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+
+    fn prg_rom_addr(&self, bank: u8, addr: u16) -> usize {
+        let banks = self.rom.prg_rom.len() / 0x2000;
+        (bank as usize % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    fn prg_ram_selected(&self) -> bool {
+        self.prg_bank_6000 & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_selected() && self.prg_bank_6000 & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / 0x0400] as usize;
+        bank * 0x0400 + (addr as usize & 0x03FF)
+    }
+}
+
+impl Mapper for Mapper69 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            // Open bus
+            0x6000..=0x7FFF if self.prg_ram_selected() => 0,
+            0x6000..=0x7FFF => self.rom.prg_rom[self.prg_rom_addr(self.prg_bank_6000 & 0x3F, addr)],
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[(addr as usize - 0x8000) / 0x2000];
+                self.rom.prg_rom[self.prg_rom_addr(bank, addr)]
+            }
+            0xE000..=0xFFFF => self.rom.prg_rom[self.prg_rom_addr(0xFF, addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let i = self.chr_addr(addr.into());
+        if self.chr_ram.is_empty() {
+            self.rom.chr_rom[i % self.rom.chr_rom.len()]
+        } else {
+            self.chr_ram[i % self.chr_ram.len()]
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        let value: u8 = value.into();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            0xC000..=0xDFFF => self.audio.select(value),
+            0xE000..=0xFFFF => self.audio.write(value),
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        if !self.chr_ram.is_empty() {
+            let i = self.chr_addr(addr.into()) % self.chr_ram.len();
+            self.chr_ram[i] = value.into();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+// Sunsoft 5B audio; a variant of YM2149F (AY-3-8910)
+// https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+#[derive(Debug, Default)]
+struct Sunsoft5b {
+    register: u8,
+
+    tone_periods: [u16; 3],
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    noise_period: u8,
+    noise_counter: u8,
+    // 17-bit LFSR
+    noise_shift: u32,
+
+    // $07; tone disable in bits 0-2 and noise disable in bits 3-5
+    mixer: u8,
+    // $08-$0A; envelope mode in bit 4
+    volumes: [u8; 3],
+
+    envelope_period: u16,
+    envelope_counter: u16,
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    // Internal clock divider
+    cycles: u8,
+}
+
+// Full volume of a channel relative to the APU output
+const SUNSOFT_5B_LEVEL: f32 = 0.15;
+
+impl Sunsoft5b {
+    fn select(&mut self, value: u8) {
+        self.register = value & 0x0F;
+    }
+
+    fn write(&mut self, value: u8) {
+        match self.register {
+            r @ (0x0 | 0x2 | 0x4) => {
+                let p = &mut self.tone_periods[r as usize / 2];
+                *p = (*p & 0x0F00) | value as u16;
+            }
+            r @ (0x1 | 0x3 | 0x5) => {
+                let p = &mut self.tone_periods[r as usize / 2];
+                *p = (*p & 0x00FF) | ((value as u16 & 0x0F) << 8);
+            }
+            0x6 => self.noise_period = value & 0x1F,
+            0x7 => self.mixer = value,
+            r @ 0x8..=0xA => self.volumes[r as usize - 0x8] = value & 0x1F,
+            0xB => self.envelope_period = (self.envelope_period & 0xFF00) | value as u16,
+            0xC => self.envelope_period = (self.envelope_period & 0x00FF) | (value as u16) << 8,
+            0xD => {
+                self.envelope_shape = value & 0x0F;
+                self.envelope_step = 0;
+                self.envelope_attack = value & 0b0100 != 0;
+                self.envelope_holding = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        // Tones are clocked at CPU/16, noise at CPU/32 and envelope at CPU/16 with 32 steps
+        self.cycles = self.cycles.wrapping_add(1);
+        if self.cycles & 0x0F != 0 {
+            return;
+        }
+
+        for i in 0..3 {
+            self.tone_counters[i] += 1;
+            if self.tone_periods[i] <= self.tone_counters[i] {
+                self.tone_counters[i] = 0;
+                self.tone_outputs[i] = !self.tone_outputs[i];
+            }
+        }
+
+        if self.cycles & 0x1F == 0 {
+            self.noise_counter += 1;
+            if self.noise_period <= self.noise_counter {
+                self.noise_counter = 0;
+                if self.noise_shift == 0 {
+                    self.noise_shift = 1;
+                }
+                let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+                self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+            }
+        }
+
+        self.envelope_counter += 1;
+        if self.envelope_period <= self.envelope_counter {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let continued = self.envelope_shape & 0b1000 != 0;
+        let alternate = self.envelope_shape & 0b0010 != 0;
+        let hold = self.envelope_shape & 0b0001 != 0;
+        if !continued {
+            // Shape $00-$07 stay at zero after the first cycle
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+            return;
+        }
+        if alternate {
+            self.envelope_attack = !self.envelope_attack;
+        }
+        if hold {
+            self.envelope_holding = true;
+            self.envelope_step = 31;
+        } else {
+            self.envelope_step = 0;
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    // 5-bit level of the channel
+    fn channel_level(&self, i: usize) -> u8 {
+        let tone_disabled = self.mixer & (1 << i) != 0;
+        let noise_disabled = self.mixer & (1 << (i + 3)) != 0;
+        let tone = tone_disabled || self.tone_outputs[i];
+        let noise = noise_disabled || self.noise_shift & 1 != 0;
+        if !(tone && noise) {
+            return 0;
+        }
+        let volume = self.volumes[i];
+        if volume & 0x10 != 0 {
+            self.envelope_level()
+        } else if volume == 0 {
+            0
+        } else {
+            // 4-bit volume steps by 3dB, twice the envelope
+            volume * 2 + 1
+        }
+    }
+
+    fn output(&self) -> f32 {
+        (0..3)
+            .map(|i| match self.channel_level(i) {
+                0 => 0.0,
+                // 1.5dB per step
+                level => 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0),
+            })
+            .sum::<f32>()
+            * SUNSOFT_5B_LEVEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::inesfile::Flag6;
+    use crate::rom::test_ines;
+
+    fn fme7() -> Mapper69 {
+        let mut ines = test_ines(69, 16, 128);
+        // Battery-backed
+        ines.flag6.insert(Flag6::BATTERY_BACKED_PRG_RAM);
+        Mapper69::new(ines)
+    }
+
+    fn command(m: &mut Mapper69, command: u8, parameter: u8) {
+        m.cpu_write(0x8000u16.into(), command.into());
+        m.cpu_write(0xA000u16.into(), parameter.into());
+    }
+
+    #[test]
+    fn switch_banks() {
+        let mut m = fme7();
+        command(&mut m, 0x9, 3);
+        command(&mut m, 0xB, 5);
+        command(&mut m, 0x7, 0x42);
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 5.into());
+        assert_eq!(m.cpu_read(0xE000u16.into()), 15.into());
+        assert_eq!(m.ppu_read(0x1C00u16.into()), 0x42.into());
+    }
+
+    #[test]
+    fn prg_ram_or_rom_at_6000() {
+        let mut m = fme7();
+        command(&mut m, 0x8, 0x07);
+        assert_eq!(m.cpu_read(0x6000u16.into()), 7.into());
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 7.into());
+
+        command(&mut m, 0x8, 0xC0);
+        m.cpu_write(0x6000u16.into(), 0x12.into());
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0x12.into());
+
+        // Disabled RAM
+        command(&mut m, 0x8, 0x40);
+        assert_eq!(m.cpu_read(0x6000u16.into()), 0.into());
+        assert_eq!(m.battery_ram().unwrap()[0], 0x12);
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut m = fme7();
+        command(&mut m, 0xE, 0x01);
+        command(&mut m, 0xF, 0x00);
+        command(&mut m, 0xD, 0x81);
+        m.cpu_clock();
+        assert!(!m.irq());
+        m.cpu_clock();
+        assert!(m.irq());
+
+        command(&mut m, 0xD, 0x81);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn audio() {
+        let mut m = fme7();
+        assert_eq!(m.audio_output(), 0.0);
+
+        // Tone A at full volume, noise disabled
+        let writes = [(0x0, 0x01), (0x1, 0x00), (0x7, 0x3E), (0x8, 0x0F)];
+        for (r, v) in writes {
+            m.cpu_write(0xC000u16.into(), r.into());
+            m.cpu_write(0xE000u16.into(), v.into());
+        }
+        for _ in 0..16 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.audio.channel_level(0), 31);
+        assert!(0.0 < m.audio_output());
+        for _ in 0..16 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.audio_output(), 0.0);
+    }
+}
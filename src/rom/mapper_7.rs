@@ -0,0 +1,121 @@
+use super::inesfile::INESFile;
+use super::*;
+use crate::data_unit::*;
+
+// https://wiki.nesdev.com/w/index.php/AxROM
+pub struct Mapper7 {
+    rom: INESFile,
+    chr_ram: Vec<u8>,
+    // Only for the trainer
+    prg_ram: Vec<u8>,
+
+    bus_conflicted: bool,
+
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Mapper7 {
+    pub(super) fn new(rom: INESFile) -> Self {
+        let chr_ram = rom.new_chr_ram();
+        let prg_ram = rom.new_prg_ram(0);
+
+        Self {
+            bus_conflicted: rom.bus_conflicted(),
+            rom,
+            chr_ram,
+            prg_ram,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn prg_addr(&self, addr: u16) -> usize {
+        (self.prg_bank * 0x8000 + (addr as usize & 0x7FFF)) % self.rom.prg_rom.len()
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn cpu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x8000..=0xFFFF => self.rom.prg_rom[self.prg_addr(addr)],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn ppu_read(&mut self, addr: Word) -> Byte {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if self.chr_ram.is_empty() => {
+                self.rom.chr_rom[addr as usize % self.rom.chr_rom.len()]
+            }
+            0x0000..=0x1FFF => self.chr_ram[addr as usize % self.chr_ram.len()],
+            _ => 0,
+        }
+        .into()
+    }
+
+    fn cpu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value.into()
+            }
+            0x8000..=0xFFFF => {
+                let mut value: u8 = value.into();
+                if self.bus_conflicted {
+                    value &= self.rom.prg_rom[self.prg_addr(addr)];
+                }
+                self.prg_bank = (value & 0b111) as usize;
+                self.mirroring = if value & 0x10 == 0 {
+                    Mirroring::SingleScreenLower
+                } else {
+                    Mirroring::SingleScreenUpper
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_write(&mut self, addr: Word, value: Byte) {
+        let addr: u16 = addr.into();
+        match addr {
+            0x0000..=0x1FFF if !self.chr_ram.is_empty() => {
+                let len = self.chr_ram.len();
+                self.chr_ram[addr as usize % len] = value.into()
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_ines;
+
+    #[test]
+    fn switch_prg_bank_and_mirroring() {
+        let mut m = Mapper7::new(test_ines(7, 4, 8));
+        assert_matches!(m.mirroring(), Mirroring::SingleScreenLower);
+
+        m.cpu_write(0x8000u16.into(), 0x13.into());
+        assert_eq!(m.cpu_read(0x8000u16.into()), 3.into());
+        assert_eq!(m.cpu_read(0xFFFFu16.into()), 3.into());
+        assert_matches!(m.mirroring(), Mirroring::SingleScreenUpper);
+
+        m.cpu_write(0x8000u16.into(), 0x02.into());
+        assert_eq!(m.cpu_read(0xC000u16.into()), 2.into());
+        assert_matches!(m.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
@@ -0,0 +1,157 @@
+// Converts the NES 2.0 XML database (nes20db.xml) into lines of gamedb.txt.
+// build.rs includes this file as well, so only std is used here.
+// https://forums.nesdev.org/viewtopic.php?t=19940
+
+use std::collections::HashMap;
+
+// One line per `<game>` having `<rom>`, `<pcb>` and `<console>`
+pub fn convert(xml: &str) -> String {
+    let mut lines = String::new();
+    for game in xml.split("<game>").skip(1) {
+        let game = match game.find("</game>") {
+            Some(end) => &game[..end],
+            None => continue,
+        };
+        let (rom, pcb, console) = match (
+            attributes(game, "rom"),
+            attributes(game, "pcb"),
+            attributes(game, "console"),
+        ) {
+            (Some(rom), Some(pcb), Some(console)) => (rom, pcb, console),
+            _ => continue,
+        };
+        let field = |attrs: &HashMap<&str, String>, key: &str, default: &str| {
+            attrs
+                .get(key)
+                .filter(|v| !v.is_empty())
+                .map_or_else(|| default.to_string(), |v| v.replace('\t', " "))
+        };
+        let fields = [
+            field(&rom, "crc32", "-").to_ascii_uppercase(),
+            field(&rom, "sha1", "-").to_ascii_lowercase(),
+            field(&pcb, "mapper", "0"),
+            field(&pcb, "submapper", "0"),
+            // Mapper-controlled mirroring is left to the mapper
+            match pcb.get("mirroring").map(String::as_str) {
+                Some("V") => "V",
+                Some("4") => "4",
+                _ => "H",
+            }
+            .to_string(),
+            field(&pcb, "battery", "0"),
+            match console.get("region").map(String::as_str) {
+                Some("1") => "PAL",
+                Some("2") => "Multi",
+                Some("3") => "Dendy",
+                _ => "NTSC",
+            }
+            .to_string(),
+            field(&pcb, "name", "-"),
+            title(game),
+        ];
+        lines.push_str(&fields.join("\t"));
+        lines.push('\n');
+    }
+    lines
+}
+
+// File name without the extension in the comment such as `<!-- Games\Title (World).nes -->`
+fn title(game: &str) -> String {
+    let comment = game
+        .find("<!--")
+        .and_then(|start| {
+            let text = &game[start + 4..];
+            text.find("-->").map(|end| text[..end].trim())
+        })
+        .unwrap_or("-");
+    let name = comment.rsplit(['\\', '/']).next().unwrap_or(comment);
+    let name = match name.rfind('.') {
+        Some(dot) if 0 < dot => &name[..dot],
+        _ => name,
+    };
+    name.replace('\t', " ")
+}
+
+// Attributes of the first `<name .../>` element
+fn attributes<'a>(xml: &'a str, name: &str) -> Option<HashMap<&'a str, String>> {
+    let start = xml.find(&format!("<{} ", name))? + name.len() + 2;
+    let end = start + xml[start..].find('>')?;
+    let mut rest = xml[start..end].trim_end_matches('/');
+    let mut attrs = HashMap::new();
+    while let Some(eq) = rest.find("=\"") {
+        let key = rest[..eq].trim();
+        let value = &rest[eq + 2..];
+        let close = value.find('"')?;
+        attrs.insert(key, unescape(&value[..close]));
+        rest = &value[close + 1..];
+    }
+    Some(attrs)
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::{GameDatabase, Timing};
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db date="2024-01-01">
+<game>
+	<!-- Licensed\Test Game (Europe).nes -->
+	<prgrom size="131072" crc32="01234567" sha1="0000000000000000000000000000000000000000" sum16="0000"/>
+	<chrrom size="131072" crc32="89ABCDEF" sha1="1111111111111111111111111111111111111111" sum16="0000"/>
+	<rom size="262144" crc32="0a1b2c3d" sha1="0123456789ABCDEF0123456789ABCDEF01234567"/>
+	<pcb name="NES-TLROM &amp; co" mapper="4" submapper="0" mirroring="V" battery="1"/>
+	<console type="0" region="1"/>
+</game>
+<game>
+	<!-- Unlicensed\Other Game.unf -->
+	<rom size="32768" crc32="FEDCBA98"/>
+	<pcb mapper="0" submapper="0" mirroring="1" battery="0"/>
+	<console type="0" region="0"/>
+</game>
+<game>
+	<!-- No ROM checksums -->
+	<pcb mapper="0"/>
+</game>
+</nes20db>
+"#;
+
+    #[test]
+    fn convert_games() {
+        let lines = convert(XML);
+        assert_eq!(
+            lines,
+            "0A1B2C3D\t0123456789abcdef0123456789abcdef01234567\t4\t0\tV\t1\tPAL\tNES-TLROM & co\tTest Game (Europe)\n\
+             FEDCBA98\t-\t0\t0\tH\t0\tNTSC\t-\tOther Game\n"
+        );
+
+        let db = GameDatabase::parse(&lines).unwrap();
+        let game = db.find_by_checksums(0x0A1B2C3D, "").unwrap();
+        assert_eq!(game.title, "Test Game (Europe)");
+        assert_eq!(game.board, "NES-TLROM & co");
+        assert_eq!(game.region, Timing::Pal);
+        assert!(db.find_by_checksums(0xFEDCBA98, "").is_some());
+    }
+
+    // Games in the database given to the build are found by their real checksums
+    #[cfg(nes20db)]
+    #[test]
+    fn builtin_has_nes20db() {
+        let xml = std::fs::read_to_string(env!("NES20DB_PATH")).unwrap();
+        let db = GameDatabase::builtin();
+        for line in convert(&xml).lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let crc = u32::from_str_radix(fields[0], 16).unwrap_or_default();
+            assert!(db.find_by_checksums(crc, fields[1]).is_some(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,491 @@
+use std::convert::TryInto;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use super::CartridgeError;
+
+// Soft-patches applied to ROM images before parsing them
+// https://wiki.nesdev.com/w/index.php/IPS
+// https://www.romhacking.net/documents/746/ (BPS)
+// https://www.romhacking.net/documents/392/ (UPS)
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const UPS_MAGIC: &[u8] = b"UPS1";
+// Source, target and patch CRC32 at the end of BPS and UPS
+const FOOTER_LEN: usize = 12;
+// Far larger than any cartridge; a broken patch would otherwise allocate up to 16MB
+// by an IPS offset, or any size given as the BPS or UPS target length
+const MAX_TARGET_LEN: usize = 0x80_0000;
+
+// Applies IPS, BPS or UPS patch by the magic at its beginning
+pub fn apply_patch(data: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(data, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(&data, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(&data, patch)
+    } else {
+        Err(CartridgeError::InvalidPatch.into())
+    }
+}
+
+// Patch file with the same name next to the ROM, e.g. game.ips for game.nes
+pub fn find_patch<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| path.as_ref().with_extension(ext))
+        .find(|p| p.is_file())
+}
+
+enum IpsRecord<'a> {
+    Copy(usize, &'a [u8]),
+    Fill(usize, usize, u8),
+}
+
+impl IpsRecord<'_> {
+    fn range(&self) -> Range<usize> {
+        match self {
+            Self::Copy(offset, bytes) => *offset..offset + bytes.len(),
+            Self::Fill(offset, len, _) => *offset..offset + len,
+        }
+    }
+}
+
+// Records and the truncated length given after "EOF" by some patchers
+fn ips_records(patch: &[u8]) -> Result<(Vec<IpsRecord<'_>>, Option<usize>)> {
+    let invalid = || CartridgeError::InvalidPatch;
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(invalid().into());
+    }
+    let mut records = Vec::new();
+    let mut pos = IPS_MAGIC.len();
+    loop {
+        let record = patch.get(pos..pos + 3).ok_or_else(invalid)?;
+        if record == IPS_EOF {
+            let truncate = patch
+                .get(pos + 3..pos + 6)
+                .map(|s| (s[0] as usize) << 16 | (s[1] as usize) << 8 | s[2] as usize);
+            return Ok((records, truncate));
+        }
+        let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let size = patch.get(pos + 3..pos + 5).ok_or_else(invalid)?;
+        let size = (size[0] as usize) << 8 | size[1] as usize;
+        pos += 5;
+
+        if size == 0 {
+            // RLE record
+            let rle = patch.get(pos..pos + 3).ok_or_else(invalid)?;
+            let len = (rle[0] as usize) << 8 | rle[1] as usize;
+            records.push(IpsRecord::Fill(offset, len, rle[2]));
+            pos += 3;
+        } else {
+            let bytes = patch.get(pos..pos + size).ok_or_else(invalid)?;
+            records.push(IpsRecord::Copy(offset, bytes));
+            pos += size;
+        }
+    }
+}
+
+fn write_ips_record(data: &mut [u8], record: &IpsRecord) -> Result<()> {
+    let dest = data
+        .get_mut(record.range())
+        .ok_or(CartridgeError::InvalidPatch)?;
+    match record {
+        IpsRecord::Copy(_, bytes) => dest.copy_from_slice(bytes),
+        IpsRecord::Fill(_, _, value) => dest.fill(*value),
+    }
+    Ok(())
+}
+
+// Patches in place without changing the size, for saved disk images
+pub(crate) fn ips_patch(data: &mut [u8], patch: &[u8]) -> Result<()> {
+    let (records, _) = ips_records(patch)?;
+    for r in &records {
+        write_ips_record(data, r)?;
+    }
+    Ok(())
+}
+
+// Records past the end extend the ROM image
+fn apply_ips(mut data: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    let (records, truncate) = ips_records(patch)?;
+    let len = records.iter().map(|r| r.range().end).max().unwrap_or(0);
+    if data.len() < len {
+        check_target_len(len)?;
+        data.resize(len, 0);
+    }
+    for r in &records {
+        write_ips_record(&mut data, r)?;
+    }
+    if let Some(len) = truncate {
+        data.truncate(len);
+    }
+    Ok(data)
+}
+
+struct PatchReader<'a> {
+    patch: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(patch: &'a [u8], magic: &[u8]) -> Self {
+        Self {
+            patch,
+            pos: magic.len(),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let b = *self
+            .patch
+            .get(self.pos)
+            .ok_or(CartridgeError::InvalidPatch)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.patch.get(self.pos..end))
+            .ok_or(CartridgeError::InvalidPatch)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // Variable-length number shared by BPS and UPS
+    fn number(&mut self) -> Result<usize> {
+        let overflow = || CartridgeError::InvalidPatch;
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            data = ((x & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|n| data.checked_add(n))
+                .ok_or_else(overflow)?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_shl(7).ok_or_else(overflow)?;
+            data = data.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+
+    // Signed relative offset of BPS copy actions
+    fn offset(&mut self, base: usize) -> Result<usize> {
+        let n = self.number()?;
+        let delta = n >> 1;
+        if n & 1 == 0 {
+            base.checked_add(delta)
+        } else {
+            base.checked_sub(delta)
+        }
+        .ok_or_else(|| CartridgeError::InvalidPatch.into())
+    }
+}
+
+// Checks the footer of BPS and UPS and returns the body and the CRC32 of the patched ROM
+fn verify_footer<'a>(source: &[u8], patch: &'a [u8]) -> Result<(&'a [u8], u32)> {
+    if patch.len() < FOOTER_LEN {
+        return Err(CartridgeError::InvalidPatch.into());
+    }
+    let (body, footer) = patch.split_at(patch.len() - FOOTER_LEN);
+    let crc = |i: usize| u32::from_le_bytes(footer[i..i + 4].try_into().unwrap());
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc(8) {
+        return Err(CartridgeError::PatchChecksum("patch").into());
+    }
+    if crc32fast::hash(source) != crc(0) {
+        return Err(CartridgeError::PatchChecksum("source ROM").into());
+    }
+    Ok((body, crc(4)))
+}
+
+fn verify_target(target: &[u8], crc: u32) -> Result<()> {
+    if crc32fast::hash(target) != crc {
+        return Err(CartridgeError::PatchChecksum("patched ROM").into());
+    }
+    Ok(())
+}
+
+fn check_target_len(len: usize) -> Result<()> {
+    if MAX_TARGET_LEN < len {
+        return Err(CartridgeError::PatchedRomTooLarge(len).into());
+    }
+    Ok(())
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let invalid = || CartridgeError::InvalidPatch;
+    let (body, target_crc) = verify_footer(source, patch)?;
+    let mut r = PatchReader::new(body, BPS_MAGIC);
+    if r.number()? != source.len() {
+        return Err(CartridgeError::PatchChecksum("source ROM").into());
+    }
+    let target_len = r.number()?;
+    check_target_len(target_len)?;
+    let metadata_len = r.number()?;
+    r.bytes(metadata_len)?;
+
+    let mut target = Vec::new();
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while r.pos < body.len() {
+        let data = r.number()?;
+        let len = (data >> 2) + 1;
+        if target_len < target.len() + len {
+            return Err(invalid().into());
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let out = target.len();
+                let bytes = source.get(out..out + len).ok_or_else(invalid)?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(r.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_offset = r.offset(source_offset)?;
+                let end = source_offset.checked_add(len).ok_or_else(invalid)?;
+                let bytes = source.get(source_offset..end).ok_or_else(invalid)?;
+                target.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // TargetCopy, which may overlap the bytes being written
+            _ => {
+                target_offset = r.offset(target_offset)?;
+                if target.len() <= target_offset {
+                    return Err(invalid().into());
+                }
+                for _ in 0..len {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_len {
+        return Err(invalid().into());
+    }
+    verify_target(&target, target_crc)?;
+    Ok(target)
+}
+
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (body, target_crc) = verify_footer(source, patch)?;
+    let mut r = PatchReader::new(body, UPS_MAGIC);
+    if r.number()? != source.len() {
+        return Err(CartridgeError::PatchChecksum("source ROM").into());
+    }
+    let target_len = r.number()?;
+    check_target_len(target_len)?;
+
+    let mut target = source.to_vec();
+    target.resize(target_len, 0);
+    let mut pos: usize = 0;
+    while r.pos < body.len() {
+        pos = pos
+            .checked_add(r.number()?)
+            .ok_or(CartridgeError::InvalidPatch)?;
+        // XOR bytes until the terminating zero, which also moves the position
+        loop {
+            let x = r.byte()?;
+            if x == 0 {
+                pos += 1;
+                break;
+            }
+            if let Some(b) = target.get_mut(pos) {
+                *b ^= x;
+            }
+            pos += 1;
+        }
+    }
+    verify_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn number_encoding() {
+        for n in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 0x12345678] {
+            let bytes = number(n);
+            let mut r = PatchReader {
+                patch: &bytes,
+                pos: 0,
+            };
+            assert_eq!(r.number().unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn ips() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record past the end extends the image
+        patch.extend([0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x02, 0xCC]);
+        patch.extend(IPS_EOF);
+
+        let data = apply_patch(vec![0; 4], &patch).unwrap();
+        assert_eq!(data, vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC]);
+
+        // Truncation after EOF
+        patch.extend([0x00, 0x00, 0x03]);
+        let data = apply_patch(vec![0; 4], &patch).unwrap();
+        assert_eq!(data, vec![0, 0xAA, 0xBB]);
+
+        // Saved disk images must not grow
+        assert_matches!(ips_patch(&mut [0; 4], &patch), Err(_));
+    }
+
+    #[test]
+    fn patched_rom_too_large() {
+        // RLE record at the largest IPS offset
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00]);
+        patch.extend(IPS_EOF);
+        assert_matches!(
+            apply_patch(vec![0; 4], &patch)
+                .unwrap_err()
+                .downcast::<CartridgeError>(),
+            Ok(CartridgeError::PatchedRomTooLarge(0x100_FFFE))
+        );
+
+        let source = vec![0; 4];
+        for magic in [BPS_MAGIC, UPS_MAGIC] {
+            let mut patch = magic.to_vec();
+            patch.extend(number(source.len()));
+            patch.extend(number(usize::MAX >> 8));
+            let patch = with_footer(patch, &source, &[]);
+            assert_matches!(
+                apply_patch(source.clone(), &patch)
+                    .unwrap_err()
+                    .downcast::<CartridgeError>(),
+                Ok(CartridgeError::PatchedRomTooLarge(_))
+            );
+        }
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"ABCDEFGH".to_vec();
+        let target = b"ABCxyzFGHFGHFGH".to_vec();
+
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(0));
+        // SourceRead "ABC"
+        patch.extend(number((3 - 1) << 2));
+        // TargetRead "xyz"
+        patch.extend(number((3 - 1) << 2 | 1));
+        patch.extend(b"xyz");
+        // SourceCopy "FGH" from +5
+        patch.extend(number((3 - 1) << 2 | 2));
+        patch.extend(number(5 << 1));
+        // TargetCopy "FGHFGH" from +6, overlapping the output
+        patch.extend(number((6 - 1) << 2 | 3));
+        patch.extend(number(6 << 1));
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply_patch(source.clone(), &patch).unwrap(), target);
+
+        // Made for another ROM
+        assert_matches!(
+            apply_patch(b"12345678".to_vec(), &patch)
+                .unwrap_err()
+                .downcast::<CartridgeError>(),
+            Ok(CartridgeError::PatchChecksum("source ROM"))
+        );
+
+        // SourceCopy from the farthest offset
+        let mut far = BPS_MAGIC.to_vec();
+        far.extend(number(source.len()));
+        far.extend(number(target.len()));
+        far.extend(number(0));
+        far.extend(number((3 - 1) << 2 | 2));
+        far.extend(number(usize::MAX - 1));
+        let far = with_footer(far, &source, &target);
+        assert_matches!(
+            apply_patch(source.clone(), &far)
+                .unwrap_err()
+                .downcast::<CartridgeError>(),
+            Ok(CartridgeError::InvalidPatch)
+        );
+
+        let mut broken = patch.clone();
+        broken[6] ^= 1;
+        assert_matches!(
+            apply_patch(source, &broken)
+                .unwrap_err()
+                .downcast::<CartridgeError>(),
+            Ok(CartridgeError::PatchChecksum("patch"))
+        );
+    }
+
+    #[test]
+    fn ups() {
+        let source = b"ABCDEFGH".to_vec();
+        let mut target = b"ABcDEFgHIJ".to_vec();
+
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(2));
+        patch.extend([b'C' ^ b'c', 0]);
+        patch.extend(number(2));
+        patch.extend([b'G' ^ b'g', 0]);
+        patch.extend(number(0));
+        patch.extend([b'I', b'J', 0]);
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply_patch(source.clone(), &patch).unwrap(), target);
+
+        target[0] = b'Z';
+        let patch = with_footer(patch[..patch.len() - FOOTER_LEN].to_vec(), &source, &target);
+        assert_matches!(
+            apply_patch(source, &patch)
+                .unwrap_err()
+                .downcast::<CartridgeError>(),
+            Ok(CartridgeError::PatchChecksum("patched ROM"))
+        );
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert_matches!(apply_patch(vec![0; 4], b"NOTAPATCH"), Err(_));
+    }
+}
@@ -0,0 +1,55 @@
+use super::gamedb::GameInfo;
+use super::inesfile::INESFile;
+use super::{ConsoleType, Mirroring, Timing};
+
+// Metadata of a cartridge image for tools to list and filter ROMs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub nes2: bool,
+
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    // TV system
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+
+    // Checksums of PRG-ROM followed by CHR-ROM in the same way as the game database
+    pub crc32: u32,
+    pub sha1: String,
+    // Identified by the game database; always `None` when built with `NES20DB=none`
+    pub game: Option<GameInfo>,
+}
+
+impl RomInfo {
+    pub(super) fn new(ines: &INESFile, game: Option<GameInfo>, crc32: u32, sha1: String) -> Self {
+        Self {
+            mapper: ines.mapper,
+            submapper: ines.submapper,
+            nes2: ines.nes2,
+            prg_rom_size: ines.prg_rom.len(),
+            chr_rom_size: ines.chr_rom.len(),
+            prg_ram_size: ines.prg_ram_size,
+            prg_nvram_size: ines.prg_nvram_size,
+            chr_ram_size: ines.chr_ram_size,
+            chr_nvram_size: ines.chr_nvram_size,
+            mirroring: ines.mirroring(),
+            battery: ines.battery_backed(),
+            trainer: !ines.trainer.is_empty(),
+            timing: ines.timing,
+            console_type: ines.console_type,
+            crc32,
+            sha1,
+            game,
+        }
+    }
+}
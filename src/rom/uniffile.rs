@@ -0,0 +1,305 @@
+use anyhow::Result;
+
+use super::inesfile::INESFile;
+use super::{CartridgeError, Mirroring};
+
+// https://wiki.nesdev.com/w/index.php/UNIF
+#[derive(Debug, Default)]
+pub struct UNIFFile {
+    // MAPR
+    pub board: String,
+    // PRG0-PRGF and CHR0-CHRF concatenated in order
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // MIRR
+    pub mirroring: Option<u8>,
+    // BATR
+    pub battery: bool,
+    // TVCI
+    pub tv_system: Option<u8>,
+}
+
+const MAGIC: &[u8] = b"UNIF";
+const HEADER_LEN: usize = 32;
+
+// MIRR values
+const MIRRORING_VERTICAL: u8 = 1;
+const MIRRORING_SINGLE_SCREEN_LOWER: u8 = 2;
+const MIRRORING_SINGLE_SCREEN_UPPER: u8 = 3;
+const MIRRORING_FOUR_SCREEN: u8 = 4;
+const MIRRORING_MAPPER_CONTROLLED: u8 = 5;
+// TVCI value
+const TV_SYSTEM_PAL: u8 = 1;
+
+impl UNIFFile {
+    pub fn is_unif(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !Self::is_unif(data) || data.len() < HEADER_LEN {
+            return Err(CartridgeError::InvalidUnif.into());
+        }
+        let mut unif = Self::default();
+        let mut prg_chunks: [Option<&[u8]>; 16] = Default::default();
+        let mut chr_chunks: [Option<&[u8]>; 16] = Default::default();
+
+        let mut pos = HEADER_LEN;
+        while pos < data.len() {
+            let header = data.get(pos..pos + 8).ok_or(CartridgeError::InvalidUnif)?;
+            let id = &header[..4];
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            pos += 8;
+            let body = data
+                .get(pos..pos + len)
+                .ok_or(CartridgeError::InvalidUnif)?;
+            pos += len;
+
+            match id {
+                b"MAPR" => {
+                    let name = body.split(|&b| b == 0).next().unwrap_or_default();
+                    unif.board = String::from_utf8_lossy(name).into_owned();
+                }
+                [b'P', b'R', b'G', n] => prg_chunks[chunk_index(*n)?] = Some(body),
+                [b'C', b'H', b'R', n] => chr_chunks[chunk_index(*n)?] = Some(body),
+                b"MIRR" => unif.mirroring = body.first().copied(),
+                b"BATR" => unif.battery = true,
+                b"TVCI" => unif.tv_system = body.first().copied(),
+                // Metadata such as NAME, DINF and CRCs are not needed to run
+                _ => {}
+            }
+        }
+        if unif.board.is_empty() {
+            return Err(CartridgeError::InvalidUnif.into());
+        }
+        unif.prg_rom = prg_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        unif.chr_rom = chr_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        if unif.prg_rom.is_empty() {
+            return Err(CartridgeError::InvalidUnif.into());
+        }
+        Ok(unif)
+    }
+
+    // Converts into NES 2.0 with the mapper of the board so that existing mappers can load it
+    pub fn into_ines(self) -> Result<INESFile> {
+        let (mapper, submapper) = board_mapper(&self.board)
+            .ok_or_else(|| CartridgeError::NotSupportedBoard(self.board.clone()))?;
+        let prg_rom = fill_units(self.prg_rom, 0x4000);
+        let chr_rom = fill_units(self.chr_rom, 0x2000);
+        let prg_units = prg_rom.len() / 0x4000;
+        let chr_units = chr_rom.len() / 0x2000;
+
+        let mut flag6 = ((mapper & 0x0F) << 4) as u8;
+        let mut mirroring_override = None;
+        match self.mirroring {
+            Some(MIRRORING_VERTICAL) => flag6 |= 0b0001,
+            Some(MIRRORING_SINGLE_SCREEN_LOWER) => {
+                mirroring_override = Some(Mirroring::SingleScreenLower)
+            }
+            Some(MIRRORING_SINGLE_SCREEN_UPPER) => {
+                mirroring_override = Some(Mirroring::SingleScreenUpper)
+            }
+            Some(MIRRORING_FOUR_SCREEN) => flag6 |= 0b1000,
+            // Left to the mapper, which ignores the header for boards switching mirroring
+            Some(MIRRORING_MAPPER_CONTROLLED) => {}
+            _ => {}
+        }
+        // 8KB of PRG-RAM, battery-backed if BATR exists; 8KB of CHR-RAM without CHR chunks
+        let prg_ram = if self.battery {
+            flag6 |= 0b0010;
+            0x70
+        } else {
+            0x07
+        };
+        let chr_ram = if chr_rom.is_empty() { 0x07 } else { 0x00 };
+        let timing = match self.tv_system {
+            Some(TV_SYSTEM_PAL) => 1,
+            _ => 0,
+        };
+
+        let mut data = vec![
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            prg_units as u8,
+            chr_units as u8,
+            flag6,
+            (mapper & 0xF0) as u8 | 0b1000,
+            (submapper << 4) | (mapper >> 8) as u8,
+            ((chr_units >> 8) << 4 | prg_units >> 8) as u8,
+            prg_ram,
+            chr_ram,
+            timing,
+        ];
+        data.extend(vec![0; 3]);
+        data.extend(prg_rom);
+        data.extend(chr_rom);
+        let mut ines = INESFile::parse(data)?;
+        ines.mirroring_override = mirroring_override;
+        Ok(ines)
+    }
+}
+
+fn chunk_index(n: u8) -> Result<usize> {
+    (n as char)
+        .to_digit(16)
+        .map(|i| i as usize)
+        .ok_or_else(|| CartridgeError::InvalidUnif.into())
+}
+
+// Mirrors smaller ROM to fill a bank and pads to the bank size
+fn fill_units(mut rom: Vec<u8>, unit: usize) -> Vec<u8> {
+    if rom.is_empty() {
+        return rom;
+    }
+    while rom.len() < unit {
+        rom.extend_from_within(..);
+    }
+    let len = rom.len().div_ceil(unit) * unit;
+    rom.resize(len, 0);
+    rom
+}
+
+// Mapper and submapper implementing the board
+// https://wiki.nesdev.com/w/index.php/UNIF_to_NES_2.0_Mapping
+fn board_mapper(board: &str) -> Option<(u16, u8)> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => (1, 0),
+        "UNROM" | "UOROM" => (2, 0),
+        "CNROM" => (3, 0),
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TNROM"
+        | "TR1ROM" | "TSROM" | "TVROM" | "HKROM" => (4, 0),
+        "ELROM" | "EKROM" | "ETROM" | "EWROM" => (5, 0),
+        "ANROM" | "AN1ROM" | "AMROM" | "AOROM" => (7, 0),
+        "BNROM" => (34, 0),
+        "GNROM" | "MHROM" => (66, 0),
+        "BTR" | "JLROM" | "JSROM" => (69, 0),
+        "DEROM" | "DE1ROM" | "DRROM" => (206, 0),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::MapperRegistry;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend((body.len() as u32).to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn unif(board: &str, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"UNIF".to_vec();
+        data.extend(7u32.to_le_bytes());
+        data.resize(HEADER_LEN, 0);
+        data.extend(chunk(b"MAPR", format!("{}\0", board).as_bytes()));
+        for c in chunks {
+            data.extend(c);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_chunks() {
+        let data = unif(
+            "NES-SNROM",
+            &[
+                chunk(b"PRG1", &[1; 0x4000]),
+                chunk(b"PRG0", &[0; 0x4000]),
+                chunk(b"MIRR", &[1]),
+                chunk(b"BATR", &[0]),
+                chunk(b"NAME", b"Test\0"),
+            ],
+        );
+        let unif = UNIFFile::parse(&data).unwrap();
+        assert_eq!(unif.board, "NES-SNROM");
+        assert_eq!(unif.prg_rom.len(), 0x8000);
+        assert_eq!(unif.prg_rom[0x3FFF], 0);
+        assert_eq!(unif.prg_rom[0x4000], 1);
+        assert!(unif.chr_rom.is_empty());
+        assert!(unif.battery);
+
+        let ines = unif.into_ines().unwrap();
+        assert_eq!(ines.mapper, 1);
+        assert_eq!(ines.prg_rom.len(), 0x8000);
+        assert_eq!(ines.new_chr_ram().len(), 0x2000);
+        assert_matches!(ines.mirroring(), Mirroring::Vertical);
+        assert!(ines.battery_backed());
+        assert_eq!(ines.prg_ram_len(), 0x2000);
+
+        // Truncated chunk
+        assert_matches!(UNIFFile::parse(&data[..data.len() - 1]), Err(_));
+    }
+
+    #[test]
+    fn single_screen_mirroring() {
+        for (mirr, mirroring) in [
+            (2, Mirroring::SingleScreenLower),
+            (3, Mirroring::SingleScreenUpper),
+        ] {
+            let data = unif(
+                "NES-NROM-256",
+                &[chunk(b"PRG0", &[0; 0x8000]), chunk(b"MIRR", &[mirr])],
+            );
+            let ines = UNIFFile::parse(&data).unwrap().into_ines().unwrap();
+            assert_eq!(ines.mirroring(), mirroring);
+        }
+
+        // AxROM switches it by itself
+        let data = unif(
+            "NES-AOROM",
+            &[chunk(b"PRG0", &[0; 0x20000]), chunk(b"MIRR", &[5])],
+        );
+        let ines = UNIFFile::parse(&data).unwrap().into_ines().unwrap();
+        let constructor = MapperRegistry::default().find(ines.mapper, 0).unwrap();
+        let mut mapper = constructor(ines).unwrap();
+        mapper.cpu_write(0x8000u16.into(), 0x10.into());
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn fill_small_rom() {
+        let data = unif(
+            "NES-NROM-128",
+            &[
+                chunk(b"PRG0", &[0xEA; 0x2000]),
+                chunk(b"CHR0", &[1; 0x2000]),
+            ],
+        );
+        let ines = UNIFFile::parse(&data).unwrap().into_ines().unwrap();
+        assert_eq!(ines.mapper, 0);
+        assert_eq!(ines.prg_rom, vec![0xEA; 0x4000]);
+        assert_eq!(ines.chr_rom.len(), 0x2000);
+    }
+
+    #[test]
+    fn unknown_board() {
+        let data = unif("UNL-FOO", &[chunk(b"PRG0", &[0; 0x4000])]);
+        let result = UNIFFile::parse(&data).unwrap().into_ines();
+        assert_matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(CartridgeError::NotSupportedBoard(name)) if name == "UNL-FOO"
+        );
+    }
+}
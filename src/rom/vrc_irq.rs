@@ -0,0 +1,70 @@
+// IRQ counter shared by Konami VRC4, VRC6 and VRC7
+// https://wiki.nesdev.com/w/index.php/VRC_IRQ
+#[derive(Debug, Default)]
+pub(super) struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+
+    pub(super) occurred: bool,
+}
+
+impl VrcIrq {
+    pub(super) fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    pub(super) fn write_latch_low(&mut self, value: u8) {
+        self.latch = (self.latch & 0xF0) | (value & 0x0F);
+    }
+
+    pub(super) fn write_latch_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0F) | (value << 4);
+    }
+
+    pub(super) fn write_control(&mut self, value: u8) {
+        self.enabled_after_ack = value & 0b001 != 0;
+        self.enabled = value & 0b010 != 0;
+        self.cycle_mode = value & 0b100 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+        self.occurred = false;
+    }
+
+    pub(super) fn acknowledge(&mut self) {
+        self.occurred = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    // Clocked by every CPU cycle
+    pub(super) fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            // Scanline mode divides CPU cycles by 113.667
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.occurred = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
@@ -172,7 +172,7 @@ fn to_assembly_code(
                     nes.read_bus(addr)
                 )
             }
-            AddressingMode::IndirectIndexed => {
+            AddressingMode::IndirectIndexed { .. } => {
                 let addr = read_on_indirect(cpu_operand_1(nes).into(), nes);
                 format!(
                     "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
@@ -217,7 +217,9 @@ fn decode_address(addressing_mode: AddressingMode, nes: &mut Nes) -> Word {
         AddressingMode::IndexedIndirect => {
             read_on_indirect((cpu_operand_16(nes) + nes.cpu.x) & 0xFF, nes)
         }
-        AddressingMode::IndirectIndexed => read_on_indirect(cpu_operand_16(nes), nes) + nes.cpu.y,
+        AddressingMode::IndirectIndexed { .. } => {
+            read_on_indirect(cpu_operand_16(nes), nes) + nes.cpu.y
+        }
         _ => 0x00u16.into(),
     }
 }
@@ -250,7 +252,7 @@ impl AddressingMode {
             | Self::ZeroPageX
             | Self::ZeroPageY
             | Self::Relative
-            | Self::IndirectIndexed
+            | Self::IndirectIndexed { .. }
             | Self::IndexedIndirect => 2,
             Self::Indirect | Self::Absolute | Self::AbsoluteX { .. } | Self::AbsoluteY { .. } => 3,
             _ => 1,
@@ -258,10 +260,12 @@ impl AddressingMode {
     }
 }
 
-const UNDOCUMENTED_OPCODES: [u8; 80] = [
+const UNDOCUMENTED_OPCODES: [u8; 105] = [
     0xEB, 0x04, 0x44, 0x64, 0x0C, 0x14, 0x34, 0x54, 0x74, 0xD4, 0xF4, 0x1A, 0x3A, 0x5A, 0x7A, 0xDA,
     0xFA, 0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC, 0x80, 0x82, 0x89, 0xC2, 0xE2, 0xA3, 0xA7, 0xAF, 0xB3,
     0xB7, 0xBF, 0x83, 0x87, 0x8F, 0x97, 0xC3, 0xC7, 0xCF, 0xD3, 0xD7, 0xDB, 0xDF, 0xE3, 0xE7, 0xEF,
     0xF3, 0xF7, 0xFB, 0xFF, 0x03, 0x07, 0x0F, 0x13, 0x17, 0x1B, 0x1F, 0x23, 0x27, 0x2F, 0x33, 0x37,
     0x3B, 0x3F, 0x43, 0x47, 0x4F, 0x53, 0x57, 0x5B, 0x5F, 0x63, 0x67, 0x6F, 0x73, 0x77, 0x7B, 0x7F,
+    0x0B, 0x2B, 0x4B, 0x6B, 0xCB, 0xBB, 0x9E, 0x9C, 0x93, 0x9F, 0x9B, 0xAB, 0x8B, 0x02, 0x12, 0x22,
+    0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
 ];